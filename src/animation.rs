@@ -0,0 +1,490 @@
+use crate::{
+    canvas, output, palette,
+    parse::{Palette, SharedSettings},
+    pipeline,
+};
+use image::{DynamicImage, GenericImage, GenericImageView, ImageOutputFormat, Rgba, RgbaImage};
+use png::HasParameters;
+use set_error::ChangeError;
+use std::{
+    convert::TryFrom,
+    ffi::OsStr,
+    fs::{self, File},
+    io::Read,
+    path::Path,
+};
+
+/// One whole frame of an animation, with every earlier frame it builds on already drawn.
+pub struct Frame {
+    pub image: DynamicImage,
+    /// How long the frame is shown in hundredths of a second.
+    pub delay: u16,
+}
+
+pub struct Animated {
+    pub frames: Vec<Frame>,
+    /// How many times to repeat, 0 being forever, or play once if not set.
+    pub loops: Option<u16>,
+}
+
+/// Decodes every frame of the GIF at `path`, or nothing if it is not a GIF or has only one frame.
+/// Only the start of other files is read.
+pub fn open(path: &str) -> Result<Option<Animated>, String> {
+    let mut magic = [0; 4];
+    let is_gif = File::open(path)
+        .and_then(|mut x| x.read_exact(&mut magic))
+        .is_ok()
+        && magic == *b"GIF8";
+    if !is_gif {
+        return Ok(None);
+    }
+    let bytes = fs::read(path).set_error(&format!("failed to open file {path}"))?;
+    let animated = decode_gif(&bytes)?;
+    Ok(Some(animated).filter(|x| x.frames.len() > 1))
+}
+
+/// Drops and speeds up frames as `settings` asks, then runs its jobs on every frame but the first,
+/// which is given already transformed as `first`. Every frame is trimmed to what trimming the first
+/// frame keeps, and any frame the jobs still make a different size is cropped or padded to the size
+/// of the first, as every frame of a GIF shares one size.
+pub fn transform(
+    animated: Animated,
+    first: DynamicImage,
+    settings: &SharedSettings,
+) -> Result<Animated, String> {
+    let step = settings.animation.frame_step.unwrap_or(1).max(1) as usize;
+    let speed = f64::from(settings.animation.speed.unwrap_or(1.0));
+    let trim_box = match (&settings.jobs.trim, animated.frames.first()) {
+        (Some(trim), Some(frame)) => canvas::trim_box(&frame.image, trim),
+        _ => None,
+    };
+    let mut untrimmed = settings.clone();
+    untrimmed.jobs.trim = None;
+    let jobs = pipeline::jobs(&untrimmed);
+    let size = first.dimensions();
+    let mut frames = Vec::new();
+    let mut first = Some(first);
+    let mut source = animated.frames.into_iter().peekable();
+    while let Some(frame) = source.next() {
+        let mut delay = u32::from(frame.delay);
+        for _ in 1..step {
+            match source.next() {
+                Some(dropped) => delay += u32::from(dropped.delay),
+                None => break,
+            }
+        }
+        let image = if let Some(first) = first.take() {
+            first
+        } else {
+            let mut image = frame.image;
+            if let Some((left, top, width, height)) = trim_box {
+                image = image.crop(left, top, width, height);
+            }
+            fit(pipeline::apply(&jobs, image)?, size)
+        };
+        frames.push(Frame {
+            image,
+            delay: scale_delay(delay, speed),
+        });
+    }
+    output::detail(&format!("Kept {} frames", frames.len()));
+    Ok(Animated {
+        frames,
+        loops: animated.loops,
+    })
+}
+
+/// Whether an output at `output_path` can keep every frame of an animation.
+pub fn keeps_frames(output_path: &str) -> bool {
+    matches!(
+        output_format(output_path),
+        Some(ImageOutputFormat::GIF | ImageOutputFormat::PNG)
+    )
+}
+
+/// Saves `animated` as a GIF or APNG at `output_path` and returns how many bytes were written.
+/// GIF frames are reduced to `palette` or to 256 colours, while APNG frames keep every colour.
+pub fn save(
+    animated: &Animated,
+    output_path: &str,
+    palette: Option<&Palette>,
+) -> Result<usize, String> {
+    let bytes = match output_format(output_path) {
+        Some(ImageOutputFormat::GIF) => {
+            let palette = palette.cloned().unwrap_or_default();
+            let indexed: Vec<(palette::Indexed, u16)> = animated
+                .frames
+                .iter()
+                .map(|x| (palette::quantize(&x.image, &palette), x.delay))
+                .collect();
+            let frames: Vec<(&palette::Indexed, u16)> =
+                indexed.iter().map(|(x, y)| (x, *y)).collect();
+            palette::encode_gif_frames(&frames, animated.loops)?
+        }
+        Some(ImageOutputFormat::PNG) => encode_apng(animated)?,
+        _ => {
+            return Err(format!(
+                "Animations can only be saved as GIF or PNG, not \"{output_path}\"."
+            ))
+        }
+    };
+    fs::write(output_path, &bytes).set_error("Failed to save.")?;
+    Ok(bytes.len())
+}
+
+fn output_format(output_path: &str) -> Option<ImageOutputFormat> {
+    Path::new(output_path)
+        .extension()
+        .and_then(OsStr::to_str)
+        .and_then(|x| pipeline::output_format(x).ok())
+}
+
+/// Writes `animated` as an APNG, each frame covering the whole image and replacing the last.
+fn encode_apng(animated: &Animated) -> Result<Vec<u8>, String> {
+    let error = "Failed to encode APNG.";
+    let first = animated
+        .frames
+        .first()
+        .set_error("An APNG needs a frame.")?;
+    let (width, height) = first.image.dimensions();
+    let frames = u32::try_from(animated.frames.len()).set_error(error)?;
+    // GIFs count the repeats after the first play, where APNGs count every play.
+    let plays = match animated.loops {
+        Some(0) => 0,
+        Some(x) => u32::from(x) + 1,
+        None => 1,
+    };
+    let mut bytes = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut bytes, width, height);
+        encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().set_error(error)?;
+        writer
+            .write_chunk(
+                *b"acTL",
+                &[frames.to_be_bytes(), plays.to_be_bytes()].concat(),
+            )
+            .set_error(error)?;
+        let mut sequence = 0_u32;
+        for (index, frame) in animated.frames.iter().enumerate() {
+            let control = [
+                &sequence.to_be_bytes()[..],
+                &width.to_be_bytes(),
+                &height.to_be_bytes(),
+                &0_u32.to_be_bytes(),
+                &0_u32.to_be_bytes(),
+                &frame.delay.to_be_bytes(),
+                &100_u16.to_be_bytes(),
+                // Leaves the frame when done, and replaces rather than blends over what is there.
+                &[0, 0],
+            ]
+            .concat();
+            writer.write_chunk(*b"fcTL", &control).set_error(error)?;
+            sequence += 1;
+            let rgba = frame.image.to_rgba();
+            if index == 0 {
+                writer.write_image_data(&rgba).set_error(error)?;
+            } else {
+                let data = [&sequence.to_be_bytes()[..], &image_data(&rgba)?].concat();
+                writer.write_chunk(*b"fdAT", &data).set_error(error)?;
+                sequence += 1;
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+/// The compressed pixels of `rgba`, taken from its IDAT chunks when written as a PNG.
+fn image_data(rgba: &RgbaImage) -> Result<Vec<u8>, String> {
+    let error = "Failed to encode APNG frame.";
+    let mut png = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut png, rgba.width(), rgba.height());
+        encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().set_error(error)?;
+        writer.write_image_data(rgba).set_error(error)?;
+    }
+    Ok(chunks(&png)
+        .filter(|(name, _)| name == b"IDAT")
+        .flat_map(|(_, data)| data.iter().copied())
+        .collect())
+}
+
+/// The name and data of each chunk of the PNG in `bytes`.
+fn chunks(bytes: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    let mut rest = bytes.get(8..).unwrap_or_default();
+    std::iter::from_fn(move || {
+        let length = u32::from_be_bytes(<[u8; 4]>::try_from(rest.get(..4)?).ok()?) as usize;
+        let name = <[u8; 4]>::try_from(rest.get(4..8)?).ok()?;
+        let data = rest.get(8..8 + length)?;
+        rest = rest.get(12 + length..)?;
+        Some((name, data))
+    })
+}
+
+/// Crops or pads `img` with transparency on the right and bottom to `size`.
+fn fit(img: DynamicImage, (width, height): (u32, u32)) -> DynamicImage {
+    if img.dimensions() == (width, height) {
+        return img;
+    }
+    output::detail(&format!(
+        "Fitting a {}x{} frame to {}x{}",
+        img.width(),
+        img.height(),
+        width,
+        height
+    ));
+    let mut fitted = RgbaImage::new(width, height);
+    let kept = img
+        .to_rgba()
+        .view(0, 0, width.min(img.width()), height.min(img.height()))
+        .to_image();
+    fitted.copy_from(&kept, 0, 0);
+    DynamicImage::ImageRgba8(fitted)
+}
+
+/// Draws each frame over what the earlier frames left, as GIF frames can cover just part of the
+/// image and keep or clear what was there before.
+fn decode_gif(bytes: &[u8]) -> Result<Animated, String> {
+    let mut decoder = gif::Decoder::new(bytes);
+    gif::SetParameter::set(&mut decoder, gif::ColorOutput::RGBA);
+    let mut reader = decoder.read_info().set_error("Failed to decode GIF.")?;
+    let mut canvas = RgbaImage::new(u32::from(reader.width()), u32::from(reader.height()));
+    let mut frames = Vec::new();
+    while let Some(frame) = reader
+        .read_next_frame()
+        .set_error("Failed to decode GIF frame.")?
+    {
+        let before = match frame.dispose {
+            gif::DisposalMethod::Previous => Some(canvas.clone()),
+            _ => None,
+        };
+        let (left, top) = (u32::from(frame.left), u32::from(frame.top));
+        let (width, height) = (u32::from(frame.width), u32::from(frame.height));
+        if width > 0 {
+            for (index, pixel) in frame.buffer.chunks(4).enumerate() {
+                let (x, y) = (left + index as u32 % width, top + index as u32 / width);
+                if pixel[3] > 0 && x < canvas.width() && y < canvas.height() {
+                    canvas.put_pixel(x, y, Rgba([pixel[0], pixel[1], pixel[2], pixel[3]]));
+                }
+            }
+        }
+        frames.push(Frame {
+            image: DynamicImage::ImageRgba8(canvas.clone()),
+            delay: frame.delay,
+        });
+        if let Some(before) = before {
+            canvas = before;
+        } else if let gif::DisposalMethod::Background = frame.dispose {
+            for y in top..(top + height).min(canvas.height()) {
+                for x in left..(left + width).min(canvas.width()) {
+                    canvas.put_pixel(x, y, Rgba([0; 4]));
+                }
+            }
+        }
+    }
+    Ok(Animated {
+        frames,
+        loops: loops(bytes),
+    })
+}
+
+/// Reads the repeat count from the Netscape application extension, which GIFs without it do not
+/// have. Every block is walked so image data that happens to hold the same bytes is skipped.
+fn loops(bytes: &[u8]) -> Option<u16> {
+    let mut index = 13 + colour_table_len(*bytes.get(10)?);
+    loop {
+        match *bytes.get(index)? {
+            0x21 => {
+                let label = *bytes.get(index + 1)?;
+                index += 2;
+                if label == 0xff
+                    && matches!(sub_block(bytes, index)?, b"NETSCAPE2.0" | b"ANIMEXTS1.0")
+                {
+                    if let [1, low, high] = *sub_block(bytes, index + 12)? {
+                        return Some(u16::from_le_bytes([low, high]));
+                    }
+                }
+                index = skip_sub_blocks(bytes, index)?;
+            }
+            // An image descriptor, then its colour table, LZW code size and data.
+            0x2c => {
+                index += 10 + colour_table_len(*bytes.get(index + 9)?) + 1;
+                index = skip_sub_blocks(bytes, index)?;
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// The length of the colour table given by the flags of a screen or image descriptor.
+fn colour_table_len(flags: u8) -> usize {
+    if flags & 0x80 == 0 {
+        0
+    } else {
+        3 << ((flags & 7) + 1)
+    }
+}
+
+/// The data of the sub-block whose length is at `index`.
+fn sub_block(bytes: &[u8], index: usize) -> Option<&[u8]> {
+    let length = usize::from(*bytes.get(index)?);
+    bytes.get(index + 1..index + 1 + length)
+}
+
+/// The index just after the sub-blocks starting at `index` and the empty one ending them.
+fn skip_sub_blocks(bytes: &[u8], mut index: usize) -> Option<usize> {
+    loop {
+        let length = usize::from(*bytes.get(index)?);
+        index += 1 + length;
+        if length == 0 {
+            return Some(index);
+        }
+    }
+}
+
+/// Divides `delay` by `speed`, never rounding a frame that is shown down to not being shown.
+#[allow(clippy::cast_sign_loss)]
+fn scale_delay(delay: u32, speed: f64) -> u16 {
+    if delay == 0 {
+        return 0;
+    }
+    (f64::from(delay) / speed)
+        .round()
+        .clamp(1.0, f64::from(u16::MAX)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::Trim;
+
+    fn frame(square_at: u32) -> Frame {
+        let mut image = RgbaImage::new(20, 10);
+        for y in 2..6 {
+            for x in square_at..square_at + 4 {
+                image.put_pixel(x, y, Rgba([255, 0, 0, 255]));
+            }
+        }
+        Frame {
+            image: DynamicImage::ImageRgba8(image),
+            delay: 10,
+        }
+    }
+
+    #[test]
+    fn scale_delay_keeps_shown_frames() {
+        assert_eq!(scale_delay(10, 2.0), 5);
+        assert_eq!(scale_delay(1, 4.0), 1);
+        assert_eq!(scale_delay(0, 0.5), 0);
+        assert_eq!(scale_delay(u32::from(u16::MAX), 0.1), u16::MAX);
+    }
+
+    fn indexed(colour: u8) -> palette::Indexed {
+        palette::Indexed {
+            width: 2,
+            height: 2,
+            colours: vec![[colour, 0, 0, 255]],
+            indexes: vec![0; 4],
+        }
+    }
+
+    #[test]
+    fn loops_reads_netscape_extension() {
+        let (red, blue) = (indexed(255), indexed(0));
+        for count in [None, Some(0), Some(261)] {
+            let bytes = palette::encode_gif_frames(&[(&red, 10), (&blue, 10)], count).unwrap();
+            assert_eq!(loops(&bytes), count);
+        }
+        assert_eq!(loops(b"GIF89a"), None);
+    }
+
+    #[test]
+    fn loops_ignores_lookalike_image_data() {
+        let mut bytes = b"GIF89a\x01\x00\x01\x00\x00\x00\x00".to_vec();
+        bytes.extend_from_slice(b"\x2c\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02");
+        bytes.push(16);
+        bytes.extend_from_slice(b"NETSCAPE2.0\x03\x01\x05\x00\x00");
+        bytes.extend_from_slice(b"\x00\x3b");
+        assert_eq!(loops(&bytes), None);
+    }
+
+    #[test]
+    fn apng_has_every_frame() {
+        let animated = Animated {
+            frames: vec![frame(2), frame(8), frame(14)],
+            loops: Some(0),
+        };
+        let bytes = encode_apng(&animated).unwrap();
+        let names: Vec<[u8; 4]> = chunks(&bytes).map(|(name, _)| name).collect();
+        assert_eq!(
+            names,
+            [
+                *b"IHDR", *b"acTL", *b"fcTL", *b"IDAT", *b"fcTL", *b"fdAT", *b"fcTL", *b"fdAT",
+                *b"IEND"
+            ]
+        );
+        let (_, control) = chunks(&bytes).find(|(name, _)| name == b"acTL").unwrap();
+        assert_eq!(control, [0, 0, 0, 3, 0, 0, 0, 0]);
+        let sequence: Vec<u32> = chunks(&bytes)
+            .filter(|(name, _)| name == b"fcTL" || name == b"fdAT")
+            .map(|(_, data)| u32::from_be_bytes([data[0], data[1], data[2], data[3]]))
+            .collect();
+        assert_eq!(sequence, [0, 1, 2, 3, 4]);
+        let (_, last) = chunks(&bytes)
+            .filter(|(name, _)| name == b"fdAT")
+            .last()
+            .unwrap();
+        assert_eq!(
+            last[4..],
+            image_data(&frame(14).image.to_rgba()).unwrap()[..]
+        );
+        // Viewers without APNG support show the first frame.
+        let still = image::load_from_memory(&bytes).unwrap();
+        assert_eq!(
+            still.to_rgba().into_raw(),
+            frame(2).image.to_rgba().into_raw()
+        );
+    }
+
+    #[test]
+    fn only_gif_and_png_keep_frames() {
+        assert!(keeps_frames("banner.gif"));
+        assert!(keeps_frames("banner.PNG"));
+        assert!(!keeps_frames("banner.jpg"));
+        let animated = Animated {
+            frames: vec![frame(2), frame(8)],
+            loops: None,
+        };
+        assert!(save(&animated, "banner.jpg", None).is_err());
+    }
+
+    #[test]
+    fn fit_crops_and_pads() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 2, Rgba([1; 4])));
+        let fitted = fit(img, (3, 3));
+        assert_eq!(fitted.dimensions(), (3, 3));
+        assert_eq!(fitted.get_pixel(2, 1).data, [1; 4]);
+        assert_eq!(fitted.get_pixel(2, 2).data, [0; 4]);
+    }
+
+    #[test]
+    fn transform_trims_every_frame_like_the_first() {
+        let mut settings = SharedSettings::default();
+        settings.jobs.trim = Some(Trim {
+            colour: None,
+            tolerance: 0,
+        });
+        let animated = Animated {
+            frames: vec![frame(2), frame(8), frame(14)],
+            loops: None,
+        };
+        let first = canvas::trim(frame(2).image, settings.jobs.trim.as_ref().unwrap());
+        let animated = transform(animated, first, &settings).unwrap();
+        assert_eq!(animated.frames.len(), 3);
+        assert!(animated
+            .frames
+            .iter()
+            .all(|x| x.image.dimensions() == (4, 4)));
+    }
+}
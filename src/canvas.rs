@@ -0,0 +1,314 @@
+use crate::{
+    colour, output,
+    parse::{Border, Mask, Pad, Trim},
+};
+use image::{
+    DynamicImage, FilterType, GenericImage, GenericImageView, ImageOutputFormat, Rgba, RgbaImage,
+};
+use set_error::ChangeError;
+
+/// Whether `img` has an alpha channel, even if every pixel is opaque.
+pub fn has_alpha(img: &DynamicImage) -> bool {
+    matches!(
+        img,
+        DynamicImage::ImageLumaA8(_) | DynamicImage::ImageRgba8(_) | DynamicImage::ImageBgra8(_)
+    )
+}
+
+/// Draws `img` over `background`. The result is RGB if the background is opaque.
+pub fn flatten(img: DynamicImage, background: [u8; 4]) -> DynamicImage {
+    if !has_alpha(&img) {
+        return img;
+    }
+    let mut rgba = img.to_rgba();
+    for pixel in rgba.pixels_mut() {
+        pixel.data = over(pixel.data, background);
+    }
+    let flattened = DynamicImage::ImageRgba8(rgba);
+    if background[3] == 255 {
+        DynamicImage::ImageRgb8(flattened.to_rgb())
+    } else {
+        flattened
+    }
+}
+
+/// Flattens `img` onto white if `format` can not store alpha, so transparent pixels do not come
+/// out as whatever colour they happen to hide, usually black. Warns if any pixel was transparent.
+pub fn flatten_for(img: &DynamicImage, format: &ImageOutputFormat) -> Option<DynamicImage> {
+    let name = match format {
+        ImageOutputFormat::JPEG(_) => "JPEG",
+        ImageOutputFormat::BMP => "BMP",
+        _ => return None,
+    };
+    if !has_alpha(img) {
+        return None;
+    }
+    if img.to_rgba().pixels().any(|x| x.data[3] < 255) {
+        output::warn(&format!(
+            "{name} has no alpha, so transparent areas are drawn over white. Set background to pick the colour or save as PNG to keep them."
+        ));
+    }
+    Some(flatten(img.clone(), [255; 4]))
+}
+
+/// Resizes `img` with its colours multiplied by their alpha, so colours hidden under transparent
+/// pixels do not bleed into the edges of what is visible.
+pub fn resize_premultiplied(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    filter: FilterType,
+) -> DynamicImage {
+    let mut rgba = img.to_rgba();
+    for pixel in rgba.pixels_mut() {
+        let alpha = f64::from(pixel.data[3]) / 255.0;
+        for channel in &mut pixel.data[..3] {
+            *channel = round(f64::from(*channel) * alpha);
+        }
+    }
+    let mut resized = DynamicImage::ImageRgba8(rgba)
+        .resize(width, height, filter)
+        .to_rgba();
+    for pixel in resized.pixels_mut() {
+        let alpha = f64::from(pixel.data[3]) / 255.0;
+        for channel in &mut pixel.data[..3] {
+            *channel = if alpha > 0.0 {
+                round(f64::from(*channel) / alpha)
+            } else {
+                0
+            };
+        }
+    }
+    DynamicImage::ImageRgba8(resized)
+}
+
+/// Crops away the rows and columns around the edges where every pixel matches `trim`. An image
+/// that matches everywhere is left alone.
+pub fn trim(mut img: DynamicImage, trim: &Trim) -> DynamicImage {
+    let Some((left, top, width, height)) = trim_box(&img, trim) else {
+        output::detail("Not trimming as the whole image matches");
+        return img;
+    };
+    output::detail(&format!("Trimming to {width}x{height} at {left},{top}"));
+    img.crop(left, top, width, height)
+}
+
+/// The left, top, width and height of what `trim` would keep of `img`, or nothing if every pixel
+/// matches.
+pub fn trim_box(img: &DynamicImage, trim: &Trim) -> Option<(u32, u32, u32, u32)> {
+    let matches = |pixel: [u8; 4]| match trim.colour {
+        Some(colour) => pixel
+            .iter()
+            .zip(&colour)
+            .all(|(channel, colour)| channel.abs_diff(*colour) <= trim.tolerance),
+        None => pixel[3] <= trim.tolerance,
+    };
+    let (width, height) = img.dimensions();
+    let (mut left, mut top, mut right, mut bottom) = (width, height, 0, 0);
+    for (x, y, pixel) in img.to_rgba().enumerate_pixels() {
+        if !matches(pixel.data) {
+            left = left.min(x);
+            top = top.min(y);
+            right = right.max(x + 1);
+            bottom = bottom.max(y + 1);
+        }
+    }
+    if right == 0 {
+        return None;
+    }
+    Some((left, top, right - left, bottom - top))
+}
+
+/// Adds a border of `pad.colour` around `img`, each side as wide as set.
+pub fn pad(img: &DynamicImage, pad: &Pad) -> Result<DynamicImage, String> {
+    let (width, height) = img.dimensions();
+    let (padded_width, padded_height) = width
+        .checked_add(pad.left)
+        .and_then(|x| x.checked_add(pad.right))
+        .zip(
+            height
+                .checked_add(pad.top)
+                .and_then(|x| x.checked_add(pad.bottom)),
+        )
+        .set_error("Padding makes the image too large.")?;
+    let mut padded = RgbaImage::from_pixel(padded_width, padded_height, Rgba(pad.colour));
+    padded.copy_from(&img.to_rgba(), pad.left, pad.top);
+    let padded = DynamicImage::ImageRgba8(padded);
+    Ok(if pad.colour[3] == 255 && !has_alpha(img) {
+        DynamicImage::ImageRgb8(padded.to_rgb())
+    } else {
+        padded
+    })
+}
+
+/// Makes everything outside the rounded corners and the mask transparent, with antialiased
+/// edges, then draws the border inside what is left. Mask images are stretched to fit `img`.
+pub fn shape(
+    img: &DynamicImage,
+    border: Option<&Border>,
+    radius: Option<u32>,
+    mask: Option<&Mask>,
+) -> Result<DynamicImage, String> {
+    let (width, height) = img.dimensions();
+    let mask_image = match mask {
+        Some(Mask::Image(path)) => Some(
+            image::open(path)
+                .set_error(&format!("Failed to open mask image {path}"))?
+                .resize_exact(width, height, FilterType::Triangle)
+                .to_rgba(),
+        ),
+        _ => None,
+    };
+    let (half_width, half_height) = (f64::from(width) / 2.0, f64::from(height) / 2.0);
+    let radius = f64::from(radius.unwrap_or(0)).min(half_width.min(half_height));
+    // How far each pixel's centre is outside the shape, negative inside it.
+    let distance = |x: u32, y: u32| -> f64 {
+        let (dx, dy) = (
+            f64::from(x) + 0.5 - half_width,
+            f64::from(y) + 0.5 - half_height,
+        );
+        let (qx, qy) = (
+            dx.abs() - (half_width - radius),
+            dy.abs() - (half_height - radius),
+        );
+        let rounded = qx.max(0.0).hypot(qy.max(0.0)) + qx.max(qy).min(0.0) - radius;
+        let masked = match mask {
+            Some(Mask::Circle) => dx.hypot(dy) - half_width.min(half_height),
+            // The ellipse's equation divided by the length of its gradient, which is close to
+            // the distance near the edge.
+            Some(Mask::Ellipse) => {
+                let (ex, ey) = (dx / half_width, dy / half_height);
+                let gradient = 2.0 * (ex / half_width).hypot(ey / half_height);
+                if gradient > 0.0 {
+                    (ex * ex + ey * ey - 1.0) / gradient
+                } else {
+                    -half_width.min(half_height)
+                }
+            }
+            _ => f64::NEG_INFINITY,
+        };
+        rounded.max(masked)
+    };
+    let mut rgba = img.to_rgba();
+    for (x, y, pixel) in rgba.enumerate_pixels_mut() {
+        let distance = distance(x, y);
+        if let Some(border) = border {
+            let amount = (distance + f64::from(border.width) + 0.5).clamp(0.0, 1.0);
+            let [red, green, blue, alpha] = border.colour;
+            pixel.data = over(
+                [red, green, blue, round(f64::from(alpha) * amount)],
+                pixel.data,
+            );
+        }
+        let mut coverage = (0.5 - distance).clamp(0.0, 1.0);
+        if let Some(mask_image) = &mask_image {
+            let [red, green, blue, alpha] = mask_image.get_pixel(x, y).data;
+            coverage *= colour::luma([red, green, blue]) / 255.0 * f64::from(alpha) / 255.0;
+        }
+        pixel.data[3] = round(f64::from(pixel.data[3]) * coverage);
+    }
+    Ok(DynamicImage::ImageRgba8(rgba))
+}
+
+/// Draws `top` over `bottom`, each with its own alpha.
+fn over(top: [u8; 4], bottom: [u8; 4]) -> [u8; 4] {
+    let top_alpha = f64::from(top[3]) / 255.0;
+    let bottom_alpha = f64::from(bottom[3]) / 255.0 * (1.0 - top_alpha);
+    let total = top_alpha + bottom_alpha;
+    let mut mixed = [0; 4];
+    for ((channel, top), bottom) in mixed.iter_mut().zip(top).zip(bottom).take(3) {
+        if total > 0.0 {
+            *channel =
+                round((f64::from(top) * top_alpha + f64::from(bottom) * bottom_alpha) / total);
+        }
+    }
+    mixed[3] = round(total * 255.0);
+    mixed
+}
+
+#[allow(clippy::cast_sign_loss)]
+fn round(value: f64) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    fn pad_of(size: u32, colour: [u8; 4]) -> Pad {
+        Pad {
+            top: size,
+            right: size,
+            bottom: size,
+            left: size,
+            colour,
+        }
+    }
+
+    #[test]
+    fn pad_adds_each_side() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(3, 2, image::Rgb([9, 9, 9])));
+        let sides = Pad {
+            top: 1,
+            right: 2,
+            bottom: 3,
+            left: 4,
+            colour: [255, 0, 0, 255],
+        };
+        let padded = pad(&img, &sides).unwrap();
+        assert_eq!(padded.dimensions(), (9, 6));
+        assert!(!has_alpha(&padded));
+        assert_eq!(padded.get_pixel(0, 0).data, [255, 0, 0, 255]);
+        assert_eq!(padded.get_pixel(4, 1).data, [9, 9, 9, 255]);
+    }
+
+    #[test]
+    fn pad_keeps_alpha_for_transparent_colours() {
+        let img = DynamicImage::ImageRgb8(RgbImage::new(2, 2));
+        assert!(has_alpha(&pad(&img, &pad_of(1, [0; 4])).unwrap()));
+    }
+
+    #[test]
+    fn pad_overflow_is_an_error() {
+        let img = DynamicImage::ImageRgb8(RgbImage::new(2, 2));
+        assert!(pad(&img, &pad_of(u32::MAX, [0; 4])).is_err());
+        assert!(pad(&img, &pad_of(u32::MAX / 2, [0; 4])).is_err());
+    }
+
+    #[test]
+    fn trim_crops_matching_edges() {
+        let mut rgba = RgbaImage::from_pixel(6, 5, Rgba([0; 4]));
+        rgba.put_pixel(2, 1, Rgba([1, 2, 3, 255]));
+        rgba.put_pixel(3, 3, Rgba([1, 2, 3, 10]));
+        let trimmed = trim(
+            DynamicImage::ImageRgba8(rgba.clone()),
+            &Trim {
+                colour: None,
+                tolerance: 0,
+            },
+        );
+        assert_eq!(trimmed.dimensions(), (2, 3));
+        let trimmed = trim(
+            DynamicImage::ImageRgba8(rgba),
+            &Trim {
+                colour: None,
+                tolerance: 10,
+            },
+        );
+        assert_eq!(trimmed.dimensions(), (1, 1));
+    }
+
+    #[test]
+    fn trim_by_colour_leaves_matching_images_alone() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, image::Rgb([250; 3])));
+        let trimmed = trim(
+            img,
+            &Trim {
+                colour: Some([255; 4]),
+                tolerance: 5,
+            },
+        );
+        assert_eq!(trimmed.dimensions(), (4, 4));
+    }
+}
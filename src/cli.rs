@@ -1,6 +1,16 @@
-use clap::{self, ArgMatches};
+use crate::{
+    parse::{
+        above_zero, border_from_str, bytes_from_str, colour_from_hex, convolve_from_str,
+        curve_from_str, dither_from_name, duotone_from_str, expand_glob, in_range, levels_from_str,
+        mask_from_str, not_negative, pad_from_str, resize_filter_from_name, tint_from_str,
+        trim_from_str, vignette_from_str, Animation, Curves, Encoding, Equalize, ImgEditJobs,
+        Palette, Placeholders, Resize, SharedSettings, Size,
+    },
+    serve::ServeOptions,
+};
+use clap::{self, Arg, ArgMatches};
 use read_input::prelude::*;
-use std::str::FromStr;
+use std::{fmt::Display, path::PathBuf, str::FromStr, time::Duration};
 
 #[derive(Debug)]
 pub enum Mode {
@@ -9,11 +19,33 @@ pub enum Mode {
 }
 use Mode::*;
 
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+    Debug,
+}
+
+impl Verbosity {
+    pub fn get(matches: &ArgMatches) -> Self {
+        if matches.is_present("quiet") {
+            Verbosity::Quiet
+        } else {
+            match matches.occurrences_of("verbose") {
+                0 => Verbosity::Normal,
+                1 => Verbosity::Verbose,
+                _ => Verbosity::Debug,
+            }
+        }
+    }
+}
+
 impl Mode {
     pub fn get(matches: &ArgMatches) -> Self {
         if matches.is_present("watch") {
             Mode::Watch
-        } else if matches.is_present("compile") {
+        } else if matches.is_present("compile") || matches.is_present("input") {
             Mode::Compile
         } else {
             input()
@@ -38,3 +70,463 @@ impl FromStr for Mode {
         }
     }
 }
+
+pub fn debounce(matches: &ArgMatches) -> Result<Duration, String> {
+    Ok(milliseconds(matches, "debounce")?.unwrap_or_else(|| Duration::from_millis(300)))
+}
+
+pub fn poll_interval(matches: &ArgMatches) -> Result<Option<Duration>, String> {
+    milliseconds(matches, "poll-interval")
+}
+
+pub fn livereload_port(matches: &ArgMatches) -> Result<u16, String> {
+    Ok(value(matches, "livereload-port")?.unwrap_or(35729))
+}
+
+fn milliseconds(matches: &ArgMatches, name: &str) -> Result<Option<Duration>, String> {
+    Ok(value(matches, name)?.map(Duration::from_millis))
+}
+
+/// Arguments for images given on the command line instead of in the config file, and for every
+/// job, named the same as in the config file.
+#[allow(clippy::too_many_lines)]
+pub fn job_args() -> Vec<Arg<'static, 'static>> {
+    fn value_arg(name: &'static str, help: &'static str) -> Arg<'static, 'static> {
+        Arg::with_name(name)
+            .long(name)
+            .value_name("VALUE")
+            .takes_value(true)
+            .help(help)
+    }
+    fn flag_arg(name: &'static str, help: &'static str) -> Arg<'static, 'static> {
+        Arg::with_name(name).long(name).help(help)
+    }
+    vec![
+        Arg::with_name("input")
+            .help(
+                "Images to transform instead of the files in the config file. Globs are expanded.",
+            )
+            .multiple(true),
+        Arg::with_name("output")
+            .long("output")
+            .short("o")
+            .value_name("PATH")
+            .takes_value(true)
+            .requires("input")
+            .help("Sets path to save the output image to. Only allowed with one input."),
+        value_arg("width", "Sets width while preserving aspect ratio."),
+        value_arg("height", "Sets height while preserving aspect ratio."),
+        value_arg("resize_filter", "Sets filter used when resizing.").possible_values(&[
+            "Nearest",
+            "Triangle",
+            "CatmullRom",
+            "Gaussian",
+            "Lanczos3",
+        ]),
+        value_arg(
+            "trim",
+            "Crops away borders that are transparent or a colour, like transparent or #ffffff,10.",
+        ),
+        value_arg(
+            "pad",
+            "Adds padding after resizing, like 10, 10,20 or 10,20,10,20,#ffffff.",
+        ),
+        value_arg(
+            "border",
+            "Draws a border inside the edges, like 4 or 4,#ffffff.",
+        ),
+        value_arg("rounded_corners", "Rounds the corners to this radius in pixels."),
+        value_arg(
+            "mask",
+            "Makes image transparent outside of circle, ellipse or the white of a mask image.",
+        ),
+        value_arg(
+            "background",
+            "Fills transparent areas with a colour, like #ffffff.",
+        ),
+        value_arg("blur", "Gaussian blurs image."),
+        value_arg("sharpen", "Sharpens image."),
+        value_arg("contrast", "Changes image contrast.").allow_hyphen_values(true),
+        value_arg("brighten", "Brightens image.").allow_hyphen_values(true),
+        value_arg("huerotate", "Rotates image hue.").allow_hyphen_values(true),
+        flag_arg("flipv", "Flips image vertically."),
+        flag_arg("fliph", "Flips image horizontally."),
+        flag_arg("rotate90", "Rotates image 90 degrees."),
+        flag_arg("rotate180", "Rotates image 180 degrees."),
+        flag_arg("rotate270", "Rotates image 270 degrees."),
+        flag_arg("grayscale", "Makes image grayscale."),
+        flag_arg("invert", "Inverts image."),
+        value_arg(
+            "saturation",
+            "Multiplies saturation, where 0 is grayscale and 1 is unchanged.",
+        ),
+        value_arg(
+            "gamma",
+            "Brightens midtones above 1 and darkens them below.",
+        ),
+        value_arg("exposure", "Changes exposure by stops.").allow_hyphen_values(true),
+        value_arg(
+            "levels",
+            "Maps input black and white to output black and white, like 10,245,0,255.",
+        ),
+        value_arg(
+            "curves",
+            "Applies a tone curve through input:output points, like 0:0,64:50,255:255.",
+        ),
+        value_arg(
+            "auto_levels",
+            "Stretches each channel to black and white, at a strength from 0 to 1.",
+        ),
+        value_arg(
+            "auto_contrast",
+            "Stretches contrast keeping colours balanced, at a strength from 0 to 1.",
+        ),
+        value_arg(
+            "equalize",
+            "Equalizes the histogram, at a strength from 0 to 1.",
+        ),
+        flag_arg(
+            "equalize_local",
+            "Equalizes in tiles instead of over the whole image, like CLAHE.",
+        )
+        .requires("equalize"),
+        value_arg(
+            "equalize_tiles",
+            "Sets the tiles across and down for --equalize_local. Defaults to 8.",
+        )
+        .requires("equalize_local"),
+        value_arg(
+            "equalize_clip_limit",
+            "Caps how many times the average count a brightness can have in a tile for --equalize_local, at least 1. Defaults to 2.",
+        )
+        .requires("equalize_local"),
+        value_arg("sepia", "Tones image brown, at a strength from 0 to 1."),
+        value_arg(
+            "tint",
+            "Mixes a colour into image, with an optional strength, like #ff8800,0.3.",
+        ),
+        value_arg(
+            "duotone",
+            "Maps image between a shadow and highlight colour, like #1b1b3a,#ffd166.",
+        ),
+        value_arg("posterize", "Keeps this many values per channel."),
+        value_arg("pixelate", "Averages image in blocks of this many pixels."),
+        value_arg(
+            "vignette",
+            "Fades the edges, with an optional radius and colour, like 0.4 or 0.4,0.6,#1b1b3a.",
+        ),
+        value_arg(
+            "convolve",
+            "Convolves image with a preset or square kernel, like edge_detect or 0,-1,0,-1,5,-1,0,-1,0.",
+        )
+        .allow_hyphen_values(true),
+        value_arg(
+            "max_bytes",
+            "Lowers JPEG quality until the saved image is at most this size, like 150KB. Other formats need --palette or --shrink_to_fit.",
+        ),
+        value_arg(
+            "min_quality",
+            "Sets the lowest quality tried to fit max_bytes.",
+        ),
+        value_arg(
+            "min_ssim",
+            "Sets the lowest SSIM kept when lowering JPEG quality, like 0.98.",
+        ),
+        flag_arg(
+            "shrink_to_fit",
+            "Scales image down if the lowest quality does not fit max_bytes.",
+        ),
+        value_arg("speed", "Multiplies how fast animated GIFs play."),
+        value_arg(
+            "frame_step",
+            "Keeps one of every this many frames of animated GIFs.",
+        ),
+        value_arg(
+            "poster_frame",
+            "Saves only this frame of animated GIFs, counting from 0, as a still image. Without it they are saved as GIFs or APNGs with every frame.",
+        ),
+        value_arg(
+            "palette",
+            "Reduces image to this many colours, saved as an indexed PNG or GIF.",
+        ),
+        value_arg("dither", "Sets how the palette is dithered.").possible_values(&[
+            "floyd_steinberg",
+            "ordered",
+            "none",
+        ]),
+        Arg::with_name("script")
+            .long("script")
+            .value_name("PATH")
+            .takes_value(true)
+            .help("Runs a Rhai script on image after every other job."),
+    ]
+}
+
+/// The jobs set on the command line. These take priority over the config file.
+#[allow(clippy::too_many_lines)]
+pub fn shared_settings(matches: &ArgMatches) -> Result<SharedSettings, String> {
+    let width = value(matches, "width")?;
+    let height = value(matches, "height")?;
+    let equalize_tiles = above_zero("equalize_tiles", value(matches, "equalize_tiles")?)?;
+    let equalize_clip_limit = match value(matches, "equalize_clip_limit")? {
+        Some(x) if x < 1.0 => {
+            return Err(String::from(
+                "equalize_clip_limit value is not valid: Must be at least 1",
+            ))
+        }
+        x => x.unwrap_or(2.0),
+    };
+    Ok(SharedSettings {
+        jobs: ImgEditJobs {
+            trim: matches.value_of("trim").map(trim_from_str).transpose()?,
+            resize: match (width, height) {
+                (Some(width), Some(height)) => Some(Size::WidthHeight(width, height)),
+                (Some(width), None) => Some(Size::Width(width)),
+                (None, Some(height)) => Some(Size::Height(height)),
+                (None, None) => None,
+            }
+            .map(|size| Resize { size }),
+            pad: matches.value_of("pad").map(pad_from_str).transpose()?,
+            blur: value(matches, "blur")?,
+            sharpen: value(matches, "sharpen")?,
+            adjust_contrast: value(matches, "contrast")?,
+            brighten: value(matches, "brighten")?,
+            huerotate: value(matches, "huerotate")?,
+            flipv: matches.is_present("flipv"),
+            fliph: matches.is_present("fliph"),
+            rotate90: matches.is_present("rotate90"),
+            rotate180: matches.is_present("rotate180"),
+            rotate270: matches.is_present("rotate270"),
+            grayscale: matches.is_present("grayscale"),
+            invert: matches.is_present("invert"),
+            saturation: not_negative("saturation", value(matches, "saturation")?)?,
+            gamma: above_zero("gamma", value(matches, "gamma")?)?,
+            exposure: value(matches, "exposure")?,
+            levels: matches
+                .value_of("levels")
+                .map(levels_from_str)
+                .transpose()?,
+            curves: match matches.value_of("curves") {
+                Some(x) => Some(Curves {
+                    rgb: curve_from_str(x)?,
+                    ..Curves::default()
+                }),
+                None => None,
+            },
+            auto_levels: strength(matches, "auto_levels")?,
+            auto_contrast: strength(matches, "auto_contrast")?,
+            equalize: strength(matches, "equalize")?.map(|strength| Equalize {
+                strength,
+                local: matches.is_present("equalize_local"),
+                tiles: equalize_tiles.unwrap_or(8),
+                clip_limit: equalize_clip_limit,
+            }),
+            sepia: strength(matches, "sepia")?,
+            tint: matches.value_of("tint").map(tint_from_str).transpose()?,
+            duotone: matches
+                .value_of("duotone")
+                .map(duotone_from_str)
+                .transpose()?,
+            posterize: in_range("posterize", value(matches, "posterize")?, 2..=256)?,
+            pixelate: above_zero("pixelate", value(matches, "pixelate")?)?,
+            vignette: matches
+                .value_of("vignette")
+                .map(vignette_from_str)
+                .transpose()?,
+            convolve: matches
+                .value_of("convolve")
+                .map(convolve_from_str)
+                .transpose()?,
+            border: matches
+                .value_of("border")
+                .map(border_from_str)
+                .transpose()?,
+            rounded_corners: value(matches, "rounded_corners")?,
+            mask: matches.value_of("mask").map(mask_from_str),
+            background: matches
+                .value_of("background")
+                .map(colour_from_hex)
+                .transpose()?,
+            script: matches.value_of("script").map(String::from),
+        },
+        resize_filter: match matches.value_of("resize_filter") {
+            Some(x) => Some(resize_filter_from_name(x)?),
+            None => None,
+        },
+        after_save: None,
+        placeholders: Placeholders::default(),
+        encoding: Encoding {
+            max_bytes: match matches.value_of("max_bytes") {
+                Some(x) => Some(
+                    bytes_from_str(x)
+                        .map_err(|e| format!("max_bytes value {x} is not valid: {e}"))?,
+                ),
+                None => None,
+            },
+            min_quality: in_range(
+                "min_quality",
+                value::<u32>(matches, "min_quality")?,
+                1..=100,
+            )?
+            .map(|x| x as u8),
+            min_ssim: in_range("min_ssim", value(matches, "min_ssim")?, 0.0..=1.0)?,
+            shrink_to_fit: Some(matches.is_present("shrink_to_fit")).filter(|x| *x),
+            palette: match (
+                in_range("palette", value(matches, "palette")?, 2..=256)?,
+                matches
+                    .value_of("dither")
+                    .map(dither_from_name)
+                    .transpose()?,
+            ) {
+                (None, None) => None,
+                (colors, dither) => Some(Palette { colors, dither }),
+            },
+        },
+        animation: Animation {
+            speed: above_zero("speed", value(matches, "speed")?)?,
+            frame_step: above_zero("frame_step", value(matches, "frame_step")?)?,
+            poster_frame: value(matches, "poster_frame")?,
+        },
+    })
+}
+
+pub fn serve_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("root")
+            .long("root")
+            .value_name("FOLDER")
+            .takes_value(true)
+            .default_value(".")
+            .help("Sets the folder to serve images from."),
+        Arg::with_name("port")
+            .long("port")
+            .value_name("PORT")
+            .takes_value(true)
+            .default_value("8080")
+            .help("Sets the port to listen on."),
+        Arg::with_name("max-size")
+            .long("max-size")
+            .value_name("PIXELS")
+            .takes_value(true)
+            .default_value("4096")
+            .help("Sets the largest width or height that can be requested."),
+        Arg::with_name("allowed-sizes")
+            .long("allowed-sizes")
+            .value_name("PIXELS")
+            .takes_value(true)
+            .use_delimiter(true)
+            .help("Only allows these widths and heights to be requested, separated by commas."),
+        Arg::with_name("cache-size")
+            .long("cache-size")
+            .value_name("MEGABYTES")
+            .takes_value(true)
+            .default_value("64")
+            .help("Sets how much memory transformed images can be cached in."),
+        Arg::with_name("cache-dir")
+            .long("cache-dir")
+            .value_name("FOLDER")
+            .takes_value(true)
+            .help("Also caches transformed images in this folder."),
+        Arg::with_name("cache-dir-size")
+            .long("cache-dir-size")
+            .value_name("MEGABYTES")
+            .takes_value(true)
+            .default_value("1024")
+            .help("Sets how much space transformed images can take up in the cache folder."),
+    ]
+}
+
+pub fn serve_options(matches: &ArgMatches) -> Result<ServeOptions, String> {
+    let mut allowed_sizes = Vec::new();
+    for size in matches.values_of("allowed-sizes").into_iter().flatten() {
+        allowed_sizes.push(
+            size.parse()
+                .map_err(|e| format!("allowed-sizes value {size} is not valid: {e}"))?,
+        );
+    }
+    Ok(ServeOptions {
+        root: PathBuf::from(matches.value_of("root").unwrap_or(".")),
+        port: value(matches, "port")?.unwrap_or(8080),
+        max_size: value(matches, "max-size")?.unwrap_or(4096),
+        allowed_sizes,
+        cache_bytes: value::<usize>(matches, "cache-size")?.unwrap_or(64) * 1024 * 1024,
+        cache_dir: matches.value_of("cache-dir").map(PathBuf::from),
+        cache_dir_bytes: value::<u64>(matches, "cache-dir-size")?.unwrap_or(1024) * 1024 * 1024,
+    })
+}
+
+/// The images given on the command line, with any globs expanded.
+pub fn inputs(matches: &ArgMatches) -> Result<Vec<String>, String> {
+    let mut inputs = Vec::new();
+    for input in matches.values_of("input").into_iter().flatten() {
+        inputs.extend(expand_glob(input)?);
+    }
+    if inputs.len() > 1 && matches.is_present("output") {
+        return Err(String::from("--output can only be used with one input."));
+    }
+    Ok(inputs)
+}
+
+fn strength(matches: &ArgMatches, name: &str) -> Result<Option<f32>, String> {
+    in_range(name, value(matches, name)?, 0.0..=1.0)
+}
+
+fn value<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>, String>
+where
+    T::Err: Display,
+{
+    match matches.value_of(name) {
+        Some(x) => {
+            Ok(Some(x.parse().map_err(|e| {
+                format!("{name} value {x} is not valid: {e}")
+            })?))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::App;
+
+    fn settings(args: &[&str]) -> Result<SharedSettings, String> {
+        let matches = App::new("test")
+            .args(&job_args())
+            .get_matches_from_safe(std::iter::once("test").chain(args.iter().copied()))
+            .map_err(|e| e.message)?;
+        shared_settings(&matches)
+    }
+
+    #[test]
+    fn negative_values_are_not_flags() {
+        let settings = settings(&["--brighten", "-20", "--contrast", "-1.5", "x.png"]).unwrap();
+        assert_eq!(settings.jobs.brighten, Some(-20));
+        assert_eq!(settings.jobs.adjust_contrast, Some(-1.5));
+        let settings = self::settings(&["--huerotate=-90", "--exposure", "-1"]).unwrap();
+        assert_eq!(settings.jobs.huerotate, Some(-90));
+    }
+
+    #[test]
+    fn ranges_match_the_config_file() {
+        assert!(settings(&["--min_quality", "0"]).is_err());
+        assert!(settings(&["--min_quality", "300"]).is_err());
+        assert_eq!(
+            settings(&["--min_quality", "40"])
+                .unwrap()
+                .encoding
+                .min_quality,
+            Some(40)
+        );
+        assert!(settings(&["--min_ssim", "1.5"]).is_err());
+        assert!(settings(&["--min_ssim=-0.5"]).is_err());
+        assert_eq!(
+            settings(&["--min_ssim", "0.98"]).unwrap().encoding.min_ssim,
+            Some(0.98)
+        );
+        assert!(settings(&["--gamma", "0"]).is_err());
+        assert!(settings(&["--palette", "1"]).is_err());
+        assert!(settings(&["--frame_step", "0"]).is_err());
+        assert!(settings(&["--sepia", "2"]).is_err());
+    }
+}
@@ -0,0 +1,295 @@
+use crate::parse::{Curves, Levels};
+use image::DynamicImage;
+
+/// A new value for every possible value of a channel.
+type Lut = [u8; 256];
+
+/// Changes every pixel's red, green and blue with `f`, keeping alpha. RGB images stay RGB and
+/// everything else becomes RGBA, so a curve on one channel can colour a grayscale image.
+pub fn map_rgb(img: DynamicImage, f: impl Fn([u8; 3]) -> [u8; 3]) -> DynamicImage {
+    map_rgb_at(img, |_, _, rgb| f(rgb))
+}
+
+/// Like `map_rgb`, also giving `f` the position of each pixel.
+pub fn map_rgb_at(img: DynamicImage, f: impl Fn(u32, u32, [u8; 3]) -> [u8; 3]) -> DynamicImage {
+    match img {
+        DynamicImage::ImageRgb8(mut rgb) => {
+            for (x, y, pixel) in rgb.enumerate_pixels_mut() {
+                pixel.data = f(x, y, pixel.data);
+            }
+            DynamicImage::ImageRgb8(rgb)
+        }
+        img => {
+            let mut rgba = img.to_rgba();
+            for (x, y, pixel) in rgba.enumerate_pixels_mut() {
+                let [red, green, blue, alpha] = pixel.data;
+                let [red, green, blue] = f(x, y, [red, green, blue]);
+                pixel.data = [red, green, blue, alpha];
+            }
+            DynamicImage::ImageRgba8(rgba)
+        }
+    }
+}
+
+/// Applies `lut` to every colour channel.
+fn map_lut(img: DynamicImage, lut: &Lut) -> DynamicImage {
+    map_rgb(img, |[red, green, blue]| {
+        [
+            lut[usize::from(red)],
+            lut[usize::from(green)],
+            lut[usize::from(blue)],
+        ]
+    })
+}
+
+/// Builds a table by calling `f` with every value scaled to between 0 and 1, clamping its
+/// result back to a channel value.
+#[allow(clippy::cast_sign_loss)]
+fn lut(f: impl Fn(f64) -> f64) -> Lut {
+    let mut lut = [0; 256];
+    for (value, entry) in (0_u8..=255).zip(lut.iter_mut()) {
+        *entry = (f(f64::from(value) / 255.0) * 255.0)
+            .round()
+            .clamp(0.0, 255.0) as u8;
+    }
+    lut
+}
+
+/// Moves every colour towards or away from its luma by `amount`.
+#[allow(clippy::cast_sign_loss)]
+pub fn saturation(img: DynamicImage, amount: f32) -> DynamicImage {
+    let amount = f64::from(amount);
+    map_rgb(img, |rgb| {
+        let luma = luma(rgb);
+        rgb.map(|x| {
+            (luma + (f64::from(x) - luma) * amount)
+                .round()
+                .clamp(0.0, 255.0) as u8
+        })
+    })
+}
+
+pub fn gamma(img: DynamicImage, gamma: f32) -> DynamicImage {
+    let exponent = 1.0 / f64::from(gamma);
+    map_lut(img, &lut(|x| x.powf(exponent)))
+}
+
+/// Multiplies the light in each channel by two to the power of `stops`, in linear light so
+/// highlights clip the way a camera's would.
+pub fn exposure(img: DynamicImage, stops: f32) -> DynamicImage {
+    let factor = 2_f64.powf(f64::from(stops));
+    map_lut(img, &lut(|x| to_srgb(to_linear(x) * factor)))
+}
+
+pub fn levels(img: DynamicImage, levels: &Levels) -> DynamicImage {
+    let [in_black, in_white, out_black, out_white] = [
+        levels.in_black,
+        levels.in_white,
+        levels.out_black,
+        levels.out_white,
+    ]
+    .map(|x| f64::from(x) / 255.0);
+    map_lut(
+        img,
+        &lut(|x| {
+            let x = ((x - in_black) / (in_white - in_black)).clamp(0.0, 1.0);
+            out_black + x * (out_white - out_black)
+        }),
+    )
+}
+
+pub fn curves(img: DynamicImage, curves: &Curves) -> DynamicImage {
+    let rgb = curve_lut(&curves.rgb);
+    let [red, green, blue] = [&curves.red, &curves.green, &curves.blue].map(|x| {
+        let channel = curve_lut(x);
+        let mut combined = [0; 256];
+        for (entry, value) in combined.iter_mut().zip(rgb.iter()) {
+            *entry = channel[usize::from(*value)];
+        }
+        combined
+    });
+    map_rgb(img, |[r, g, b]| {
+        [
+            red[usize::from(r)],
+            green[usize::from(g)],
+            blue[usize::from(b)],
+        ]
+    })
+}
+
+/// A smooth curve through `points` that never overshoots between them, using monotone cubic
+/// interpolation. Values outside the first and last points are held flat.
+fn curve_lut(points: &[(u8, u8)]) -> Lut {
+    let (Some(first), Some(last)) = (points.first(), points.last()) else {
+        return lut(|x| x);
+    };
+    let xs: Vec<f64> = points.iter().map(|x| f64::from(x.0)).collect();
+    let ys: Vec<f64> = points.iter().map(|x| f64::from(x.1)).collect();
+    let slopes: Vec<f64> = (0..points.len() - 1)
+        .map(|i| (ys[i + 1] - ys[i]) / (xs[i + 1] - xs[i]))
+        .collect();
+    // Fritsch-Butland tangents, flat wherever the curve turns.
+    let mut tangents = vec![0.0; points.len()];
+    tangents[0] = slopes.first().copied().unwrap_or(0.0);
+    tangents[points.len() - 1] = slopes.last().copied().unwrap_or(0.0);
+    for i in 1..points.len() - 1 {
+        let (before, after) = (slopes[i - 1], slopes[i]);
+        if before * after > 0.0 {
+            let (width_before, width_after) = (xs[i] - xs[i - 1], xs[i + 1] - xs[i]);
+            tangents[i] = 3.0 * (width_before + width_after)
+                / ((2.0 * width_after + width_before) / before
+                    + (width_after + 2.0 * width_before) / after);
+        }
+    }
+    lut(|x| {
+        let x = x * 255.0;
+        if x <= f64::from(first.0) {
+            return f64::from(first.1) / 255.0;
+        }
+        if x >= f64::from(last.0) {
+            return f64::from(last.1) / 255.0;
+        }
+        let i = xs.iter().rposition(|start| *start <= x).unwrap_or(0);
+        let width = xs[i + 1] - xs[i];
+        let t = (x - xs[i]) / width;
+        let (t2, t3) = (t * t, t * t * t);
+        let y = (2.0 * t3 - 3.0 * t2 + 1.0) * ys[i]
+            + (t3 - 2.0 * t2 + t) * width * tangents[i]
+            + (-2.0 * t3 + 3.0 * t2) * ys[i + 1]
+            + (t3 - t2) * width * tangents[i + 1];
+        y / 255.0
+    })
+}
+
+fn to_linear(x: f64) -> f64 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn to_srgb(x: f64) -> f64 {
+    if x <= 0.003_130_8 {
+        x * 12.92
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Rec. 709 luma, from 0 to 255.
+pub fn luma([red, green, blue]: [u8; 3]) -> f64 {
+    0.2126 * f64::from(red) + 0.7152 * f64::from(green) + 0.0722 * f64::from(blue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayAlphaImage, LumaA, Rgb, RgbImage};
+
+    /// Every channel value once in each channel, in a different order in each.
+    fn ramp() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(256, 1, |x, _| {
+            Rgb([x as u8, 255 - x as u8, (x * 7 % 256) as u8])
+        }))
+    }
+
+    fn values(img: &DynamicImage) -> Vec<u8> {
+        img.to_rgb().into_raw()
+    }
+
+    #[test]
+    fn neutral_settings_change_nothing() {
+        let img = ramp();
+        assert_eq!(values(&gamma(img.clone(), 1.0)), values(&img));
+        assert_eq!(values(&exposure(img.clone(), 0.0)), values(&img));
+        assert_eq!(values(&saturation(img.clone(), 1.0)), values(&img));
+        let identity = Curves {
+            rgb: vec![(0, 0), (255, 255)],
+            ..Curves::default()
+        };
+        assert_eq!(values(&curves(img.clone(), &identity)), values(&img));
+        assert_eq!(
+            values(&curves(img.clone(), &Curves::default())),
+            values(&img)
+        );
+        let full = Levels {
+            in_black: 0,
+            in_white: 255,
+            out_black: 0,
+            out_white: 255,
+        };
+        assert_eq!(values(&levels(img.clone(), &full)), values(&img));
+    }
+
+    #[test]
+    fn levels_stretch_and_clip() {
+        let lut = |levels: &Levels| {
+            let img =
+                DynamicImage::ImageRgb8(RgbImage::from_fn(256, 1, |x, _| Rgb([x as u8, 0, 0])));
+            let mapped = super::levels(img, levels).to_rgb();
+            move |x: u32| mapped.get_pixel(x, 0).data[0]
+        };
+        let stretch = lut(&Levels {
+            in_black: 50,
+            in_white: 200,
+            out_black: 0,
+            out_white: 255,
+        });
+        assert_eq!(
+            [
+                stretch(0),
+                stretch(50),
+                stretch(140),
+                stretch(200),
+                stretch(255)
+            ],
+            [0, 0, 153, 255, 255]
+        );
+        let squash = lut(&Levels {
+            in_black: 0,
+            in_white: 255,
+            out_black: 100,
+            out_white: 200,
+        });
+        assert_eq!([squash(0), squash(255)], [100, 200]);
+    }
+
+    #[test]
+    fn curves_pass_through_their_points_without_overshooting() {
+        let lut = curve_lut(&[(0, 0), (64, 50), (128, 200), (255, 255)]);
+        assert_eq!([lut[0], lut[64], lut[128], lut[255]], [0, 50, 200, 255]);
+        assert!(lut.windows(2).all(|x| x[0] <= x[1]));
+        let flat = curve_lut(&[(100, 20), (200, 220)]);
+        assert_eq!(
+            [flat[0], flat[100], flat[200], flat[255]],
+            [20, 20, 220, 220]
+        );
+    }
+
+    #[test]
+    fn gamma_and_exposure_brighten_midtones() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(1, 1, Rgb([0, 128, 255])));
+        let brighter = values(&gamma(img.clone(), 2.0));
+        assert!(brighter[0] == 0 && brighter[1] > 128 && brighter[2] == 255);
+        let exposed = values(&exposure(img, 1.0));
+        assert!(exposed[0] == 0 && exposed[1] > 128 && exposed[2] == 255);
+    }
+
+    #[test]
+    fn no_saturation_is_grey() {
+        for [red, green, blue] in values(&saturation(ramp(), 0.0))
+            .chunks(3)
+            .map(|x| [x[0], x[1], x[2]])
+        {
+            assert!(red == green && green == blue);
+        }
+    }
+
+    #[test]
+    fn map_rgb_keeps_alpha() {
+        let img = DynamicImage::ImageLumaA8(GrayAlphaImage::from_pixel(1, 1, LumaA([10, 77])));
+        let mapped = map_rgb(img, |[red, green, blue]| [red, green + 1, blue + 2]);
+        assert_eq!(mapped.to_rgba().get_pixel(0, 0).data, [10, 11, 12, 77]);
+    }
+}
@@ -0,0 +1,219 @@
+use crate::{output, parse::ContactSheet};
+use embedded_graphics::{
+    geometry::{OriginDimensions, Point, Size},
+    mono_font::{ascii::FONT_6X10, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    text::{Baseline, Text},
+    Drawable, Pixel,
+};
+use image::{imageops, DynamicImage, FilterType, Rgba, RgbaImage};
+use set_error::ChangeError;
+use std::{convert::Infallible, convert::TryFrom, path::Path};
+
+/// Space under each image for its caption.
+const CAPTION_HEIGHT: u32 = 14;
+
+/// Scales every image in `sheet` to fit its cell, keeping aspect ratio, and lays them out left to
+/// right then top to bottom.
+pub fn build(sheet: &ContactSheet) -> Result<DynamicImage, String> {
+    if sheet.images.is_empty() {
+        return Err(format!("No images match {}", sheet.patterns.join(", ")));
+    }
+    let count = sheet.images.len() as u32;
+    let columns = sheet.columns.min(count).max(1);
+    let rows = count.div_ceil(columns);
+    let cell_height = sheet.cell_height + if sheet.captions { CAPTION_HEIGHT } else { 0 };
+    let mut canvas = RgbaImage::from_pixel(
+        columns * (sheet.cell_width + sheet.padding) + sheet.padding,
+        rows * (cell_height + sheet.padding) + sheet.padding,
+        Rgba(sheet.background),
+    );
+    output::detail(&format!(
+        "Laying out {count} images in {columns} columns and {rows} rows"
+    ));
+    for (index, path) in sheet.images.iter().enumerate() {
+        let index = index as u32;
+        let img = image::open(path).set_error(&format!("failed to open file {path}"))?;
+        let thumbnail = img
+            .resize(sheet.cell_width, sheet.cell_height, FilterType::Triangle)
+            .to_rgba();
+        let x = sheet.padding + (index % columns) * (sheet.cell_width + sheet.padding);
+        let y = sheet.padding + (index / columns) * (cell_height + sheet.padding);
+        imageops::overlay(
+            &mut canvas,
+            &thumbnail,
+            x + (sheet.cell_width - thumbnail.width()) / 2,
+            y + (sheet.cell_height - thumbnail.height()) / 2,
+        );
+        if sheet.captions {
+            let name = Path::new(path)
+                .file_name()
+                .map_or_else(|| path.clone(), |x| x.to_string_lossy().into_owned());
+            caption(
+                &mut canvas,
+                &name,
+                x,
+                y + sheet.cell_height + 2,
+                sheet.cell_width,
+                contrasting(sheet.background),
+            );
+        }
+    }
+    Ok(DynamicImage::ImageRgba8(canvas))
+}
+
+/// Writes `text` centred in the `width` pixels from `x`, cutting it short if it does not fit.
+fn caption(canvas: &mut RgbaImage, text: &str, x: u32, y: u32, width: u32, colour: Rgba<u8>) {
+    let char_width = FONT_6X10.character_size.width + FONT_6X10.character_spacing;
+    let text = shorten(text, (width / char_width) as usize);
+    let text_width = text.chars().count() as u32 * char_width;
+    let position = Point::new(
+        i32::try_from(x + width.saturating_sub(text_width) / 2).unwrap_or(i32::MAX),
+        i32::try_from(y).unwrap_or(i32::MAX),
+    );
+    let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+    Text::with_baseline(&text, position, style, Baseline::Top)
+        .draw(&mut Canvas { canvas, colour })
+        .ok();
+}
+
+/// `text` cut to `max_chars`, ending in `..` when anything was cut.
+fn shorten(text: &str, max_chars: usize) -> String {
+    if text.chars().count() > max_chars {
+        text.chars()
+            .take(max_chars.saturating_sub(2))
+            .chain("..".chars())
+            .take(max_chars)
+            .collect()
+    } else {
+        text.to_string()
+    }
+}
+
+/// Black on light backgrounds and white on dark ones.
+fn contrasting(background: [u8; 4]) -> Rgba<u8> {
+    let [r, g, b, _] = background;
+    let luma = u32::from(r) * 299 + u32::from(g) * 587 + u32::from(b) * 114;
+    if luma > 128_000 {
+        Rgba([0, 0, 0, 255])
+    } else {
+        Rgba([255, 255, 255, 255])
+    }
+}
+
+/// Lets text be drawn onto an image in one colour.
+struct Canvas<'a> {
+    canvas: &'a mut RgbaImage,
+    colour: Rgba<u8>,
+}
+
+impl OriginDimensions for Canvas<'_> {
+    fn size(&self) -> Size {
+        Size::new(self.canvas.width(), self.canvas.height())
+    }
+}
+
+impl embedded_graphics::draw_target::DrawTarget for Canvas<'_> {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, colour) in pixels {
+            if let (BinaryColor::On, Ok(x), Ok(y)) =
+                (colour, u32::try_from(point.x), u32::try_from(point.y))
+            {
+                if x < self.canvas.width() && y < self.canvas.height() {
+                    self.canvas.put_pixel(x, y, self.colour);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const WHITE: [u8; 4] = [255; 4];
+
+    fn sheet(name: &str, images: usize) -> ContactSheet {
+        let folder = std::env::temp_dir().join(format!("image-watcher-sheet-{name}"));
+        std::fs::create_dir_all(&folder).unwrap();
+        let path = folder.join("wide.png");
+        RgbaImage::from_pixel(20, 10, Rgba(RED))
+            .save(&path)
+            .unwrap();
+        ContactSheet {
+            patterns: vec![String::from("*.png")],
+            images: vec![path.to_string_lossy().into_owned(); images],
+            output: String::from("sheet.png"),
+            columns: 2,
+            cell_width: 20,
+            cell_height: 20,
+            padding: 2,
+            background: WHITE,
+            captions: false,
+        }
+    }
+
+    #[test]
+    fn grid_has_a_cell_per_image() {
+        let img = build(&sheet("grid", 3)).unwrap().to_rgba();
+        assert_eq!(img.dimensions(), (46, 46));
+        // The third image starts the second row, leaving the cell beside it empty.
+        assert_eq!(img.get_pixel(12, 24 + 10).data, RED);
+        assert_eq!(img.get_pixel(34, 24 + 10).data, WHITE);
+        let img = build(&ContactSheet {
+            columns: 5,
+            ..sheet("grid", 3)
+        })
+        .unwrap();
+        assert_eq!(img.dimensions(), (68, 24));
+    }
+
+    #[test]
+    fn images_are_centred_in_their_cells() {
+        let img = build(&sheet("centre", 1)).unwrap().to_rgba();
+        let column: Vec<[u8; 4]> = (2..22).map(|y| img.get_pixel(12, y).data).collect();
+        assert_eq!(column[..5], [WHITE; 5]);
+        assert_eq!(column[5..15], [RED; 10]);
+        assert_eq!(column[15..], [WHITE; 5]);
+    }
+
+    #[test]
+    fn captions_add_height_under_each_image() {
+        let img = build(&ContactSheet {
+            captions: true,
+            ..sheet("captions", 2)
+        })
+        .unwrap();
+        assert_eq!(img.dimensions(), (46, 20 + CAPTION_HEIGHT + 4));
+    }
+
+    #[test]
+    fn long_captions_are_cut_short() {
+        assert_eq!(shorten("wide.png", 10), "wide.png");
+        assert_eq!(shorten("a_very_long_name.png", 10), "a_very_l..");
+        assert_eq!(shorten("name.png", 1), ".");
+        assert_eq!(shorten("name.png", 0), "");
+    }
+
+    #[test]
+    fn captions_are_centred() {
+        let mut canvas = RgbaImage::from_pixel(60, 12, Rgba(WHITE));
+        caption(&mut canvas, "ab", 0, 0, 60, Rgba(RED));
+        let drawn: Vec<u32> = canvas
+            .enumerate_pixels()
+            .filter(|(_, _, pixel)| pixel.data == RED)
+            .map(|(x, _, _)| x)
+            .collect();
+        assert!(!drawn.is_empty());
+        assert!(drawn.iter().all(|x| (24..36).contains(x)));
+    }
+}
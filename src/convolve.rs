@@ -0,0 +1,109 @@
+use crate::{colour, parse::Convolve};
+use image::{DynamicImage, RgbaImage};
+
+/// Sobel kernels for the change from left to right and from top to bottom.
+const SOBEL_X: [f32; 9] = [-1.0, 0.0, 1.0, -2.0, 0.0, 2.0, -1.0, 0.0, 1.0];
+const SOBEL_Y: [f32; 9] = [-1.0, -2.0, -1.0, 0.0, 0.0, 0.0, 1.0, 2.0, 1.0];
+
+/// Convolves the colour channels of `img`, keeping alpha. Pixels past the edges repeat the
+/// nearest edge pixel.
+pub fn apply(img: DynamicImage, convolve: &Convolve) -> DynamicImage {
+    let source = img.to_rgba();
+    match convolve {
+        Convolve::Kernel(kernel) => colour::map_rgb_at(img, |x, y, _| {
+            let sums = weighted_sum(&source, x, y, kernel.size, &kernel.weights);
+            round(sums.map(|sum| sum / kernel.divisor + kernel.offset))
+        }),
+        Convolve::Sobel => colour::map_rgb_at(img, |x, y, _| {
+            let along = weighted_sum(&source, x, y, 3, &SOBEL_X);
+            let down = weighted_sum(&source, x, y, 3, &SOBEL_Y);
+            let mut magnitude = [0.0; 3];
+            for ((channel, along), down) in magnitude.iter_mut().zip(along).zip(down) {
+                *channel = along.hypot(down);
+            }
+            round(magnitude)
+        }),
+    }
+}
+
+/// The sum of each colour channel around `x`, `y` times `weights`, a `size` by `size` kernel.
+fn weighted_sum(source: &RgbaImage, x: u32, y: u32, size: u32, weights: &[f32]) -> [f32; 3] {
+    let (width, height) = source.dimensions();
+    let reach = size / 2;
+    let mut sums = [0.0; 3];
+    for (index, weight) in weights.iter().enumerate() {
+        let index = index as u32;
+        let sample_x = (x + index % size).saturating_sub(reach).min(width - 1);
+        let sample_y = (y + index / size).saturating_sub(reach).min(height - 1);
+        let pixel = source.get_pixel(sample_x, sample_y).data;
+        for (sum, channel) in sums.iter_mut().zip(pixel.iter()) {
+            *sum += f32::from(*channel) * weight;
+        }
+    }
+    sums
+}
+
+#[allow(clippy::cast_sign_loss)]
+fn round(channels: [f32; 3]) -> [u8; 3] {
+    channels.map(|x| x.round().clamp(0.0, 255.0) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::Kernel;
+    use image::{Rgba, RgbaImage};
+
+    fn kernel(weights: Vec<f32>, divisor: f32, offset: f32) -> Convolve {
+        Convolve::Kernel(Kernel {
+            size: 3,
+            weights,
+            divisor,
+            offset,
+        })
+    }
+
+    /// A dark left half and a light right half, half transparent.
+    fn step() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(6, 4, |x, _| {
+            let value = if x < 3 { 20 } else { 220 };
+            Rgba([value, value, value, 128])
+        }))
+    }
+
+    #[test]
+    fn identity_kernel_changes_nothing() {
+        let identity = kernel(vec![0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0], 1.0, 0.0);
+        assert_eq!(
+            apply(step(), &identity).to_rgba().into_raw(),
+            step().to_rgba().into_raw()
+        );
+    }
+
+    #[test]
+    fn box_blur_averages_and_repeats_edges() {
+        let blurred = apply(step(), &kernel(vec![1.0; 9], 9.0, 0.0)).to_rgba();
+        // Edge pixels see themselves again past the edge, so flat areas stay flat.
+        assert_eq!(blurred.get_pixel(0, 0).data, [20, 20, 20, 128]);
+        assert_eq!(blurred.get_pixel(5, 3).data, [220, 220, 220, 128]);
+        // Two dark columns and one light.
+        assert_eq!(blurred.get_pixel(2, 1).data, [87, 87, 87, 128]);
+        assert_eq!(blurred.get_pixel(3, 1).data, [153, 153, 153, 128]);
+    }
+
+    #[test]
+    fn offset_is_added_after_dividing() {
+        let identity = kernel(vec![0.0, 0.0, 0.0, 0.0, 2.0, 0.0, 0.0, 0.0, 0.0], 2.0, 50.0);
+        let shifted = apply(step(), &identity).to_rgba();
+        assert_eq!(shifted.get_pixel(0, 0).data, [70, 70, 70, 128]);
+        assert_eq!(shifted.get_pixel(5, 0).data, [255, 255, 255, 128]);
+    }
+
+    #[test]
+    fn sobel_finds_only_edges() {
+        let edges = apply(step(), &Convolve::Sobel).to_rgba();
+        assert_eq!(edges.get_pixel(0, 1).data, [0, 0, 0, 128]);
+        assert_eq!(edges.get_pixel(5, 1).data, [0, 0, 0, 128]);
+        assert_eq!(edges.get_pixel(2, 1).data, [255, 255, 255, 128]);
+    }
+}
@@ -0,0 +1,270 @@
+use crate::{colour, parse::Equalize};
+use image::{DynamicImage, GenericImageView};
+
+/// Share of pixels at each end of a histogram treated as outliers when finding black and white.
+const CLIP_PERCENT: f64 = 0.5;
+
+type Histogram = [u64; 256];
+
+/// Stretches each channel on its own so its darkest and brightest values, ignoring outliers,
+/// become black and white. This also removes colour casts.
+pub fn auto_levels(img: DynamicImage, strength: f32) -> DynamicImage {
+    let mut histograms = [[0; 256]; 3];
+    for [red, green, blue] in opaque_pixels(&img) {
+        histograms[0][usize::from(red)] += 1;
+        histograms[1][usize::from(green)] += 1;
+        histograms[2][usize::from(blue)] += 1;
+    }
+    let [red, green, blue] = histograms.map(|x| blend(&stretch(&x), strength));
+    colour::map_rgb(img, |[r, g, b]| {
+        [
+            red[usize::from(r)],
+            green[usize::from(g)],
+            blue[usize::from(b)],
+        ]
+    })
+}
+
+/// Stretches every channel by the same amount, worked out from the luma, so colours keep their
+/// balance.
+pub fn auto_contrast(img: DynamicImage, strength: f32) -> DynamicImage {
+    let mut histogram = [0; 256];
+    for rgb in opaque_pixels(&img) {
+        histogram[usize::from(luma(rgb))] += 1;
+    }
+    let lut = blend(&stretch(&histogram), strength);
+    colour::map_rgb(img, |rgb| rgb.map(|x| lut[usize::from(x)]))
+}
+
+/// Spreads the luma out so every brightness is used about as often, either over the whole image
+/// or, if `local`, in each tile with the tile's contrast limited and the tiles blended together.
+pub fn equalize(img: DynamicImage, equalize: &Equalize) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return img;
+    }
+    let lumas: Vec<u8> = img.to_rgb().pixels().map(|x| luma(x.data)).collect();
+    let strength = f64::from(equalize.strength);
+    if !equalize.local {
+        let mut histogram = [0; 256];
+        for value in &lumas {
+            histogram[usize::from(*value)] += 1;
+        }
+        let lut = cumulative(&histogram, None);
+        return colour::map_rgb_at(img, |x, y, rgb| {
+            let value = lumas[(y * width + x) as usize];
+            shift(rgb, (lut[usize::from(value)] - f64::from(value)) * strength)
+        });
+    }
+    let tiles = equalize.tiles.min(width).min(height).max(1);
+    let (tile_width, tile_height) = (width.div_ceil(tiles), height.div_ceil(tiles));
+    let mut luts = Vec::new();
+    for tile_y in 0..tiles {
+        for tile_x in 0..tiles {
+            let mut histogram = [0; 256];
+            for y in tile_y * tile_height..((tile_y + 1) * tile_height).min(height) {
+                for x in tile_x * tile_width..((tile_x + 1) * tile_width).min(width) {
+                    histogram[usize::from(lumas[(y * width + x) as usize])] += 1;
+                }
+            }
+            luts.push(cumulative(&histogram, Some(equalize.clip_limit)));
+        }
+    }
+    // Where a pixel falls between the centres of the tiles on one axis, and how far along.
+    let between = |position: u32, size: u32| -> (u32, u32, f64) {
+        let centre = (f64::from(position) + 0.5) / f64::from(size) - 0.5;
+        let first = centre.floor().clamp(0.0, f64::from(tiles - 1));
+        #[allow(clippy::cast_sign_loss)]
+        let first_tile = first as u32;
+        (
+            first_tile,
+            (first_tile + 1).min(tiles - 1),
+            (centre - first).clamp(0.0, 1.0),
+        )
+    };
+    colour::map_rgb_at(img, |x, y, rgb| {
+        let value = lumas[(y * width + x) as usize];
+        let (left, right, along) = between(x, tile_width);
+        let (top, bottom, down) = between(y, tile_height);
+        let at =
+            |tile_x: u32, tile_y: u32| luts[(tile_y * tiles + tile_x) as usize][usize::from(value)];
+        let equalized = (at(left, top) * (1.0 - along) + at(right, top) * along) * (1.0 - down)
+            + (at(left, bottom) * (1.0 - along) + at(right, bottom) * along) * down;
+        shift(rgb, (equalized - f64::from(value)) * strength)
+    })
+}
+
+/// The colour of every pixel that is not fully transparent.
+fn opaque_pixels(img: &DynamicImage) -> Vec<[u8; 3]> {
+    img.to_rgba()
+        .pixels()
+        .filter(|x| x.data[3] > 0)
+        .map(|x| [x.data[0], x.data[1], x.data[2]])
+        .collect()
+}
+
+#[allow(clippy::cast_sign_loss)]
+fn luma(rgb: [u8; 3]) -> u8 {
+    colour::luma(rgb).round().clamp(0.0, 255.0) as u8
+}
+
+/// Maps the values at `CLIP_PERCENT` from each end of `histogram` to black and white.
+#[allow(clippy::cast_precision_loss, clippy::cast_sign_loss)]
+fn stretch(histogram: &Histogram) -> [f64; 256] {
+    let total: u64 = histogram.iter().sum();
+    let clipped = (total as f64 * CLIP_PERCENT / 100.0) as u64;
+    let percentile = |values: Box<dyn Iterator<Item = usize>>| {
+        let mut count = 0;
+        for value in values {
+            count += histogram[value];
+            if count > clipped {
+                return value;
+            }
+        }
+        0
+    };
+    let black = percentile(Box::new(0..256));
+    let white = percentile(Box::new((0..256).rev()));
+    let mut lut = [0.0; 256];
+    for (value, entry) in lut.iter_mut().enumerate() {
+        *entry = if white > black {
+            ((value as f64 - black as f64) / (white - black) as f64 * 255.0).clamp(0.0, 255.0)
+        } else {
+            value as f64
+        };
+    }
+    lut
+}
+
+/// Equalizes `histogram`, first cutting each count to `clip_limit` times the average and sharing
+/// what was cut between every value, if given.
+#[allow(clippy::cast_precision_loss)]
+fn cumulative(histogram: &Histogram, clip_limit: Option<f32>) -> [f64; 256] {
+    let total: u64 = histogram.iter().sum();
+    let mut counts = histogram.map(|x| x as f64);
+    if let Some(clip_limit) = clip_limit {
+        let limit = (f64::from(clip_limit) * total as f64 / 256.0).max(1.0);
+        let excess: f64 = counts.iter().map(|x| (x - limit).max(0.0)).sum();
+        for count in &mut counts {
+            *count = count.min(limit) + excess / 256.0;
+        }
+    }
+    let mut lut = [0.0; 256];
+    let first = counts.iter().copied().find(|x| *x > 0.0).unwrap_or(0.0);
+    if total as f64 <= first {
+        // Only one brightness is used, so there is nothing to spread out.
+        for (value, entry) in lut.iter_mut().enumerate() {
+            *entry = value as f64;
+        }
+        return lut;
+    }
+    let mut sum = 0.0;
+    for (count, entry) in counts.iter().zip(lut.iter_mut()) {
+        sum += count;
+        *entry = ((sum - first) / (total as f64 - first) * 255.0).clamp(0.0, 255.0);
+    }
+    lut
+}
+
+/// Mixes `lut` with leaving values unchanged, rounding back to channel values.
+#[allow(clippy::cast_sign_loss, clippy::cast_precision_loss)]
+fn blend(lut: &[f64; 256], strength: f32) -> [u8; 256] {
+    let mut blended = [0; 256];
+    for (value, (entry, target)) in blended.iter_mut().zip(lut).enumerate() {
+        let value = value as f64;
+        *entry = (value + (target - value) * f64::from(strength))
+            .round()
+            .clamp(0.0, 255.0) as u8;
+    }
+    blended
+}
+
+/// Brightens every channel by `amount`, which keeps the hue of most colours.
+#[allow(clippy::cast_sign_loss)]
+fn shift(rgb: [u8; 3], amount: f64) -> [u8; 3] {
+    rgb.map(|x| (f64::from(x) + amount).round().clamp(0.0, 255.0) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn grey(width: u32, height: u32, value: impl Fn(u32, u32) -> u8) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let value = value(x, y);
+            Rgb([value, value, value])
+        }))
+    }
+
+    fn values(img: &DynamicImage) -> Vec<u8> {
+        img.to_rgb().into_raw()
+    }
+
+    #[test]
+    fn auto_levels_ignores_outliers() {
+        // Two pixels at each end are under half a percent of the thousand.
+        let img = grey(1000, 1, |x, _| match x {
+            0..=1 => 0,
+            998.. => 255,
+            x => (50 + (x - 2) * 150 / 995) as u8,
+        });
+        let stretched = auto_levels(img, 1.0).to_rgb();
+        assert_eq!(stretched.get_pixel(2, 0).data, [0, 0, 0]);
+        assert_eq!(stretched.get_pixel(997, 0).data, [255, 255, 255]);
+        assert_eq!(stretched.get_pixel(0, 0).data, [0, 0, 0]);
+        assert_eq!(stretched.get_pixel(999, 0).data, [255, 255, 255]);
+    }
+
+    #[test]
+    fn strength_zero_changes_nothing() {
+        let img = grey(32, 32, |x, y| (60 + x * 2 + y) as u8);
+        assert_eq!(values(&auto_levels(img.clone(), 0.0)), values(&img));
+        assert_eq!(values(&auto_contrast(img.clone(), 0.0)), values(&img));
+        for local in [false, true] {
+            let equalize_settings = Equalize {
+                strength: 0.0,
+                local,
+                tiles: 4,
+                clip_limit: 2.0,
+            };
+            assert_eq!(
+                values(&equalize(img.clone(), &equalize_settings)),
+                values(&img)
+            );
+        }
+    }
+
+    #[test]
+    fn one_unclipped_tile_is_global_equalization() {
+        let img = grey(32, 16, |x, y| (x * 3 + y * 5) as u8);
+        let settings = |local| Equalize {
+            strength: 1.0,
+            local,
+            tiles: 1,
+            clip_limit: 1000.0,
+        };
+        let global = equalize(img.clone(), &settings(false));
+        assert_ne!(values(&global), values(&img));
+        assert_eq!(values(&equalize(img, &settings(true))), values(&global));
+    }
+
+    #[test]
+    fn flat_images_stay_flat() {
+        let img = grey(64, 64, |_, _| 128);
+        for local in [false, true] {
+            let equalized = equalize(
+                img.clone(),
+                &Equalize {
+                    strength: 1.0,
+                    local,
+                    tiles: 8,
+                    clip_limit: 2.0,
+                },
+            );
+            let equalized = values(&equalized);
+            assert!(equalized.iter().all(|x| *x == equalized[0]));
+            assert!(equalized[0].abs_diff(128) <= 2);
+        }
+    }
+}
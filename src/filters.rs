@@ -0,0 +1,210 @@
+use crate::{
+    colour,
+    parse::{Duotone, Tint, Vignette},
+};
+use image::{DynamicImage, GenericImageView, ImageBuffer, Pixel};
+
+/// Tones the image brown like an old photograph, mixed with the original by `strength`.
+pub fn sepia(img: DynamicImage, strength: f32) -> DynamicImage {
+    colour::map_rgb(img, |rgb| {
+        let [red, green, blue] = rgb.map(f64::from);
+        let sepia = [
+            0.393 * red + 0.769 * green + 0.189 * blue,
+            0.349 * red + 0.686 * green + 0.168 * blue,
+            0.272 * red + 0.534 * green + 0.131 * blue,
+        ];
+        mix(rgb, sepia, strength)
+    })
+}
+
+/// Mixes every pixel with the tint's colour by its strength.
+pub fn tint(img: DynamicImage, tint: &Tint) -> DynamicImage {
+    let colour = rgb(tint.colour);
+    colour::map_rgb(img, |rgb| mix(rgb, colour, tint.strength))
+}
+
+/// Replaces every colour with one between the two duotone colours, picked by its luma.
+pub fn duotone(img: DynamicImage, duotone: &Duotone) -> DynamicImage {
+    let [red, green, blue, _] = duotone.shadows;
+    let highlights = rgb(duotone.highlights);
+    colour::map_rgb(img, |rgb| {
+        mix(
+            [red, green, blue],
+            highlights,
+            (colour::luma(rgb) / 255.0) as f32,
+        )
+    })
+}
+
+/// Rounds every channel to one of `levels` evenly spaced values.
+#[allow(clippy::cast_sign_loss)]
+pub fn posterize(img: DynamicImage, levels: u32) -> DynamicImage {
+    let step = 255.0 / f64::from(levels - 1);
+    colour::map_rgb(img, |rgb| {
+        rgb.map(|x| {
+            ((f64::from(x) / step).round() * step)
+                .round()
+                .clamp(0.0, 255.0) as u8
+        })
+    })
+}
+
+/// Fills every `size` by `size` block with its average colour, including alpha.
+pub fn pixelate(img: DynamicImage, size: u32) -> DynamicImage {
+    match img {
+        DynamicImage::ImageRgb8(rgb) => DynamicImage::ImageRgb8(pixelate_buffer(&rgb, size)),
+        img => DynamicImage::ImageRgba8(pixelate_buffer(&img.to_rgba(), size)),
+    }
+}
+
+/// Fades the edges towards the vignette's colour, starting at `radius` of the way from the centre
+/// to the corners and reaching `strength` at the corners.
+pub fn vignette(img: DynamicImage, vignette: &Vignette) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    let centre = (f64::from(width) / 2.0, f64::from(height) / 2.0);
+    let corner = (centre.0 * centre.0 + centre.1 * centre.1).sqrt().max(1.0);
+    let radius = f64::from(vignette.radius);
+    let colour = rgb(vignette.colour);
+    colour::map_rgb_at(img, |x, y, rgb| {
+        let (dx, dy) = (f64::from(x) + 0.5 - centre.0, f64::from(y) + 0.5 - centre.1);
+        let distance = (dx * dx + dy * dy).sqrt() / corner;
+        let t = ((distance - radius) / (1.0 - radius).max(f64::EPSILON)).clamp(0.0, 1.0);
+        let smooth = t * t * (3.0 - 2.0 * t);
+        mix(rgb, colour, (smooth * f64::from(vignette.strength)) as f32)
+    })
+}
+
+fn pixelate_buffer<P>(buffer: &ImageBuffer<P, Vec<u8>>, size: u32) -> ImageBuffer<P, Vec<u8>>
+where
+    P: Pixel<Subpixel = u8> + 'static,
+{
+    let (width, height) = buffer.dimensions();
+    let channels = usize::from(P::channel_count());
+    let mut pixelated = buffer.clone();
+    for top in (0..height).step_by(size as usize) {
+        for left in (0..width).step_by(size as usize) {
+            let (right, bottom) = ((left + size).min(width), (top + size).min(height));
+            let mut sums = vec![0_u64; channels];
+            for y in top..bottom {
+                for x in left..right {
+                    for (sum, channel) in sums.iter_mut().zip(buffer.get_pixel(x, y).channels()) {
+                        *sum += u64::from(*channel);
+                    }
+                }
+            }
+            let count = u64::from((right - left) * (bottom - top));
+            let average: Vec<u8> = sums.iter().map(|x| (x / count) as u8).collect();
+            for y in top..bottom {
+                for x in left..right {
+                    pixelated
+                        .get_pixel_mut(x, y)
+                        .channels_mut()
+                        .copy_from_slice(&average);
+                }
+            }
+        }
+    }
+    pixelated
+}
+
+fn rgb([red, green, blue, _]: [u8; 4]) -> [f64; 3] {
+    [f64::from(red), f64::from(green), f64::from(blue)]
+}
+
+/// Moves `from` towards `to` by `amount`, from 0 to 1.
+#[allow(clippy::cast_sign_loss)]
+fn mix(from: [u8; 3], to: [f64; 3], amount: f32) -> [u8; 3] {
+    let amount = f64::from(amount);
+    let mut mixed = [0; 3];
+    for ((channel, from), to) in mixed.iter_mut().zip(from).zip(to) {
+        *channel = (f64::from(from) + (to - f64::from(from)) * amount)
+            .round()
+            .clamp(0.0, 255.0) as u8;
+    }
+    mixed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    fn solid(rgb: [u8; 3]) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(1, 1, Rgb(rgb)))
+    }
+
+    fn first(img: &DynamicImage) -> [u8; 3] {
+        img.to_rgb().get_pixel(0, 0).data
+    }
+
+    #[test]
+    fn sepia_tones_and_mixes() {
+        assert_eq!(first(&sepia(solid([255, 255, 255]), 1.0)), [255, 255, 239]);
+        assert_eq!(first(&sepia(solid([10, 80, 200]), 0.0)), [10, 80, 200]);
+    }
+
+    #[test]
+    fn tint_mixes_in_its_colour() {
+        let tint_by = |strength| Tint {
+            colour: [255, 136, 0, 255],
+            strength,
+        };
+        assert_eq!(first(&tint(solid([0, 0, 0]), &tint_by(0.5))), [128, 68, 0]);
+        assert_eq!(first(&tint(solid([9, 9, 9]), &tint_by(1.0))), [255, 136, 0]);
+    }
+
+    #[test]
+    fn duotone_maps_black_and_white_to_its_colours() {
+        let duotone_colours = Duotone {
+            shadows: [27, 27, 58, 255],
+            highlights: [255, 209, 102, 255],
+        };
+        assert_eq!(
+            first(&duotone(solid([0, 0, 0]), &duotone_colours)),
+            [27, 27, 58]
+        );
+        assert_eq!(
+            first(&duotone(solid([255, 255, 255]), &duotone_colours)),
+            [255, 209, 102]
+        );
+    }
+
+    #[test]
+    fn posterize_rounds_to_even_steps() {
+        assert_eq!(first(&posterize(solid([0, 127, 128]), 2)), [0, 0, 255]);
+        assert_eq!(first(&posterize(solid([60, 64, 200]), 3)), [0, 128, 255]);
+    }
+
+    #[test]
+    fn pixelate_averages_blocks_with_alpha() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(3, 2, |x, _| match x {
+            0 => Rgba([0, 0, 0, 0]),
+            1 => Rgba([200, 100, 50, 255]),
+            _ => Rgba([9, 9, 9, 9]),
+        }));
+        let pixelated = pixelate(img, 2).to_rgba();
+        for y in 0..2 {
+            assert_eq!(pixelated.get_pixel(0, y).data, [100, 50, 25, 127]);
+            assert_eq!(pixelated.get_pixel(1, y).data, [100, 50, 25, 127]);
+            assert_eq!(pixelated.get_pixel(2, y).data, [9, 9, 9, 9]);
+        }
+    }
+
+    #[test]
+    fn vignette_fades_only_the_edges() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(101, 101, Rgb([200, 200, 200])));
+        let faded = vignette(
+            img,
+            &Vignette {
+                radius: 0.5,
+                strength: 1.0,
+                colour: [0, 0, 255, 255],
+            },
+        )
+        .to_rgb();
+        assert_eq!(faded.get_pixel(50, 50).data, [200, 200, 200]);
+        assert_eq!(faded.get_pixel(50, 25).data, [200, 200, 200]);
+        let corner = faded.get_pixel(0, 0).data;
+        assert!(corner[0] <= 2 && corner[1] <= 2 && corner[2] >= 253);
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{output, parse::Hook};
+use set_error::ChangeError;
+use std::{
+    io::Read,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Runs `hook` through the shell with each `{name}` in its command replaced by the quoted value.
+/// Anything the command prints is only shown when it fails.
+pub fn run(hook: &Hook, placeholders: &[(&str, String)]) -> Result<(), String> {
+    let command = placeholders
+        .iter()
+        .fold(hook.command.clone(), |command, (name, value)| {
+            command.replace(&format!("{{{name}}}"), &quote(value))
+        });
+    output::detail(&format!("Running \"{command}\""));
+    let started = Instant::now();
+    let mut child = shell(&command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .set_error(&format!("Failed to run \"{command}\""))?;
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+    let status = wait(&mut child, hook.timeout).map_err(|e| format!("\"{command}\" {e}"))?;
+    output::debug(&format!(
+        "\"{}\" finished in {}ms",
+        command,
+        started.elapsed().as_millis()
+    ));
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "\"{}\" failed with {}\n{}{}",
+            command,
+            status,
+            stdout.join().unwrap_or_default(),
+            stderr.join().unwrap_or_default()
+        ))
+    }
+}
+
+fn wait(child: &mut Child, timeout: Duration) -> Result<std::process::ExitStatus, String> {
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().set_error("could not be waited for")? {
+            return Ok(status);
+        }
+        if started.elapsed() >= timeout {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(format!("timed out after {}s", timeout.as_secs_f32()));
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Reads a pipe on another thread so a command printing a lot can not fill it and block.
+fn read_in_background<R: 'static + Read + Send>(pipe: Option<R>) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut contents = String::new();
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_string(&mut contents).ok();
+        }
+        contents
+    })
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.args(["/C", command]);
+    shell
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.args(["-c", command]);
+    shell
+}
+
+#[cfg(windows)]
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+#[cfg(not(windows))]
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use super::*;
+
+    fn hook(command: &str, timeout: Duration) -> Hook {
+        Hook {
+            command: command.to_string(),
+            timeout,
+        }
+    }
+
+    #[test]
+    fn placeholders_are_passed_as_one_argument() {
+        assert_eq!(quote("it's a b"), "'it'\\''s a b'");
+        let placeholders = [("input", String::from("it's a b; exit 1"))];
+        let check = hook("[ {input} = \"it's a b; exit 1\" ]", Duration::from_secs(5));
+        assert!(run(&check, &placeholders).is_ok());
+        let wrong = hook("[ {input} = \"it's\" ]", Duration::from_secs(5));
+        assert!(run(&wrong, &placeholders).is_err());
+    }
+
+    #[test]
+    fn failures_include_the_output() {
+        let e = run(
+            &hook(
+                "echo printed; echo warned >&2; exit 3",
+                Duration::from_secs(5),
+            ),
+            &[],
+        )
+        .unwrap_err();
+        assert!(e.contains('3'));
+        assert!(e.contains("printed"));
+        assert!(e.contains("warned"));
+    }
+
+    #[test]
+    fn slow_commands_are_killed() {
+        let started = Instant::now();
+        let e = run(&hook("exec sleep 5", Duration::from_millis(100)), &[]).unwrap_err();
+        assert!(e.contains("timed out after 0.1s"));
+        assert!(started.elapsed() < Duration::from_secs(4));
+    }
+}
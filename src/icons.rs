@@ -0,0 +1,108 @@
+use crate::{canvas, livereload, output, parse::IconSet, pipeline};
+use image::{imageops, DynamicImage, FilterType, Rgba, RgbaImage};
+use set_error::ChangeError;
+use std::{fs, path::Path};
+
+/// The sizes stored in `favicon.ico`.
+const FAVICON_SIZES: [u32; 3] = [16, 32, 48];
+
+/// The icon iOS uses, which is drawn over white as it can not be transparent.
+const TOUCH_ICON: &str = "apple-touch-icon.png";
+
+/// Each icon saved as a PNG, with its size.
+const PNG_ICONS: [(&str, u32); 3] = [
+    (TOUCH_ICON, 180),
+    ("android-chrome-192x192.png", 192),
+    ("android-chrome-512x512.png", 512),
+];
+
+/// Saves every icon in `set` made from `img`, and a `site.webmanifest` listing the Android ones.
+pub fn save(set: &IconSet, img: &DynamicImage) -> Result<(), String> {
+    let folder = Path::new(&set.output);
+    fs::create_dir_all(folder).set_error(&format!("Failed to create folder {}", set.output))?;
+    let favicons: Vec<DynamicImage> = FAVICON_SIZES
+        .iter()
+        .map(|&size| square(set, img, size))
+        .collect();
+    write(
+        set,
+        &folder.join("favicon.ico"),
+        &pipeline::encode_ico(&favicons)?,
+    )?;
+    for (name, size) in &PNG_ICONS {
+        let path = folder.join(name);
+        let mut icon = square(set, img, *size);
+        // iOS shows transparent parts of touch icons as black.
+        if *name == TOUCH_ICON {
+            icon = canvas::flatten(icon, [255; 4]);
+        }
+        icon.save(&path)
+            .set_error(&format!("Failed to save {}", path.display()))?;
+        changed(set, &path);
+    }
+    let icons: Vec<String> = PNG_ICONS
+        .iter()
+        .filter(|(name, _)| name.starts_with("android"))
+        .map(|(name, size)| {
+            format!(
+                "    {{ \"src\": \"{name}\", \"sizes\": \"{size}x{size}\", \"type\": \"image/png\" }}"
+            )
+        })
+        .collect();
+    let manifest = format!("{{\n  \"icons\": [\n{}\n  ]\n}}\n", icons.join(",\n"));
+    write(set, &folder.join("site.webmanifest"), manifest.as_bytes())
+}
+
+/// Scales `img` to fit a `size` pixel square, leaving the set's padding around it and filling the
+/// rest with its background.
+fn square(set: &IconSet, img: &DynamicImage, size: u32) -> DynamicImage {
+    let inner = (size - size * set.padding * 2 / 100).max(1);
+    let scaled = img.resize(inner, inner, FilterType::Lanczos3).to_rgba();
+    let mut canvas = RgbaImage::from_pixel(size, size, Rgba(set.background));
+    imageops::overlay(
+        &mut canvas,
+        &scaled,
+        (size - scaled.width()) / 2,
+        (size - scaled.height()) / 2,
+    );
+    DynamicImage::ImageRgba8(canvas)
+}
+
+fn write(set: &IconSet, path: &Path, contents: &[u8]) -> Result<(), String> {
+    fs::write(path, contents).set_error(&format!("Failed to save {}", path.display()))?;
+    changed(set, path);
+    Ok(())
+}
+
+fn changed(set: &IconSet, path: &Path) {
+    let path = path.to_string_lossy();
+    livereload::changed(&path);
+    output::info(&format!("\"{}\" -> \"{}\"", set.path, path));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::SharedSettings;
+    use image::GenericImageView;
+
+    #[test]
+    fn touch_icon_is_opaque() {
+        let output = std::env::temp_dir().join("image-watcher-icons");
+        let set = IconSet {
+            path: String::from("logo.png"),
+            output: output.to_string_lossy().into_owned(),
+            padding: 10,
+            background: [0; 4],
+            other: SharedSettings::default(),
+        };
+        let logo = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255])));
+        save(&set, &logo).unwrap();
+        let touch = image::open(output.join(TOUCH_ICON)).unwrap();
+        assert_eq!(touch.dimensions(), (180, 180));
+        assert_eq!(touch.get_pixel(0, 0).data, [255, 255, 255, 255]);
+        assert_eq!(touch.get_pixel(90, 90).data, [255, 0, 0, 255]);
+        let android = image::open(output.join("android-chrome-192x192.png")).unwrap();
+        assert_eq!(android.get_pixel(0, 0).data[3], 0);
+    }
+}
@@ -0,0 +1,179 @@
+use std::{collections::BTreeMap, fmt::Write as _, iter::Peekable, str::CharIndices};
+
+/// Quotes `value` as a JSON string.
+pub fn string(value: &str) -> String {
+    let mut string = String::from("\"");
+    for x in value.chars() {
+        match x {
+            '"' => string.push_str("\\\""),
+            '\\' => string.push_str("\\\\"),
+            '\n' => string.push_str("\\n"),
+            '\r' => string.push_str("\\r"),
+            '\t' => string.push_str("\\t"),
+            '\u{8}' => string.push_str("\\b"),
+            '\u{c}' => string.push_str("\\f"),
+            x if x < ' ' => {
+                write!(string, "\\u{:04x}", u32::from(x)).ok();
+            }
+            x => string.push(x),
+        }
+    }
+    string.push('"');
+    string
+}
+
+/// Splits the JSON object in `text` into its keys and the JSON of each value, so files written
+/// earlier can be merged with new entries. Nothing if `text` is not one object.
+pub fn object_entries(text: &str) -> Option<BTreeMap<String, String>> {
+    let mut chars = text.char_indices().peekable();
+    let mut entries = BTreeMap::new();
+    skip_whitespace(&mut chars);
+    if chars.next()?.1 != '{' {
+        return None;
+    }
+    skip_whitespace(&mut chars);
+    if chars.peek()?.1 == '}' {
+        chars.next();
+    } else {
+        loop {
+            skip_whitespace(&mut chars);
+            if chars.next()?.1 != '"' {
+                return None;
+            }
+            let key = read_string(&mut chars)?;
+            skip_whitespace(&mut chars);
+            if chars.next()?.1 != ':' {
+                return None;
+            }
+            skip_whitespace(&mut chars);
+            let start = chars.peek()?.0;
+            skip_value(&mut chars)?;
+            let end = chars.peek().map_or(text.len(), |(index, _)| *index);
+            entries.insert(key, text[start..end].trim_end().to_string());
+            skip_whitespace(&mut chars);
+            match chars.next()?.1 {
+                ',' => {}
+                '}' => break,
+                _ => return None,
+            }
+        }
+    }
+    skip_whitespace(&mut chars);
+    if chars.next().is_some() {
+        return None;
+    }
+    Some(entries)
+}
+
+type Chars<'a> = Peekable<CharIndices<'a>>;
+
+fn skip_whitespace(chars: &mut Chars) {
+    while chars.peek().is_some_and(|(_, x)| x.is_whitespace()) {
+        chars.next();
+    }
+}
+
+/// Reads the rest of a string after its opening quote.
+fn read_string(chars: &mut Chars) -> Option<String> {
+    let mut string = String::new();
+    loop {
+        match chars.next()?.1 {
+            '"' => return Some(string),
+            '\\' => string.push(match chars.next()?.1 {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                'u' => {
+                    let hex: String = (0..4)
+                        .map(|_| chars.next().map(|x| x.1))
+                        .collect::<Option<_>>()?;
+                    char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                }
+                x => x,
+            }),
+            x => string.push(x),
+        }
+    }
+}
+
+/// Moves past one value of any kind, checking only that brackets and strings are closed.
+fn skip_value(chars: &mut Chars) -> Option<()> {
+    let mut depth = 0_usize;
+    loop {
+        match chars.peek()?.1 {
+            '"' => {
+                chars.next();
+                read_string(chars)?;
+            }
+            '{' | '[' => {
+                chars.next();
+                depth += 1;
+            }
+            '}' | ']' if depth > 0 => {
+                chars.next();
+                depth -= 1;
+            }
+            ',' | '}' | ']' if depth == 0 => return Some(()),
+            _ => {
+                chars.next();
+            }
+        }
+        if depth == 0
+            && chars
+                .peek()
+                .is_none_or(|(_, x)| matches!(x, ',' | '}' | ']'))
+        {
+            return Some(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_back_written_objects() {
+        let text = format!(
+            "{{\n  {}: {{\n    \"a\": \"b, }}\",\n    \"c\": [1, 2]\n  }},\n  \"two\": null\n}}\n",
+            string("one \"quoted\" \\ key")
+        );
+        let entries = object_entries(&text).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries["one \"quoted\" \\ key"],
+            "{\n    \"a\": \"b, }\",\n    \"c\": [1, 2]\n  }"
+        );
+        assert_eq!(entries["two"], "null");
+    }
+
+    #[test]
+    fn reads_empty_objects_and_escapes() {
+        assert_eq!(object_entries(" {} ").unwrap().len(), 0);
+        let entries = object_entries("{\"\\u0041\\n\": 1}").unwrap();
+        assert_eq!(entries["A\n"], "1");
+    }
+
+    #[test]
+    fn strings_escape_control_characters() {
+        let value = "a\"b\\c\nd\re\tf\u{8}g\u{c}h\u{0}i\u{1f}j";
+        assert_eq!(
+            string(value),
+            "\"a\\\"b\\\\c\\nd\\re\\tf\\bg\\fh\\u0000i\\u001fj\""
+        );
+        let entries = object_entries(&format!("{{{}: 1}}", string(value))).unwrap();
+        assert_eq!(entries[value], "1");
+    }
+
+    #[test]
+    fn rejects_anything_else() {
+        assert!(object_entries("").is_none());
+        assert!(object_entries("[1]").is_none());
+        assert!(object_entries("{\"a\": 1").is_none());
+        assert!(object_entries("{\"a\": \"b}").is_none());
+        assert!(object_entries("{\"a\" 1}").is_none());
+        assert!(object_entries("{} {}").is_none());
+    }
+}
@@ -0,0 +1,17 @@
+// Reloads images on the page whenever image-watcher saves a new version of them.
+(function () {
+  var script = document.currentScript;
+  var events = new EventSource(new URL("/events", script.src));
+  events.addEventListener("changed", function (event) {
+    var changed = JSON.parse(event.data).replace(/\\/g, "/").replace(/^\.\//, "");
+    var images = document.getElementsByTagName("img");
+    for (var i = 0; i < images.length; i++) {
+      var url = new URL(images[i].src, document.baseURI);
+      var path = url.pathname;
+      if (changed.endsWith(path) || path.endsWith("/" + changed)) {
+        url.searchParams.set("livereload", Date.now());
+        images[i].src = url.href;
+      }
+    }
+  });
+})();
@@ -0,0 +1,118 @@
+use crate::{json, output};
+use std::{
+    io::Write,
+    sync::{
+        mpsc::{sync_channel, SyncSender},
+        Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+};
+use tiny_http::{Header, Response, Server};
+
+/// Browsers listening for changes. Each has a thread writing events to the open response to its
+/// `/events` request, so one that stops reading can not hold up the watcher or other browsers.
+static CLIENTS: Mutex<Vec<SyncSender<String>>> = Mutex::new(Vec::new());
+
+/// How many events a browser can fall behind by before it is dropped.
+const BACKLOG: usize = 16;
+
+const SCRIPT: &str = include_str!("livereload.js");
+
+/// Serves the reload script at `/livereload.js` and a stream of server-sent events at `/events`.
+pub fn start(port: u16) -> Result<(), String> {
+    let server = Server::http(("127.0.0.1", port))
+        .map_err(|e| format!("Failed to start live reload on port {port}: {e}"))?;
+    output::info(&format!(
+        "Live reload running, add <script src=\"http://localhost:{port}/livereload.js\"></script> to your page."
+    ));
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let result = match request.url() {
+                "/livereload.js" => request.respond(
+                    Response::from_string(SCRIPT)
+                        .with_header(header("Content-Type", "application/javascript")),
+                ),
+                "/events" => {
+                    let mut writer = request.into_writer();
+                    let result = writer
+                        .write_all(
+                            b"HTTP/1.1 200 OK\r\n\
+                              Content-Type: text/event-stream\r\n\
+                              Cache-Control: no-cache\r\n\
+                              Access-Control-Allow-Origin: *\r\n\
+                              \r\n\
+                              : connected\n\n",
+                        )
+                        .and_then(|()| writer.flush());
+                    if result.is_ok() {
+                        let (sender, events) = sync_channel::<String>(BACKLOG);
+                        clients().push(sender);
+                        thread::spawn(move || {
+                            for event in events {
+                                let sent = writer
+                                    .write_all(event.as_bytes())
+                                    .and_then(|()| writer.flush());
+                                if sent.is_err() {
+                                    break;
+                                }
+                            }
+                        });
+                    }
+                    result
+                }
+                _ => request.respond(Response::from_string("Not found.").with_status_code(404)),
+            };
+            if let Err(e) = result {
+                output::debug(&format!("Failed to respond to live reload request: {e}"));
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Tells every listening browser that the image at `output_path` was saved, without waiting for
+/// them. Browsers that have gone or fallen too far behind are dropped.
+pub fn changed(output_path: &str) {
+    let event = event(output_path);
+    clients().retain(|client| client.try_send(event.clone()).is_ok());
+}
+
+/// The event for `output_path`, given as a JSON string so no path can end the event early.
+fn event(output_path: &str) -> String {
+    format!("event: changed\ndata: {}\n\n", json::string(output_path))
+}
+
+fn clients() -> MutexGuard<'static, Vec<SyncSender<String>>> {
+    CLIENTS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).expect("header is valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_one_line_of_json() {
+        assert_eq!(
+            event("out/a\nb.png"),
+            "event: changed\ndata: \"out/a\\nb.png\"\n\n"
+        );
+    }
+
+    #[test]
+    fn changed_drops_gone_and_stalled_browsers() {
+        let (listening, events) = sync_channel(BACKLOG);
+        let (gone, _) = sync_channel(BACKLOG);
+        let (stalled, _stalled_events) = sync_channel(BACKLOG);
+        for _ in 0..BACKLOG {
+            stalled.try_send(String::new()).unwrap();
+        }
+        clients().extend([listening, gone, stalled]);
+        changed("a.png");
+        assert_eq!(clients().len(), 1);
+        assert_eq!(events.try_recv().unwrap(), event("a.png"));
+    }
+}
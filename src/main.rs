@@ -5,34 +5,59 @@
     clippy::cast_possible_truncation
 )]
 
+mod animation;
+mod canvas;
 mod cli;
+mod colour;
+mod contact_sheet;
+mod convolve;
+mod enhance;
+mod filters;
+mod hooks;
+mod icons;
+mod json;
+mod livereload;
+mod metrics;
+mod output;
+mod palette;
 mod parse;
+mod pipeline;
+mod placeholders;
+mod script;
+mod serve;
+mod snippets;
+mod sprite;
+mod watch;
 
-use clap::{self, App, Arg};
-use cli::Mode;
-use file_watcher::{
-    FileListBuilder, WatchedFile,
-    WatchingFuncResult::{self, *},
+use clap::{self, App, Arg, ArgMatches, SubCommand};
+use cli::{Mode, Verbosity};
+use image::{DynamicImage, GenericImageView, ImageOutputFormat};
+use parse::{
+    config_path, parse_config, sheet_images, Animation, ContactSheet, Encoding, FileWatch, IconSet,
+    ImgEditJobs, Mask, Palette, Placeholders, Settings, SharedSettings, SpriteSheet,
 };
-use image::{DynamicImage, FilterType};
-use parse::{parse_config, FileWatch, ImgEditJobs, Resize, SharedSettings, Size};
 use set_error::ChangeError;
 use std::{
+    cell::RefCell,
     ffi::OsStr,
-    iter::{repeat, Iterator},
+    fs,
+    io::{self, Read, Write},
+    iter::Iterator,
     path::Path,
-    time::SystemTime,
+    process,
+    rc::Rc,
+    time::Instant,
+};
+use watch::{
+    FileListBuilder, Reloaded, WatchedFile,
+    WatchingFuncResult::{self, *},
 };
 type WatchingImageFuncResult = WatchingFuncResult<DynamicImage>;
+/// The path an image was saved to with its width and height.
+type Saved = (String, (u32, u32));
 
-#[derive(Clone)]
-struct FileWatched {
-    file: FileWatch,
-    time: Option<SystemTime>,
-}
-
-fn main() {
-    let mode = Mode::get(&App::new("Image_watcher")
+fn app() -> App<'static, 'static> {
+    App::new("Image_watcher")
         .version("0.0.20")
         .author(
             "Ethan Brierley. <incoming+efunb-image-watcher-11376789-issue-@incoming.gitlab.com>",
@@ -49,240 +74,767 @@ fn main() {
             Arg::with_name("compile")
                 .long("compile")
                 .short("c")
-                .help("Sets program to watch mode.")
+                .help("Sets program to compile mode.")
                 .conflicts_with("watch"),
         )
-        .get_matches());
-    println!(
+        .arg(
+            Arg::with_name("verbose")
+                .long("verbose")
+                .short("v")
+                .multiple(true)
+                .global(true)
+                .help("Prints every job as it runs. Use twice to also print timings.")
+                .conflicts_with("quiet"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .long("quiet")
+                .short("q")
+                .global(true)
+                .help("Only prints errors."),
+        )
+        .arg(
+            Arg::with_name("debounce")
+                .long("debounce")
+                .value_name("MILLISECONDS")
+                .help("Sets how long a changed file must stay unchanged before it is rebuilt in watch mode. Defaults to 300.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("poll-interval")
+                .long("poll-interval")
+                .value_name("MILLISECONDS")
+                .help("Checks for changes after every interval instead of waiting for the operating system to report them. Useful on network filesystems.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("livereload")
+                .long("livereload")
+                .help("Tells browsers to reload images after they are rebuilt in watch mode.")
+                .requires("watch"),
+        )
+        .arg(
+            Arg::with_name("livereload-port")
+                .long("livereload-port")
+                .value_name("PORT")
+                .takes_value(true)
+                .help("Sets the port browsers connect to for live reload. Defaults to 35729."),
+        )
+        .arg(
+            Arg::with_name("stdin")
+                .long("stdin")
+                .help("Reads one image from stdin instead of the files in the config file.")
+                .requires("stdout")
+                .conflicts_with_all(&["input", "watch"]),
+        )
+        .arg(
+            Arg::with_name("stdout")
+                .long("stdout")
+                .help("Writes the transformed image to stdout instead of saving it. Logs go to stderr.")
+                .requires("stdin"),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .help("Sets the format written to stdout. Defaults to the format read from stdin.")
+                .possible_values(&["png", "jpeg", "jpg", "gif", "ico", "bmp"])
+                .requires("stdout"),
+        )
+        .arg(
+            Arg::with_name("measure")
+                .long("measure")
+                .help("Prints the SSIM and PSNR of every saved image compared to the image before it was encoded, and their averages at the end."),
+        )
+        .args(&cli::job_args())
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Serves images from a folder, transformed by the jobs in each request's query string.")
+                .args(&cli::serve_args()),
+        )
+}
+
+fn main() {
+    let matches = app().get_matches();
+    if matches.is_present("stdout") {
+        output::use_stderr();
+    }
+    output::set_verbosity(Verbosity::get(&matches));
+    if let Err(e) = run(&matches) {
+        output::error(&e);
+        process::exit(1);
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<(), String> {
+    if let Some(matches) = matches.subcommand_matches("serve") {
+        return serve::serve(cli::serve_options(matches)?);
+    }
+    let debounce = cli::debounce(matches)?;
+    let poll_interval = cli::poll_interval(matches)?;
+    let inputs = cli::inputs(matches)?;
+    let cli_settings = cli::shared_settings(matches)?;
+    if matches.is_present("measure") {
+        metrics::enable();
+    }
+    if matches.is_present("stdin") {
+        return stream(matches, &cli_settings);
+    }
+    let mode = Mode::get(matches);
+    output::info(&format!(
         "Using {} mode.",
         match mode {
             Mode::Compile => "compile",
             Mode::Watch => "watch",
         }
-    );
+    ));
 
-    print!("Parsing config file image_watcher.yaml");
-    let config = match parse_config() {
-        Ok(x) => x,
-        Err(e) => {
-            println!("Error: {}", e);
-            return;
+    // Images given on the command line replace the config's file list, but its global settings
+    // still apply if there is one.
+    let config_path = if inputs.is_empty() {
+        Some(config_path())
+    } else if Path::new("image_watcher.yaml").is_file() {
+        Some(String::from("image_watcher.yaml"))
+    } else {
+        None
+    };
+    let mut config = match &config_path {
+        Some(config_path) => {
+            output::detail(&format!("Parsing config file {config_path}"));
+            parse_config(config_path)?
         }
+        None => Settings::default(),
     };
+    if !inputs.is_empty() {
+        let output = matches.value_of("output").map(ToString::to_string);
+        config.files_list = inputs
+            .into_iter()
+            .map(|path| FileWatch {
+                path,
+                output: output.clone(),
+                other: SharedSettings::default(),
+            })
+            .collect();
+        config.contact_sheets = Vec::new();
+        config.sprites = Vec::new();
+        config.icon_sets = Vec::new();
+    }
+
+    output::separator();
+
+    config.files_list = merged_files(&config, &cli_settings);
+    let files = watched_files(&config, None)?.files;
+    let files_count = files.len();
+    let mut file_builder = FileListBuilder::new(file_open);
+    for file in files {
+        file_builder.add_file(file);
+    }
+    let current = Rc::new(RefCell::new(config));
+    if let (Mode::Watch, Some(config_path), false) =
+        (&mode, config_path, matches.is_present("input"))
+    {
+        let current = Rc::clone(&current);
+        file_builder = file_builder.with_config_reload(&config_path.clone(), move || {
+            reload_config(&config_path, &cli_settings, &current)
+        });
+    }
+    file_builder = file_builder.with_after_batch(move || {
+        if let Some(hook) = current.borrow().after_build.as_ref() {
+            if let Err(e) = hooks::run(hook, &[]) {
+                output::error(&format!("after_build failed: {e}"));
+            }
+        }
+    });
+    if let Mode::Compile = mode {
+        output::start_progress(files_count);
+    }
+    if matches.is_present("livereload") {
+        livereload::start(cli::livereload_port(matches)?)?;
+    }
+    let started = Instant::now();
+    let result = file_builder
+        .run_only_once(match mode {
+            Mode::Compile => true,
+            Mode::Watch => false,
+        })
+        .with_debounce(debounce)
+        .with_poll_interval(poll_interval)
+        .launch();
+    output::finish_progress();
+    result?;
+    output::info(&format!(
+        "Finished {} images in {}.",
+        files_count,
+        output::format_duration(started.elapsed())
+    ));
+    if let Some(summary) = metrics::summary() {
+        output::info(&summary);
+    }
+    Ok(())
+}
 
-    separator();
+/// Transforms one image from stdin and writes it to stdout, without touching any files other
+/// than reading the config's global settings if there is one.
+fn stream(matches: &ArgMatches, cli_settings: &SharedSettings) -> Result<(), String> {
+    let mut bytes = Vec::new();
+    io::stdin()
+        .read_to_end(&mut bytes)
+        .set_error("Failed to read image from stdin.")?;
+    let format = match matches.value_of("format") {
+        Some(x) => pipeline::output_format(x)?,
+        None => image::guess_format(&bytes)
+            .set_error("Failed to find the format of the image from stdin.")?
+            .into(),
+    };
+    let config = if Path::new("image_watcher.yaml").is_file() {
+        parse_config("image_watcher.yaml")?
+    } else {
+        Settings::default()
+    };
+    let settings = file_share_or_combine(cli_settings.clone(), config.other);
+    output::detail("Transforming image from stdin");
+    let img = pipeline::apply(&pipeline::jobs(&settings), pipeline::decode(&bytes)?)?;
+    pipeline::check_limits(&format, &settings.encoding)?;
+    let img = canvas::flatten_for(&img, &format).unwrap_or(img);
+    let Encoded {
+        bytes, description, ..
+    } = encode(&img, format, &settings.encoding, "stdout")?;
+    output::detail(&format!("Encoded image{description}"));
+    if metrics::enabled() {
+        output::info(&metrics::compare(&img, &pipeline::decode(&bytes)?).describe());
+    }
+    io::stdout()
+        .write_all(&bytes)
+        .set_error("Failed to write image to stdout.")
+}
 
-    let files_list: Vec<FileWatch> = config
+/// Applies the global settings to every file in the config, then the settings from the command
+/// line on top.
+fn merged_files(config: &Settings, cli_settings: &SharedSettings) -> Vec<FileWatch> {
+    config
         .files_list
         .clone()
         .into_iter()
         .map(|x| FileWatch {
-            other: file_share_or_combine(x.other, config.other.clone()),
+            other: file_share_or_combine(
+                cli_settings.clone(),
+                file_share_or_combine(x.other, config.other.clone()),
+            ),
             ..x
         })
-        .collect();
-    let mut file_builder = FileListBuilder::new(file_open);
-    for file in files_list {
-        file_builder.add_file({
-            let mut watched_file = {
-                {
-                    let temp_file = file.clone();
-                    match WatchedFile::new(&file.path, move |img| {
-                        save(&img, temp_file.output.clone(), &temp_file.path)
-                    }) {
-                        Ok(t) => t,
-                        Err(s) => {
-                            println!("{}", s);
-                            return;
-                        }
-                    }
-                }
+        .collect()
+}
+
+fn watched_file(file: &FileWatch, config: &Settings) -> Result<WatchedFile<DynamicImage>, String> {
+    let mut watched_file = {
+        let temp_file = file.clone();
+        let placeholders_file = config
+            .placeholders_file
+            .clone()
+            .unwrap_or_else(|| String::from("placeholders.json"));
+        let snippets = config.snippets.clone();
+        WatchedFile::new(&file.path, move |img| {
+            let (output_path, (width, height)) = match save_animation(&temp_file, &img)? {
+                Some(saved) => saved,
+                None => save(
+                    &img,
+                    temp_file.output.clone(),
+                    &temp_file.path,
+                    &temp_file.other.encoding,
+                )?,
             };
-            let jobs = file.other.jobs.clone();
-            if let Some(x) = jobs.resize {
-                let resize_filter = file.other.resize_filter;
-                watched_file.add_func(move |img| resize_image(&img, &x, resize_filter))
+            if let Err(e) = placeholders::record(
+                &placeholders_file,
+                &output_path,
+                &img,
+                &temp_file.other.placeholders,
+            ) {
+                output::error(&format!(
+                    "Failed to write placeholders for \"{}\": {}",
+                    temp_file.path, e
+                ));
             }
-            if let Some(x) = jobs.blur {
-                watched_file.add_func(move |img| blur_image(&img, x))
-            }
-            //Sharpen does not work for some reason. Output does not look sharp
-            if let Some(x) = jobs.sharpen {
-                watched_file.add_func(move |img| {
-                    println!("With sharpening level {}\n", x);
-                    Success(img.unsharpen(100.0, x))
-                })
-            }
-            if let Some(x) = jobs.adjust_contrast {
-                watched_file.add_func(move |img| {
-                    println!("With contrast level {}\n", x);
-                    Success(img.adjust_contrast(x))
-                })
-            }
-            if let Some(x) = jobs.brighten {
-                watched_file.add_func(move |img| {
-                    println!("With brightness level {}\n", x);
-                    Success(img.brighten(x))
-                })
-            }
-            if let Some(x) = jobs.huerotate {
-                watched_file.add_func(move |img| {
-                    println!("With hue rotation of {}\n", x);
-                    Success(img.huerotate(x))
-                })
-            }
-            if jobs.flipv {
-                watched_file.add_func(|img| {
-                    println!("And flipping vertically\n");
-                    Success(img.flipv())
-                })
-            }
-            if jobs.fliph {
-                watched_file.add_func(|img| {
-                    println!("And flipping horizontally\n");
-                    Success(img.fliph())
-                })
-            }
-            if jobs.rotate90 {
-                watched_file.add_func(|img| {
-                    println!("And rotating 90 degrees\n");
-                    Success(img.rotate90())
-                })
-            }
-            if jobs.rotate180 {
-                watched_file.add_func(|img| {
-                    println!("And rotating 180 degrees\n");
-                    Success(img.rotate180())
-                })
-            }
-            if jobs.rotate270 {
-                watched_file.add_func(|img| {
-                    println!("And rotating 270 degrees\n");
-                    Success(img.rotate270())
-                })
+            if let Some(snippets) = &snippets {
+                if let Err(e) =
+                    snippets::record(snippets, &temp_file.path, &output_path, (width, height))
+                {
+                    output::error(&format!(
+                        "Failed to write snippets for \"{}\": {}",
+                        temp_file.path, e
+                    ));
+                }
             }
-            if jobs.grayscale {
-                watched_file.add_func(|img| {
-                    println!("And changing image to grayscale\n");
-                    Success(img.grayscale())
-                })
+            if let Some(hook) = &temp_file.other.after_save {
+                let placeholders = [
+                    ("input", temp_file.path.clone()),
+                    ("output", output_path),
+                    ("width", width.to_string()),
+                    ("height", height.to_string()),
+                ];
+                if let Err(e) = hooks::run(hook, &placeholders) {
+                    output::error(&format!(
+                        "after_save failed for \"{}\": {}",
+                        temp_file.path, e
+                    ));
+                }
             }
-            if jobs.invert {
-                watched_file.add_func(|mut img| {
-                    println!("And inverting image\n");
-                    Success({
-                        img.invert();
-                        img
-                    })
-                })
+            Ok(())
+        })?
+    };
+    if let Some(script) = &file.other.jobs.script {
+        watched_file.add_dependency(script);
+    }
+    if let Some(Mask::Image(mask)) = &file.other.jobs.mask {
+        watched_file.add_dependency(mask);
+    }
+    let output_path = output_path(file)?;
+    let format = Path::new(&output_path)
+        .extension()
+        .and_then(OsStr::to_str)
+        .and_then(|x| pipeline::output_format(x).ok());
+    if let Some(format) = format {
+        pipeline::check_limits(&format, &file.other.encoding)
+            .map_err(|e| format!("\"{output_path}\" can not be saved: {e}"))?;
+    }
+    if let Some(poster_frame) = file.other.animation.poster_frame {
+        watched_file.open_with(move |path| poster_open(path, poster_frame));
+    } else if !animation::keeps_frames(&output_path) {
+        watched_file.open_with(move |path| still_open(path, &output_path));
+    }
+    let jobs = pipeline::jobs(&file.other);
+    watched_file.add_func(move |img| match pipeline::apply(&jobs, img) {
+        Ok(img) => Success(img),
+        Err(s) => Fail(s),
+    });
+    Ok(watched_file)
+}
+
+/// Rebuilds the whole sheet whenever any of its images change.
+fn watched_sheet(sheet: &ContactSheet) -> Result<WatchedFile<DynamicImage>, String> {
+    let mut watched_file = {
+        let output = sheet.output.clone();
+        let paths = sheet.patterns.join(", ");
+        WatchedFile::new(&sheet.images[0], move |img| {
+            save(&img, Some(output.clone()), &paths, &Encoding::default()).map(|_| ())
+        })?
+    };
+    for path in &sheet.images[1..] {
+        watched_file.add_dependency(path);
+    }
+    let sheet = sheet.clone();
+    // The globs are expanded again for each build, so images added or removed since the config
+    // was read are picked up.
+    watched_file.open_with(move |_| {
+        let built = sheet_images(&sheet.patterns, &sheet.output).and_then(|images| {
+            contact_sheet::build(&ContactSheet {
+                images,
+                ..sheet.clone()
+            })
+        });
+        match built {
+            Ok(img) => Success(img),
+            Err(s) => Fail(s),
+        }
+    });
+    Ok(watched_file)
+}
+
+/// Repacks the whole atlas whenever any of its images change, then writes its stylesheet and map.
+fn watched_sprite(sprite: &SpriteSheet) -> Result<WatchedFile<DynamicImage>, String> {
+    let mut sprite = sprite.clone();
+    for image in &mut sprite.images {
+        image.other = file_share_or_combine(image.other.clone(), sprite.other.clone());
+    }
+    let placements = Rc::new(RefCell::new(Vec::new()));
+    let mut watched_file = {
+        let sprite = sprite.clone();
+        let placements = Rc::clone(&placements);
+        let label = format!("{} sprite images", sprite.images.len());
+        WatchedFile::new(&sprite.images[0].path.clone(), move |img| {
+            save(
+                &img,
+                Some(sprite.output.clone()),
+                &label,
+                &Encoding::default(),
+            )?;
+            // The atlas is already saved, so retrying would only save it again.
+            if let Err(e) = sprite::write_maps(&sprite, &placements.borrow()) {
+                output::error(&format!(
+                    "Failed to write maps for \"{}\": {}",
+                    sprite.output, e
+                ));
             }
-            watched_file
-        })
+            Ok(())
+        })?
+    };
+    for image in &sprite.images[1..] {
+        watched_file.add_dependency(&image.path);
     }
-    match file_builder
-        .run_only_once(match mode {
-            Mode::Compile => true,
-            Mode::Watch => false,
-        })
-        .launch()
-    {
-        Ok(_) => return,
-        Err(s) => {
-            println!("Error: {}", s);
-            return;
+    watched_file.open_with(move |_| match sprite::build(&sprite) {
+        Ok((img, packed)) => {
+            placements.replace(packed);
+            Success(img)
+        }
+        Err(s) => Fail(s),
+    });
+    Ok(watched_file)
+}
+
+fn watched_icon_set(icon_set: &IconSet) -> Result<WatchedFile<DynamicImage>, String> {
+    let mut watched_file = {
+        let icon_set = icon_set.clone();
+        WatchedFile::new(&icon_set.path.clone(), move |img| {
+            icons::save(&icon_set, &img)?;
+            output::separator();
+            output::image_done(&icon_set.output);
+            Ok(())
+        })?
+    };
+    let jobs = pipeline::jobs(&icon_set.other);
+    watched_file.add_func(move |img| match pipeline::apply(&jobs, img) {
+        Ok(img) => Success(img),
+        Err(s) => Fail(s),
+    });
+    Ok(watched_file)
+}
+
+/// Parses the config again, keeping `current` up to date and marking only the outputs whose
+/// settings changed to be rebuilt.
+fn reload_config(
+    path: &str,
+    cli_settings: &SharedSettings,
+    current: &RefCell<Settings>,
+) -> Result<Reloaded<DynamicImage>, String> {
+    let mut config = parse_config(path)?;
+    config.files_list = merged_files(&config, cli_settings);
+    let reloaded = watched_files(&config, Some(&current.borrow()))?;
+    let saved = config
+        .files_list
+        .iter()
+        .map(|file| Ok((file.path.clone(), output_path(file)?)))
+        .collect::<Result<_, String>>()?;
+    snippets::retain(&saved);
+    output::info(&format!(
+        "Reloaded config file {}, {} of {} images changed.",
+        path,
+        reloaded.rebuild.len(),
+        reloaded.files.len()
+    ));
+    current.replace(config);
+    Ok(reloaded)
+}
+
+/// Everything `config` builds, marking those not the same in `previous` to be rebuilt.
+fn watched_files(
+    config: &Settings,
+    previous: Option<&Settings>,
+) -> Result<Reloaded<DynamicImage>, String> {
+    let mut files = Vec::new();
+    let mut rebuild = Vec::new();
+    // Every image writes its placeholders and snippets and is followed by after_build, so when
+    // they change every image is rebuilt.
+    let same_globals = previous.is_some_and(|x| {
+        x.placeholders_file == config.placeholders_file
+            && x.snippets == config.snippets
+            && x.after_build == config.after_build
+    });
+    for file in &config.files_list {
+        if !same_globals || !previous.is_some_and(|x| x.files_list.contains(file)) {
+            rebuild.push(files.len());
+        }
+        files.push(watched_file(file, config)?);
+    }
+    for sheet in &config.contact_sheets {
+        if !previous.is_some_and(|x| x.contact_sheets.contains(sheet)) {
+            rebuild.push(files.len());
+        }
+        files.push(watched_sheet(sheet)?);
+    }
+    for sprite in &config.sprites {
+        if !previous.is_some_and(|x| x.sprites.contains(sprite)) {
+            rebuild.push(files.len());
         }
+        files.push(watched_sprite(sprite)?);
     }
+    for icon_set in &config.icon_sets {
+        if !previous.is_some_and(|x| x.icon_sets.contains(icon_set)) {
+            rebuild.push(files.len());
+        }
+        files.push(watched_icon_set(icon_set)?);
+    }
+    Ok(Reloaded { files, rebuild })
 }
 
 fn file_open(path_str: &str) -> WatchingImageFuncResult {
     let path = Path::new(path_str);
-    println!("Updating image file \"{}\"\n", path_str,);
+    output::detail(&format!("Updating image file \"{path_str}\""));
     match image::open(path) {
         Ok(t) => Success(t),
         Err(_) => Retry(format!("failed to open file {}", path.display())),
     }
 }
 
-fn resize_image(
-    img: &DynamicImage,
-    resize: &Resize,
-    filter: Option<FilterType>,
-) -> WatchingImageFuncResult {
-    let filter_type = filter.unwrap_or(FilterType::Gaussian);
-    let size = &resize.size;
-    println!(
-        "With {}\n",
-        match size {
-            Size::WidthHeight(x, y) => format!(
-                "as close as possible to width {}px and height {}px while keeping aspect ratio",
-                x, y
-            ),
-            Size::Width(x) => format!("new width {}px", x),
-            Size::Height(x) => format!("new height {}px", x),
+/// Opens frame `poster_frame` of the animated GIF at `path`, or the image if it is not animated.
+fn poster_open(path: &str, poster_frame: u32) -> WatchingImageFuncResult {
+    match animation::open(path) {
+        Ok(Some(animated)) => {
+            output::detail(&format!(
+                "Updating image file \"{path}\" from frame {poster_frame}"
+            ));
+            match animated.frames.into_iter().nth(poster_frame as usize) {
+                Some(frame) => Success(frame.image),
+                None => Fail(format!("\"{path}\" has no frame {poster_frame}")),
+            }
         }
-    );
-    let size = match size {
-        Size::WidthHeight(x, y) => (x, y),
-        Size::Width(x) => (x, &u32::max_value()),
-        Size::Height(x) => (&u32::max_value(), x),
+        Ok(None) => file_open(path),
+        Err(e) => Retry(e),
+    }
+}
+
+/// Opens the image at `path` for `output_path`, which can only hold one frame, failing if it is an
+/// animated GIF rather than dropping every frame after the first.
+fn still_open(path: &str, output_path: &str) -> WatchingImageFuncResult {
+    match animation::open(path) {
+        Ok(Some(_)) => Fail(format!(
+            "\"{path}\" is animated, but \"{output_path}\" can only hold one frame. Save it as a GIF or PNG, or set poster_frame."
+        )),
+        Ok(None) => file_open(path),
+        Err(e) => Retry(e),
+    }
+}
+
+/// Saves every frame of `file` if it is an animated GIF saved as a GIF or PNG, with `first` being
+/// its first frame already transformed. Returns nothing if it should be saved as a still image.
+fn save_animation(file: &FileWatch, first: &DynamicImage) -> Result<Option<Saved>, String> {
+    let started = Instant::now();
+    let output_path = output_path(file)?;
+    if file.other.animation.poster_frame.is_some() || !animation::keeps_frames(&output_path) {
+        return Ok(None);
+    }
+    let Some(animated) = animation::open(&file.path)? else {
+        return Ok(None);
     };
-    let img = img.resize(*size.0, *size.1, filter_type);
-    Success(img)
+    let animated = animation::transform(animated, first.clone(), &file.other)?;
+    let bytes = animation::save(
+        &animated,
+        &output_path,
+        file.other.encoding.palette.as_ref(),
+    )?;
+    livereload::changed(&output_path);
+    output::info(&format!(
+        "\"{}\" -> \"{}\" ({} frames, {} bytes)",
+        file.path,
+        output_path,
+        animated.frames.len(),
+        bytes
+    ));
+    output::debug(&format!("Saved in {}ms", started.elapsed().as_millis()));
+    output::separator();
+    output::image_done(&output_path);
+    Ok(Some((output_path, first.dimensions())))
 }
 
-fn blur_image(img: &DynamicImage, blur_amount: f32) -> WatchingImageFuncResult {
-    println!("With a blur of {}\n", blur_amount);
-    Success(img.blur(blur_amount))
+/// Where `file` is saved, its output if set or beside it otherwise.
+fn output_path(file: &FileWatch) -> Result<String, String> {
+    match &file.output {
+        Some(output_path) => Ok(output_path.clone()),
+        None => output_path_from(Path::new(&file.path)),
+    }
 }
 
-fn save(img: &DynamicImage, output_path: Option<String>, input_path: &str) -> Result<(), String> {
-    fn output_path_from(path: &Path) -> Result<String, String> {
-        Ok(format!(
-            "{}{}.min.{}",
-            {
-                let parent = Path::new(&path)
-                    .parent()
-                    .and_then(Path::to_str)
-                    .set_error("file has a output path with invalid parent.")?;
-                if parent.is_empty() {
-                    parent.to_string()
-                } else {
-                    format!("{}/", parent)
-                }
-            },
-            Path::new(&path)
-                .file_stem()
-                .and_then(OsStr::to_str)
-                .set_error("file has a output path with invalid file stem.")?,
-            Path::new(&path)
-                .extension()
-                .and_then(OsStr::to_str)
-                .set_error("file has a output path with invalid extension.")?
-        ))
-    }
-
-    img.save({
-        print!("and saving to ");
-        let ptemp = if let Some(output_path) = output_path {
-            print!("\"{}\"", output_path);
-            output_path
+/// The default output path for `path`, beside it with `.min` before the extension.
+fn output_path_from(path: &Path) -> Result<String, String> {
+    Ok(format!(
+        "{}{}.min.{}",
+        {
+            let parent = Path::new(&path)
+                .parent()
+                .and_then(Path::to_str)
+                .set_error("file has a output path with invalid parent.")?;
+            if parent.is_empty() {
+                parent.to_string()
+            } else {
+                format!("{parent}/")
+            }
+        },
+        Path::new(&path)
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .set_error("file has a output path with invalid file stem.")?,
+        Path::new(&path)
+            .extension()
+            .and_then(OsStr::to_str)
+            .set_error("file has a output path with invalid extension.")?
+    ))
+}
+
+/// Saves `img`, lowering quality or size to fit `encoding`, and returns the path it was saved to
+/// with the saved width and height.
+fn save(
+    img: &DynamicImage,
+    output_path: Option<String>,
+    input_path: &str,
+    encoding: &Encoding,
+) -> Result<Saved, String> {
+    let started = Instant::now();
+    let output_path = match output_path {
+        Some(output_path) => output_path,
+        None => output_path_from(Path::new(&input_path))?,
+    };
+    let format = pipeline::output_format(
+        Path::new(&output_path)
+            .extension()
+            .and_then(OsStr::to_str)
+            .unwrap_or_default(),
+    )?;
+    let flattened = canvas::flatten_for(img, &format);
+    let img = flattened.as_ref().unwrap_or(img);
+    let Encoded {
+        bytes,
+        size,
+        description,
+    } = encode(img, format, encoding, &output_path)?;
+    fs::write(&output_path, bytes).set_error("Failed to save.")?;
+    livereload::changed(&output_path);
+    output::info(&format!(
+        "\"{}\" -> \"{}\"{}{}",
+        input_path,
+        output_path,
+        description,
+        measure(img, &output_path)
+    ));
+    output::debug(&format!("Saved in {}ms", started.elapsed().as_millis()));
+    output::separator();
+    output::image_done(&output_path);
+    Ok((output_path, size))
+}
+
+/// With `--measure`, compares the image saved at `output_path` to `img` and keeps the result for
+/// the summary.
+fn measure(img: &DynamicImage, output_path: &str) -> String {
+    if !metrics::enabled() {
+        return String::new();
+    }
+    match image::open(output_path) {
+        Ok(saved) => {
+            let quality = metrics::compare(img, &saved);
+            metrics::record(output_path, quality);
+            format!(" [{}]", quality.describe())
+        }
+        Err(e) => {
+            output::warn(&format!("Failed to measure \"{output_path}\": {e}"));
+            String::new()
+        }
+    }
+}
+
+/// An image encoded for saving, with its size and a description of how it was encoded.
+struct Encoded {
+    bytes: Vec<u8>,
+    size: (u32, u32),
+    description: String,
+}
+
+/// Encodes `img` as `format` with the palette or limits in `encoding`. `name` is what warnings
+/// call the output, so saving and writing to stdout encode the same way.
+fn encode(
+    img: &DynamicImage,
+    format: ImageOutputFormat,
+    encoding: &Encoding,
+    name: &str,
+) -> Result<Encoded, String> {
+    if let Some(palette) = &encoding.palette {
+        encode_indexed(img, format, palette, encoding, name)
+    } else if encoding.max_bytes.is_some() || encoding.min_ssim.is_some() {
+        encode_to_fit(img, &format, encoding, name)
+    } else {
+        Ok(Encoded {
+            bytes: pipeline::encode(img, format)?,
+            size: img.dimensions(),
+            description: String::new(),
+        })
+    }
+}
+
+/// Encodes `img` reduced to `palette`, described by the size of the palette.
+fn encode_indexed(
+    img: &DynamicImage,
+    format: ImageOutputFormat,
+    palette: &Palette,
+    encoding: &Encoding,
+    name: &str,
+) -> Result<Encoded, String> {
+    let indexed = palette::quantize(img, palette);
+    let bytes = palette::encode(&indexed, format)?;
+    if let Some(max_bytes) = encoding.max_bytes.filter(|x| bytes.len() as u64 > *x) {
+        output::warn(&format!(
+            "\"{}\" is {} bytes, over max_bytes of {} with {} colours",
+            name,
+            bytes.len(),
+            max_bytes,
+            indexed.colours.len()
+        ));
+    }
+    let description = format!(
+        " ({} colour palette, {} bytes)",
+        indexed.colours.len(),
+        bytes.len()
+    );
+    Ok(Encoded {
+        bytes,
+        size: img.dimensions(),
+        description,
+    })
+}
+
+/// Encodes `img` within the limits in `encoding`, described by the quality and size used.
+fn encode_to_fit(
+    img: &DynamicImage,
+    format: &ImageOutputFormat,
+    encoding: &Encoding,
+    name: &str,
+) -> Result<Encoded, String> {
+    let fitted = pipeline::encode_to_fit(img, format, encoding)?;
+    if !fitted.fits {
+        output::warn(&format!(
+            "\"{}\" is {} bytes, over max_bytes of {}{}",
+            name,
+            fitted.bytes.len(),
+            encoding.max_bytes.unwrap_or_default(),
+            fitted
+                .quality
+                .map(|x| format!(" even at quality {x}"))
+                .unwrap_or_default()
+        ));
+    }
+    let description = format!(
+        " ({}{} bytes{})",
+        fitted
+            .quality
+            .map(|x| format!("quality {x}, "))
+            .unwrap_or_default(),
+        fitted.bytes.len(),
+        if (fitted.width, fitted.height) == img.dimensions() {
+            String::new()
         } else {
-            let output_path = output_path_from(Path::new(&input_path))?;
-            print!("auto generated path \"{}\"", output_path);
-            output_path
-        };
-        separator();
-        ptemp
+            format!(", scaled to {}x{}", fitted.width, fitted.height)
+        }
+    );
+    Ok(Encoded {
+        bytes: fitted.bytes,
+        size: (fitted.width, fitted.height),
+        description,
     })
-    .set_error("Failed to save.")
 }
 
-#[allow(clippy::similar_names)]
+#[allow(clippy::similar_names, clippy::too_many_lines)]
 fn file_share_or_combine(
     settings_one: SharedSettings,
     settings_two: SharedSettings,
 ) -> SharedSettings {
+    let trim = settings_one.jobs.trim.or(settings_two.jobs.trim);
     let resize = settings_one.jobs.resize.or(settings_two.jobs.resize);
+    let pad = settings_one.jobs.pad.or(settings_two.jobs.pad);
     let blur = settings_one.jobs.blur.or(settings_two.jobs.blur);
     let sharpen = settings_one.jobs.sharpen.or(settings_two.jobs.sharpen);
     let adjust_contrast = settings_one
@@ -298,10 +850,101 @@ fn file_share_or_combine(
     let rotate270 = settings_one.jobs.rotate270 || settings_two.jobs.rotate270;
     let grayscale = settings_one.jobs.grayscale || settings_two.jobs.grayscale;
     let invert = settings_one.jobs.invert || settings_two.jobs.invert;
+    let saturation = settings_one
+        .jobs
+        .saturation
+        .or(settings_two.jobs.saturation);
+    let gamma = settings_one.jobs.gamma.or(settings_two.jobs.gamma);
+    let exposure = settings_one.jobs.exposure.or(settings_two.jobs.exposure);
+    let levels = settings_one.jobs.levels.or(settings_two.jobs.levels);
+    let curves = settings_one.jobs.curves.or(settings_two.jobs.curves);
+    let auto_levels = settings_one
+        .jobs
+        .auto_levels
+        .or(settings_two.jobs.auto_levels);
+    let auto_contrast = settings_one
+        .jobs
+        .auto_contrast
+        .or(settings_two.jobs.auto_contrast);
+    let equalize = settings_one.jobs.equalize.or(settings_two.jobs.equalize);
+    let sepia = settings_one.jobs.sepia.or(settings_two.jobs.sepia);
+    let tint = settings_one.jobs.tint.or(settings_two.jobs.tint);
+    let duotone = settings_one.jobs.duotone.or(settings_two.jobs.duotone);
+    let posterize = settings_one.jobs.posterize.or(settings_two.jobs.posterize);
+    let pixelate = settings_one.jobs.pixelate.or(settings_two.jobs.pixelate);
+    let vignette = settings_one.jobs.vignette.or(settings_two.jobs.vignette);
+    let convolve = settings_one.jobs.convolve.or(settings_two.jobs.convolve);
+    let border = settings_one.jobs.border.or(settings_two.jobs.border);
+    let rounded_corners = settings_one
+        .jobs
+        .rounded_corners
+        .or(settings_two.jobs.rounded_corners);
+    let mask = settings_one.jobs.mask.or(settings_two.jobs.mask);
+    let background = settings_one
+        .jobs
+        .background
+        .or(settings_two.jobs.background);
+    let script = settings_one.jobs.script.or(settings_two.jobs.script);
     let resize_filter = settings_one.resize_filter.or(settings_two.resize_filter);
+    let after_save = settings_one.after_save.or(settings_two.after_save);
+    let encoding = Encoding {
+        max_bytes: settings_one
+            .encoding
+            .max_bytes
+            .or(settings_two.encoding.max_bytes),
+        min_quality: settings_one
+            .encoding
+            .min_quality
+            .or(settings_two.encoding.min_quality),
+        min_ssim: settings_one
+            .encoding
+            .min_ssim
+            .or(settings_two.encoding.min_ssim),
+        shrink_to_fit: settings_one
+            .encoding
+            .shrink_to_fit
+            .or(settings_two.encoding.shrink_to_fit),
+        palette: match (settings_one.encoding.palette, settings_two.encoding.palette) {
+            (Some(one), Some(two)) => Some(Palette {
+                colors: one.colors.or(two.colors),
+                dither: one.dither.or(two.dither),
+            }),
+            (one, two) => one.or(two),
+        },
+    };
+    let animation = Animation {
+        speed: settings_one
+            .animation
+            .speed
+            .or(settings_two.animation.speed),
+        frame_step: settings_one
+            .animation
+            .frame_step
+            .or(settings_two.animation.frame_step),
+        poster_frame: settings_one
+            .animation
+            .poster_frame
+            .or(settings_two.animation.poster_frame),
+    };
+    let placeholders = Placeholders {
+        blurhash: settings_one
+            .placeholders
+            .blurhash
+            .or(settings_two.placeholders.blurhash),
+        preview: settings_one
+            .placeholders
+            .preview
+            .or(settings_two.placeholders.preview),
+        dominant_colour: settings_one
+            .placeholders
+            .dominant_colour
+            .or(settings_two.placeholders.dominant_colour),
+    };
     SharedSettings {
         jobs: ImgEditJobs {
+            trim,
             resize,
+            pad,
             blur,
             sharpen,
             adjust_contrast,
@@ -314,17 +957,76 @@ fn file_share_or_combine(
             rotate270,
             grayscale,
             invert,
+            saturation,
+            gamma,
+            exposure,
+            levels,
+            curves,
+            auto_levels,
+            auto_contrast,
+            equalize,
+            sepia,
+            tint,
+            duotone,
+            posterize,
+            pixelate,
+            vignette,
+            convolve,
+            border,
+            rounded_corners,
+            mask,
+            background,
+            script,
         },
         resize_filter,
+        after_save,
+        placeholders,
+        encoding,
+        animation,
     }
 }
 
-fn separator() {
-    println!("\n\n{}\n", {
-        if let Some((width, _)) = term_size::dimensions() {
-            repeat("-").take(width).collect::<String>()
-        } else {
-            String::from("------------")
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn argument_errors_are_failures() {
+        let e = app()
+            .get_matches_from_safe(["image-watcher", "-c", "--brightn", "20"])
+            .unwrap_err();
+        // clap exits with 1 for errors it prints to stderr, and 0 for help and version.
+        assert!(e.use_stderr());
+        assert!(app()
+            .get_matches_from_safe(["image-watcher", "-c", "--brighten", "-20"])
+            .is_ok());
+    }
+
+    #[test]
+    fn reload_rebuilds_every_image_when_globals_change() {
+        let path = std::env::temp_dir().join("image-watcher-reload.png");
+        fs::write(&path, "").unwrap();
+        let config = Settings {
+            files_list: vec![FileWatch {
+                path: path.to_str().unwrap().to_string(),
+                output: None,
+                other: SharedSettings::default(),
+            }],
+            ..Settings::default()
+        };
+        let rebuilt = |previous: &Settings| watched_files(&config, Some(previous)).unwrap().rebuild;
+        assert!(rebuilt(&config).is_empty());
+        for previous in [
+            Settings {
+                placeholders_file: Some(String::from("other.json")),
+                ..config.clone()
+            },
+            Settings {
+                snippets: Some(parse::Snippets::default()),
+                ..config.clone()
+            },
+        ] {
+            assert_eq!(rebuilt(&previous), [0]);
         }
-    })
+    }
 }
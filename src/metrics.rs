@@ -0,0 +1,212 @@
+use image::{DynamicImage, FilterType, GenericImageView, GrayImage};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex, PoisonError,
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static MEASURED: Mutex<Vec<(String, Quality)>> = Mutex::new(Vec::new());
+
+/// Side of the square windows SSIM is averaged over.
+const WINDOW: u32 = 8;
+
+/// How close a saved image is to the image before it was encoded.
+#[derive(Debug, Clone, Copy)]
+pub struct Quality {
+    /// Structural similarity of the luma, from 1 for identical down to 0.
+    pub ssim: f64,
+    /// Peak signal to noise ratio of the colour channels in decibels, infinite for identical.
+    pub psnr: f64,
+}
+
+/// Measures every saved image, for `--measure`.
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Compares `output` to `reference`, scaling the reference to the output's size if they differ.
+pub fn compare(reference: &DynamicImage, output: &DynamicImage) -> Quality {
+    let (width, height) = output.dimensions();
+    let scaled;
+    let reference = if reference.dimensions() == (width, height) {
+        reference
+    } else {
+        scaled = reference.resize_exact(width, height, FilterType::Lanczos3);
+        &scaled
+    };
+    Quality {
+        ssim: ssim(&reference.to_luma(), &output.to_luma()),
+        psnr: psnr(reference, output),
+    }
+}
+
+/// Keeps `quality` for the run summary.
+pub fn record(path: &str, quality: Quality) {
+    MEASURED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push((path.to_string(), quality));
+}
+
+/// The averages and worst SSIM of every image measured so far, if any were.
+#[allow(clippy::cast_precision_loss)]
+pub fn summary() -> Option<String> {
+    let measured = MEASURED.lock().unwrap_or_else(PoisonError::into_inner);
+    let count = measured.len() as f64;
+    let (worst_path, worst) = measured
+        .iter()
+        .min_by(|a, b| a.1.ssim.total_cmp(&b.1.ssim))?;
+    let ssim = measured.iter().map(|(_, x)| x.ssim).sum::<f64>() / count;
+    // Lossless images would make the average infinite, so only lossy ones count towards it.
+    let lossy: Vec<f64> = measured
+        .iter()
+        .map(|(_, x)| x.psnr)
+        .filter(|x| x.is_finite())
+        .collect();
+    let psnr = if lossy.is_empty() {
+        f64::INFINITY
+    } else {
+        lossy.iter().sum::<f64>() / lossy.len() as f64
+    };
+    Some(format!(
+        "Average SSIM {:.4}, PSNR {}. Lowest SSIM {:.4} for \"{}\".",
+        ssim,
+        decibels(psnr),
+        worst.ssim,
+        worst_path
+    ))
+}
+
+impl Quality {
+    pub fn describe(self) -> String {
+        format!("SSIM {:.4}, PSNR {}", self.ssim, decibels(self.psnr))
+    }
+}
+
+fn decibels(psnr: f64) -> String {
+    if psnr.is_finite() {
+        format!("{psnr:.2} dB")
+    } else {
+        String::from("lossless")
+    }
+}
+
+/// The mean SSIM of windows spaced half a window apart, or of the whole image if it is smaller
+/// than one window.
+#[allow(clippy::cast_precision_loss)]
+fn ssim(reference: &GrayImage, output: &GrayImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    let (width, height) = reference.dimensions();
+    if width == 0 || height == 0 {
+        return 1.0;
+    }
+    let window_width = WINDOW.min(width);
+    let window_height = WINDOW.min(height);
+    let mut total = 0.0;
+    let mut windows = 0;
+    for top in (0..=height - window_height).step_by((window_height as usize / 2).max(1)) {
+        for left in (0..=width - window_width).step_by((window_width as usize / 2).max(1)) {
+            let mut sums = [0.0; 5];
+            for y in top..top + window_height {
+                for x in left..left + window_width {
+                    let a = f64::from(reference.get_pixel(x, y).data[0]);
+                    let b = f64::from(output.get_pixel(x, y).data[0]);
+                    sums[0] += a;
+                    sums[1] += b;
+                    sums[2] += a * a;
+                    sums[3] += b * b;
+                    sums[4] += a * b;
+                }
+            }
+            let n = f64::from(window_width * window_height);
+            let (mean_a, mean_b) = (sums[0] / n, sums[1] / n);
+            let variance_a = sums[2] / n - mean_a * mean_a;
+            let variance_b = sums[3] / n - mean_b * mean_b;
+            let covariance = sums[4] / n - mean_a * mean_b;
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (variance_a + variance_b + C2));
+            windows += 1;
+        }
+    }
+    if windows == 0 {
+        1.0
+    } else {
+        total / f64::from(windows)
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn psnr(reference: &DynamicImage, output: &DynamicImage) -> f64 {
+    let (reference, output) = (reference.to_rgb(), output.to_rgb());
+    let count = reference.as_ref().len();
+    if count == 0 {
+        return f64::INFINITY;
+    }
+    let squared_error: f64 = reference
+        .as_ref()
+        .iter()
+        .zip(output.as_ref().iter())
+        .map(|(a, b)| (f64::from(*a) - f64::from(*b)).powi(2))
+        .sum();
+    let mean = squared_error / count as f64;
+    if mean == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (255.0 * 255.0 / mean).log10()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            Rgb([(x * 8) as u8, (y * 8) as u8, 128])
+        }))
+    }
+
+    #[test]
+    fn identical_images_are_perfect() {
+        let img = gradient(24, 16);
+        let quality = compare(&img, &img);
+        assert!((quality.ssim - 1.0).abs() < 1e-9);
+        assert!(quality.psnr.is_infinite());
+        assert_eq!(quality.describe(), "SSIM 1.0000, PSNR lossless");
+    }
+
+    #[test]
+    fn noise_lowers_both() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(16, 16, Rgb([100, 100, 100])));
+        // Every channel off by 10 is a mean squared error of 100.
+        let noisy = DynamicImage::ImageRgb8(RgbImage::from_fn(16, 16, |x, y| {
+            let value = if (x + y) % 2 == 0 { 90 } else { 110 };
+            Rgb([value, value, value])
+        }));
+        let quality = compare(&img, &noisy);
+        assert!((quality.psnr - 10.0 * (65025.0_f64 / 100.0).log10()).abs() < 1e-9);
+        assert!(quality.ssim > 0.0 && quality.ssim < 1.0);
+        let noisier = DynamicImage::ImageRgb8(RgbImage::from_fn(16, 16, |x, y| {
+            let value = if (x + y) % 2 == 0 { 70 } else { 130 };
+            Rgb([value, value, value])
+        }));
+        let worse = compare(&img, &noisier);
+        assert!(worse.psnr < quality.psnr);
+        assert!(worse.ssim < quality.ssim);
+    }
+
+    #[test]
+    fn reference_is_scaled_to_the_output() {
+        let reference = DynamicImage::ImageRgb8(RgbImage::from_pixel(40, 30, Rgb([50, 150, 250])));
+        let output = DynamicImage::ImageRgb8(RgbImage::from_pixel(20, 15, Rgb([50, 150, 250])));
+        let quality = compare(&reference, &output);
+        assert!(quality.ssim > 0.99);
+        assert!(quality.psnr > 40.0);
+    }
+}
@@ -0,0 +1,256 @@
+use crate::cli::Verbosity;
+use std::{
+    collections::HashSet,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+static VERBOSITY: AtomicUsize = AtomicUsize::new(Verbosity::Normal as usize);
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+static PROGRESS: Mutex<Option<Progress>> = Mutex::new(None);
+
+struct Progress {
+    total: usize,
+    /// The outputs saved so far, so one saved again after a retry is only counted once.
+    done: HashSet<String>,
+    started: Instant,
+    width: usize,
+}
+
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as usize, Ordering::SeqCst);
+}
+
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::SeqCst) {
+        0 => Verbosity::Quiet,
+        1 => Verbosity::Normal,
+        2 => Verbosity::Verbose,
+        _ => Verbosity::Debug,
+    }
+}
+
+/// Sends all further output to stderr, leaving stdout free for image data.
+pub fn use_stderr() {
+    TO_STDERR.store(true, Ordering::SeqCst);
+}
+
+/// Whether messages meant for `level` are printed.
+fn enabled(level: Verbosity) -> bool {
+    verbosity() >= level
+}
+
+/// Errors are printed at every verbosity level.
+pub fn error(msg: &str) {
+    emit(&format!("Error: {msg}"));
+}
+
+/// Problems that do not stop an image being saved, hidden with `-q`.
+pub fn warn(msg: &str) {
+    if enabled(Verbosity::Normal) {
+        emit(&format!("Warning: {msg}"));
+    }
+}
+
+/// A single line of normal output, such as one per processed image.
+pub fn info(msg: &str) {
+    if enabled(Verbosity::Normal) && !progress_active() {
+        emit(msg);
+    }
+}
+
+/// A description of each job as it runs, shown with `-v`.
+pub fn detail(msg: &str) {
+    if enabled(Verbosity::Verbose) {
+        emit(msg);
+    }
+}
+
+/// Timings and other internals, shown with `-vv`.
+pub fn debug(msg: &str) {
+    if enabled(Verbosity::Debug) {
+        emit(msg);
+    }
+}
+
+/// Draws a terminal wide line between images when printing every job.
+pub fn separator() {
+    if enabled(Verbosity::Verbose) {
+        let width = term_size::dimensions().map_or(12, |(width, _)| width);
+        emit(&format!("{}\n", "-".repeat(width)));
+    }
+}
+
+/// Replaces the per image lines with a progress bar when stdout is a terminal.
+pub fn start_progress(total: usize) {
+    if verbosity() != Verbosity::Normal || TO_STDERR.load(Ordering::SeqCst) {
+        return;
+    }
+    if let Some((width, _)) = term_size::dimensions_stdout() {
+        let mut progress = lock_progress();
+        *progress = Some(Progress {
+            total,
+            done: HashSet::new(),
+            started: Instant::now(),
+            width,
+        });
+        if let Some(bar) = progress.as_ref() {
+            bar.draw();
+        }
+    }
+}
+
+/// Counts `output` as saved, once however many times it is saved.
+pub fn image_done(output: &str) {
+    if let Some(bar) = lock_progress().as_mut() {
+        if bar.done.insert(output.to_string()) {
+            bar.draw();
+        }
+    }
+}
+
+pub fn finish_progress() {
+    if lock_progress().take().is_some() {
+        println!();
+    }
+}
+
+fn progress_active() -> bool {
+    lock_progress().is_some()
+}
+
+fn lock_progress() -> std::sync::MutexGuard<'static, Option<Progress>> {
+    PROGRESS
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+fn emit(msg: &str) {
+    let progress = lock_progress();
+    if let Some(bar) = progress.as_ref() {
+        print!("\r{}\r", " ".repeat(bar.width));
+    }
+    if TO_STDERR.load(Ordering::SeqCst) {
+        eprintln!("{msg}");
+    } else {
+        println!("{msg}");
+    }
+    if let Some(bar) = progress.as_ref() {
+        bar.draw();
+    }
+}
+
+impl Progress {
+    fn draw(&self) {
+        print!("\r{}", self.line());
+        io::stdout().flush().ok();
+    }
+
+    #[allow(clippy::cast_precision_loss, clippy::cast_sign_loss)]
+    fn line(&self) -> String {
+        let done = self.done.len();
+        let counter = format!(" {}/{}", done, self.total);
+        let eta = if done == 0 {
+            String::from(" ETA --:--")
+        } else {
+            let elapsed = self.started.elapsed().as_secs_f64();
+            let remaining = elapsed / done as f64 * self.total.saturating_sub(done) as f64;
+            format!(
+                " ETA {}",
+                format_duration(Duration::from_secs(remaining as u64))
+            )
+        };
+        let bar_width = self
+            .width
+            .saturating_sub(counter.len() + eta.len() + 3)
+            .min(50);
+        let filled = (bar_width * done)
+            .checked_div(self.total)
+            .unwrap_or(bar_width)
+            .min(bar_width);
+        format!(
+            "[{}{}]{}{}",
+            "#".repeat(filled),
+            "-".repeat(bar_width - filled),
+            counter,
+            eta
+        )
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress(total: usize, done: usize) -> Progress {
+        Progress {
+            total,
+            done: (0..done).map(|x| x.to_string()).collect(),
+            started: Instant::now(),
+            width: 40,
+        }
+    }
+
+    #[test]
+    fn draw_fills_the_bar_as_images_are_done() {
+        assert_eq!(
+            progress(4, 0).line(),
+            "[-----------------------] 0/4 ETA --:--"
+        );
+        assert_eq!(
+            progress(4, 2).line(),
+            "[############------------] 2/4 ETA 0:00"
+        );
+        assert_eq!(
+            progress(4, 4).line(),
+            "[########################] 4/4 ETA 0:00"
+        );
+        assert_eq!(
+            progress(0, 0).line(),
+            "[#######################] 0/0 ETA --:--"
+        );
+    }
+
+    #[test]
+    fn draw_survives_more_done_than_total() {
+        assert_eq!(
+            progress(2, 5).line(),
+            "[########################] 5/2 ETA 0:00"
+        );
+        let narrow = Progress {
+            width: 5,
+            ..progress(2, 1)
+        };
+        assert_eq!(narrow.line(), "[] 1/2 ETA 0:00");
+    }
+
+    #[test]
+    fn images_are_counted_once() {
+        *lock_progress() = Some(progress(2, 0));
+        image_done("a.png");
+        image_done("a.png");
+        let bar = lock_progress().take().unwrap();
+        assert_eq!(bar.done.len(), 1);
+    }
+
+    #[test]
+    fn verbosity_filters_output() {
+        let before = verbosity();
+        set_verbosity(Verbosity::Quiet);
+        assert!(!enabled(Verbosity::Normal));
+        set_verbosity(Verbosity::Normal);
+        assert!(enabled(Verbosity::Normal) && !enabled(Verbosity::Verbose));
+        set_verbosity(Verbosity::Debug);
+        assert!(enabled(Verbosity::Verbose) && enabled(Verbosity::Debug));
+        set_verbosity(before);
+    }
+}
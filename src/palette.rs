@@ -0,0 +1,392 @@
+use crate::{
+    output,
+    parse::{Dither, Palette},
+    pipeline,
+};
+use color_quant::NeuQuant;
+use image::{DynamicImage, GenericImageView, ImageOutputFormat, RgbaImage};
+use png::HasParameters;
+use set_error::ChangeError;
+use std::{borrow::Cow, convert::TryFrom};
+
+/// How many pixels the quantizer skips between the ones it learns from. Lower is slower and better.
+const SAMPLE_FACTOR: i32 = 10;
+
+/// Thresholds for ordered dithering, each from 0 to 63.
+const BAYER: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// An image as indexes into a palette of RGBA colours.
+pub struct Indexed {
+    pub width: u32,
+    pub height: u32,
+    pub colours: Vec<[u8; 4]>,
+    pub indexes: Vec<u8>,
+}
+
+/// Picks at most the palette's number of colours for `img` with a neural network quantizer, then
+/// maps every pixel to one, dithering if asked. Colours no pixel ended up using are dropped.
+pub fn quantize(img: &DynamicImage, palette: &Palette) -> Indexed {
+    let (width, height) = img.dimensions();
+    let most = palette.colors.unwrap_or(256);
+    let rgba = img.to_rgba();
+    let quantizer = NeuQuant::new(SAMPLE_FACTOR, most as usize, &rgba);
+    let mut indexes = match palette.dither.unwrap_or(Dither::FloydSteinberg) {
+        Dither::None => rgba
+            .pixels()
+            .map(|x| quantizer.index_of(&x.data) as u8)
+            .collect(),
+        Dither::Ordered => ordered(&rgba, &quantizer, most),
+        Dither::FloydSteinberg => floyd_steinberg(&rgba, &quantizer),
+    };
+    let colour_map = quantizer.color_map_rgba();
+    let mut used = vec![None; most as usize];
+    let mut colours = Vec::new();
+    for index in &mut indexes {
+        let slot = &mut used[usize::from(*index)];
+        *index = *slot.get_or_insert_with(|| {
+            let start = usize::from(*index) * 4;
+            colours.push([
+                colour_map[start],
+                colour_map[start + 1],
+                colour_map[start + 2],
+                colour_map[start + 3],
+            ]);
+            (colours.len() - 1) as u8
+        });
+    }
+    output::detail(&format!("Quantized to {} colours", colours.len()));
+    Indexed {
+        width,
+        height,
+        colours,
+        indexes,
+    }
+}
+
+/// Encodes `indexed` as an indexed PNG or GIF if `format` is one, or as `format` after
+/// expanding it back to colours if not.
+pub fn encode(indexed: &Indexed, format: ImageOutputFormat) -> Result<Vec<u8>, String> {
+    match format {
+        ImageOutputFormat::PNG => encode_png(indexed),
+        ImageOutputFormat::GIF => encode_gif(indexed),
+        format => pipeline::encode(&indexed.to_image(), format),
+    }
+}
+
+impl Indexed {
+    pub fn to_image(&self) -> DynamicImage {
+        let mut rgba = RgbaImage::new(self.width, self.height);
+        for (pixel, index) in rgba.pixels_mut().zip(&self.indexes) {
+            pixel.data = self.colours[usize::from(*index)];
+        }
+        DynamicImage::ImageRgba8(rgba)
+    }
+}
+
+fn ordered(rgba: &RgbaImage, quantizer: &NeuQuant, colors: u32) -> Vec<u8> {
+    // About the distance between neighbouring palette colours on each channel.
+    let spread = 255.0 / f64::from(colors).cbrt();
+    rgba.enumerate_pixels()
+        .map(|(x, y, pixel)| {
+            let threshold = (f64::from(BAYER[y as usize % 8][x as usize % 8]) + 0.5) / 64.0 - 0.5;
+            let mut pixel = pixel.data;
+            for channel in &mut pixel[..3] {
+                *channel = clamp(f64::from(*channel) + threshold * spread);
+            }
+            quantizer.index_of(&pixel) as u8
+        })
+        .collect()
+}
+
+/// Spreads each pixel's difference from its palette colour onto the pixels right and below.
+fn floyd_steinberg(rgba: &RgbaImage, quantizer: &NeuQuant) -> Vec<u8> {
+    let (width, height) = (rgba.width() as usize, rgba.height() as usize);
+    let mut values: Vec<f64> = rgba.as_ref().iter().map(|x| f64::from(*x)).collect();
+    let colour_map = quantizer.color_map_rgba();
+    let mut indexes = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let start = (y * width + x) * 4;
+            let mut pixel = [0; 4];
+            for (channel, value) in pixel.iter_mut().zip(&values[start..start + 4]) {
+                *channel = clamp(*value);
+            }
+            let index = quantizer.index_of(&pixel);
+            indexes.push(index as u8);
+            for channel in 0..4 {
+                let error = values[start + channel] - f64::from(colour_map[index * 4 + channel]);
+                let mut spread = |dx: isize, dy: usize, share: f64| {
+                    let Some(nx) = x.checked_add_signed(dx).filter(|nx| *nx < width) else {
+                        return;
+                    };
+                    if y + dy < height {
+                        values[((y + dy) * width + nx) * 4 + channel] += error * share;
+                    }
+                };
+                spread(1, 0, 7.0 / 16.0);
+                spread(-1, 1, 3.0 / 16.0);
+                spread(0, 1, 5.0 / 16.0);
+                spread(1, 1, 1.0 / 16.0);
+            }
+        }
+    }
+    indexes
+}
+
+/// Writes an indexed PNG with as few bits per pixel as the palette needs, and the alpha of each
+/// colour if any are not opaque.
+fn encode_png(indexed: &Indexed) -> Result<Vec<u8>, String> {
+    let bits: u8 = match indexed.colours.len() {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    };
+    let per_byte = 8 / usize::from(bits);
+    let row_bytes = (indexed.width as usize).div_ceil(per_byte);
+    let mut data = vec![0; row_bytes * indexed.height as usize];
+    for (row, indexes) in data
+        .chunks_mut(row_bytes)
+        .zip(indexed.indexes.chunks(indexed.width.max(1) as usize))
+    {
+        for (x, index) in indexes.iter().enumerate() {
+            let shift = 8 - bits * (x % per_byte) as u8 - bits;
+            row[x / per_byte] |= index << shift;
+        }
+    }
+    let palette: Vec<u8> = indexed
+        .colours
+        .iter()
+        .flat_map(|x| [x[0], x[1], x[2]])
+        .collect();
+    let mut alphas: Vec<u8> = indexed.colours.iter().map(|x| x[3]).collect();
+    while alphas.last() == Some(&255) {
+        alphas.pop();
+    }
+    let mut bytes = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut bytes, indexed.width, indexed.height);
+        encoder
+            .set(png::ColorType::Indexed)
+            .set(png::BitDepth::from_u8(bits).set_error("Invalid PNG bit depth.")?);
+        let mut writer = encoder
+            .write_header()
+            .set_error("Failed to encode indexed PNG.")?;
+        writer
+            .write_chunk(*b"PLTE", &palette)
+            .set_error("Failed to encode indexed PNG.")?;
+        if !alphas.is_empty() {
+            writer
+                .write_chunk(*b"tRNS", &alphas)
+                .set_error("Failed to encode indexed PNG.")?;
+        }
+        writer
+            .write_image_data(&data)
+            .set_error("Failed to encode indexed PNG.")?;
+    }
+    Ok(bytes)
+}
+
+fn encode_gif(indexed: &Indexed) -> Result<Vec<u8>, String> {
+    encode_gif_frames(&[(indexed, 0)], None)
+}
+
+/// Writes a GIF of `frames`, each a whole image with its delay in hundredths of a second.
+/// `loops` is how many times to repeat, 0 being forever, or play once if not set.
+pub fn encode_gif_frames(
+    frames: &[(&Indexed, u16)],
+    loops: Option<u16>,
+) -> Result<Vec<u8>, String> {
+    let (first, _) = frames
+        .first()
+        .set_error("A GIF needs at least one frame.")?;
+    if frames
+        .iter()
+        .any(|(x, _)| (x.width, x.height) != (first.width, first.height))
+    {
+        return Err(String::from("Every frame of a GIF must be the same size."));
+    }
+    let width = u16::try_from(first.width).set_error("Image is too wide for a GIF.")?;
+    let height = u16::try_from(first.height).set_error("Image is too high for a GIF.")?;
+    let mut bytes = Vec::new();
+    {
+        let mut encoder =
+            gif::Encoder::new(&mut bytes, width, height, &[]).set_error("Failed to encode GIF.")?;
+        let repeat = match loops {
+            Some(0) => Some(gif::Repeat::Infinite),
+            Some(x) => Some(gif::Repeat::Finite(x)),
+            None => None,
+        };
+        repeat
+            .map_or(Ok(()), |x| gif::SetParameter::set(&mut encoder, x))
+            .set_error("Failed to encode GIF.")?;
+        for (indexed, delay) in frames {
+            encoder
+                .write_frame(&gif_frame(indexed, width, height, *delay))
+                .set_error("Failed to encode GIF.")?;
+        }
+    }
+    Ok(bytes)
+}
+
+/// A whole GIF frame with its own palette. GIFs only have one transparent colour, so every
+/// colour under half opacity becomes it, and the frame is cleared before the next is drawn.
+fn gif_frame(indexed: &Indexed, width: u16, height: u16, delay: u16) -> gif::Frame<'static> {
+    let transparent = indexed.colours.iter().position(|x| x[3] < 128);
+    let buffer: Vec<u8> = match transparent {
+        Some(transparent) => indexed
+            .indexes
+            .iter()
+            .map(|x| {
+                if indexed.colours[usize::from(*x)][3] < 128 {
+                    transparent as u8
+                } else {
+                    *x
+                }
+            })
+            .collect(),
+        None => indexed.indexes.clone(),
+    };
+    // A GIF palette needs at least two colours.
+    let mut palette: Vec<u8> = indexed
+        .colours
+        .iter()
+        .flat_map(|x| [x[0], x[1], x[2]])
+        .collect();
+    palette.resize(palette.len().max(6), 0);
+    gif::Frame {
+        width,
+        height,
+        delay,
+        dispose: gif::DisposalMethod::Background,
+        transparent: transparent.map(|x| x as u8),
+        palette: Some(palette),
+        buffer: Cow::Owned(buffer),
+        ..gif::Frame::default()
+    }
+}
+
+#[allow(clippy::cast_sign_loss)]
+fn clamp(value: f64) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use png::HasParameters;
+
+    /// A gradient with a different colour in almost every pixel.
+    fn gradient(alpha: bool) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(40, 12, |x, y| {
+            image::Rgba([
+                (x * 6) as u8,
+                (y * 20) as u8,
+                ((x + y) * 4) as u8,
+                if alpha && x < 4 { 0 } else { 255 },
+            ])
+        }))
+    }
+
+    fn palette(colors: u32, dither: Dither) -> Palette {
+        Palette {
+            colors: Some(colors),
+            dither: Some(dither),
+        }
+    }
+
+    const DITHERS: [Dither; 3] = [Dither::None, Dither::Ordered, Dither::FloydSteinberg];
+
+    #[test]
+    fn palettes_never_have_more_than_colors() {
+        for dither in DITHERS {
+            for colors in [2, 5, 16, 256] {
+                let indexed = quantize(&gradient(false), &palette(colors, dither));
+                assert!(indexed.colours.len() <= colors as usize);
+                assert_eq!(indexed.indexes.len(), 40 * 12);
+                assert!(indexed
+                    .indexes
+                    .iter()
+                    .all(|x| usize::from(*x) < indexed.colours.len()));
+            }
+        }
+    }
+
+    #[test]
+    fn dithering_is_deterministic() {
+        for dither in DITHERS {
+            let first = quantize(&gradient(true), &palette(8, dither));
+            let second = quantize(&gradient(true), &palette(8, dither));
+            assert_eq!(first.indexes, second.indexes);
+            assert_eq!(first.colours, second.colours);
+        }
+    }
+
+    /// The palette and indexes of a PNG, unpacked to one byte each.
+    fn decode_png(bytes: &[u8]) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let mut decoder = png::Decoder::new(bytes);
+        decoder.set(png::Transformations::IDENTITY);
+        let (info, mut reader) = decoder.read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut data).unwrap();
+        let bits = info.bit_depth as usize;
+        let mut indexes = Vec::new();
+        for row in data.chunks(info.line_size) {
+            for x in 0..info.width as usize {
+                let byte = row[x * bits / 8];
+                let shift = 8 - bits * (x % (8 / bits)) - bits;
+                indexes.push((byte >> shift) & (0xff >> (8 - bits)));
+            }
+        }
+        let palette = reader.info().palette.clone().unwrap();
+        let alphas = reader.info().trns.clone().unwrap_or_default();
+        (palette, alphas, indexes)
+    }
+
+    #[test]
+    fn png_decodes_to_the_same_indexes() {
+        for colors in [2, 4, 16, 64] {
+            let indexed = quantize(&gradient(true), &palette(colors, Dither::FloydSteinberg));
+            let (palette, alphas, decoded) = decode_png(&encode_png(&indexed).unwrap());
+            assert_eq!(decoded, indexed.indexes);
+            for (index, colour) in indexed.colours.iter().enumerate() {
+                assert_eq!(palette[index * 3..index * 3 + 3], colour[..3]);
+                assert_eq!(alphas.get(index).copied().unwrap_or(255), colour[3]);
+            }
+        }
+    }
+
+    #[test]
+    fn gif_decodes_to_the_same_indexes() {
+        let indexed = quantize(&gradient(false), &palette(32, Dither::Ordered));
+        let bytes = encode_gif(&indexed).unwrap();
+        let mut reader = gif::Decoder::new(&bytes[..]).read_info().unwrap();
+        let frame = reader.read_next_frame().unwrap().unwrap();
+        assert_eq!((frame.width, frame.height), (40, 12));
+        assert_eq!(frame.buffer.as_ref(), &indexed.indexes[..]);
+        let palette = frame.palette.clone().unwrap();
+        for (index, colour) in indexed.colours.iter().enumerate() {
+            assert_eq!(palette[index * 3..index * 3 + 3], colour[..3]);
+        }
+    }
+
+    #[test]
+    fn gif_frames_must_match() {
+        let small = quantize(&gradient(false), &palette(4, Dither::None));
+        let large = Indexed {
+            width: 41,
+            ..quantize(&gradient(false), &palette(4, Dither::None))
+        };
+        assert!(encode_gif_frames(&[(&small, 10), (&large, 10)], Some(0)).is_err());
+        assert!(encode_gif_frames(&[], None).is_err());
+    }
+}
@@ -2,27 +2,51 @@ use image::FilterType::{self, *};
 use read_input::prelude::*;
 use set_error::ChangeError;
 use std::{
-    convert::TryFrom, fmt, fs::File, io::prelude::*, iter::Iterator, str::FromStr, string::ToString,
+    convert::TryFrom, fmt, fs::File, io::prelude::*, iter::Iterator, mem, ops::RangeInclusive,
+    path::Path, str::FromStr, string::ToString, time::Duration,
 };
 use yaml_rust::{yaml::Hash, Yaml, YamlLoader};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Settings {
     pub files_list: Vec<FileWatch>,
     pub other: SharedSettings,
+    pub after_build: Option<Hook>,
+    pub contact_sheets: Vec<ContactSheet>,
+    pub sprites: Vec<SpriteSheet>,
+    pub icon_sets: Vec<IconSet>,
+    /// Where placeholder data is written, `placeholders.json` if not set.
+    pub placeholders_file: Option<String>,
+    pub snippets: Option<Snippets>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct FileWatch {
     pub path: String,
     pub output: Option<String>,
     pub other: SharedSettings,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct SharedSettings {
     pub jobs: ImgEditJobs,
     pub resize_filter: Option<FilterType>,
+    pub after_save: Option<Hook>,
+    pub placeholders: Placeholders,
+    pub encoding: Encoding,
+    pub animation: Animation,
+}
+
+impl PartialEq for SharedSettings {
+    fn eq(&self, other: &Self) -> bool {
+        self.jobs == other.jobs
+            && self.resize_filter.as_ref().map(mem::discriminant)
+                == other.resize_filter.as_ref().map(mem::discriminant)
+            && self.after_save == other.after_save
+            && self.placeholders == other.placeholders
+            && self.encoding == other.encoding
+            && self.animation == other.animation
+    }
 }
 
 impl fmt::Debug for SharedSettings {
@@ -31,9 +55,12 @@ impl fmt::Debug for SharedSettings {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct ImgEditJobs {
+    pub trim: Option<Trim>,
     pub resize: Option<Resize>,
+    pub pad: Option<Pad>,
     pub blur: Option<f32>,
     pub sharpen: Option<i32>,
     pub adjust_contrast: Option<f32>,
@@ -46,208 +73,1792 @@ pub struct ImgEditJobs {
     pub rotate270: bool,
     pub grayscale: bool,
     pub invert: bool,
+    /// Multiplies how far each colour is from grey, where 0 is grayscale and 1 is unchanged.
+    pub saturation: Option<f32>,
+    /// Above 1 brightens the midtones and below 1 darkens them.
+    pub gamma: Option<f32>,
+    /// Stops of exposure, where each stop doubles the light.
+    pub exposure: Option<f32>,
+    pub levels: Option<Levels>,
+    pub curves: Option<Curves>,
+    /// How much to stretch each channel to black and white, from 0 to 1.
+    pub auto_levels: Option<f32>,
+    /// How much to stretch the luma to black and white, from 0 to 1.
+    pub auto_contrast: Option<f32>,
+    pub equalize: Option<Equalize>,
+    /// How much to tone the image brown, from 0 to 1.
+    pub sepia: Option<f32>,
+    pub tint: Option<Tint>,
+    pub duotone: Option<Duotone>,
+    /// Values kept per channel, from 2 to 256.
+    pub posterize: Option<u32>,
+    /// Width and height of each block in pixels.
+    pub pixelate: Option<u32>,
+    pub vignette: Option<Vignette>,
+    pub convolve: Option<Convolve>,
+    pub border: Option<Border>,
+    /// Radius of the corners in pixels.
+    pub rounded_corners: Option<u32>,
+    pub mask: Option<Mask>,
+    /// Colour transparent pixels are drawn over.
+    pub background: Option<[u8; 4]>,
+    /// Path to a Rhai script run after every other job.
+    pub script: Option<String>,
+}
+
+/// Maps the input range between `in_black` and `in_white` onto the output range between
+/// `out_black` and `out_white`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Levels {
+    pub in_black: u8,
+    pub in_white: u8,
+    pub out_black: u8,
+    pub out_white: u8,
+}
+
+/// Tone curves through control points of input and output values. `rgb` applies to every channel,
+/// then each channel's own curve. An empty curve leaves the channel unchanged.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Curves {
+    pub rgb: Vec<(u8, u8)>,
+    pub red: Vec<(u8, u8)>,
+    pub green: Vec<(u8, u8)>,
+    pub blue: Vec<(u8, u8)>,
+}
+
+/// Histogram equalization, over the whole image or in a grid of tiles.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Equalize {
+    /// How much of the change to keep, from 0 to 1.
+    pub strength: f32,
+    /// Whether to equalize each tile separately, like CLAHE.
+    pub local: bool,
+    /// Tiles across and down when `local`.
+    pub tiles: u32,
+    /// How many times the average count a brightness can have in a tile, limiting how much
+    /// contrast and noise is added.
+    pub clip_limit: f32,
+}
+
+/// A colour every pixel is mixed with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tint {
+    pub colour: [u8; 4],
+    /// How much of the colour to mix in, from 0 to 1.
+    pub strength: f32,
+}
+
+/// Two colours that dark and light pixels become, with everything between blended.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Duotone {
+    pub shadows: [u8; 4],
+    pub highlights: [u8; 4],
+}
+
+/// Border rows and columns to crop away.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trim {
+    /// The border's colour, or transparent pixels if not set.
+    pub colour: Option<[u8; 4]>,
+    /// How far each channel can be from the colour, or the highest alpha trimmed if transparent.
+    pub tolerance: u8,
+}
+
+/// Space added around the image in pixels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pad {
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+    pub left: u32,
+    pub colour: [u8; 4],
+}
+
+/// A border drawn inside the edges of the image, following rounded corners and shaped masks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Border {
+    pub width: u32,
+    pub colour: [u8; 4],
+}
+
+/// A shape outside of which the image is made transparent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mask {
+    /// The largest circle in the centre of the image.
+    Circle,
+    /// The ellipse touching every edge of the image.
+    Ellipse,
+    /// Path to an image stretched over the image, where white keeps pixels and black or
+    /// transparent hides them.
+    Image(String),
 }
-#[derive(Debug, Clone)]
+
+/// Edges faded towards a colour.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vignette {
+    /// How far from the centre to the corners the fade starts, from 0 to 1.
+    pub radius: f32,
+    /// How much of the colour the corners get, from 0 to 1.
+    pub strength: f32,
+    pub colour: [u8; 4],
+}
+
+/// A convolution run over every colour channel.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Convolve {
+    Kernel(Kernel),
+    /// The strength of the edges found by both Sobel kernels.
+    Sobel,
+}
+
+/// A square kernel with an odd size, where each result is the weighted sum divided by `divisor`
+/// plus `offset`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Kernel {
+    pub size: u32,
+    /// Weights row by row, `size` times `size` of them.
+    pub weights: Vec<f32>,
+    pub divisor: f32,
+    pub offset: f32,
+}
+
+/// The largest kernel size allowed, as the time taken grows with its square.
+const MAX_KERNEL_SIZE: u32 = 15;
+
+/// Limits on the saved file, met by lowering quality and optionally size.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Encoding {
+    pub max_bytes: Option<u64>,
+    /// The lowest JPEG quality tried when fitting `max_bytes`, 40 if not set.
+    pub min_quality: Option<u8>,
+    /// The lowest SSIM a JPEG can have, which raises the lowest quality tried.
+    pub min_ssim: Option<f32>,
+    /// Whether to scale the image down when the lowest quality is still too large.
+    pub shrink_to_fit: Option<bool>,
+    pub palette: Option<Palette>,
+}
+
+/// How animated GIFs are played back. Fields are options so a file can change one that is set
+/// for every file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Animation {
+    /// Multiplies how fast every frame plays.
+    pub speed: Option<f32>,
+    /// Keeps one of every this many frames, adding the delays of those dropped to the one kept.
+    pub frame_step: Option<u32>,
+    /// Saves only this frame, counting from 0, as a still image.
+    pub poster_frame: Option<u32>,
+}
+
+/// Reduces the saved image to a palette, saved as an indexed PNG or GIF where the format allows.
+/// Fields are options so a file can change one that is set for every file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Palette {
+    /// The most colours to keep, from 2 to 256, 256 if not set.
+    pub colors: Option<u32>,
+    /// Floyd-Steinberg if not set.
+    pub dither: Option<Dither>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dither {
+    FloydSteinberg,
+    Ordered,
+    None,
+}
+
+/// Which placeholders to work out for lazy loading an image. These are options so a file can turn
+/// off one that is turned on for every file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Placeholders {
+    pub blurhash: Option<bool>,
+    /// A tiny image as a base64 data URI.
+    pub preview: Option<bool>,
+    /// The most common and average colours.
+    pub dominant_colour: Option<bool>,
+}
+
+/// Where to write `<picture>` markup for every size and format saved from each image.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Snippets {
+    /// A folder to write one HTML partial per image to.
+    pub html: Option<String>,
+    /// A file to write every snippet's parts to, for templates.
+    pub json: Option<String>,
+    pub sizes: Option<String>,
+    /// The folder served at `/`, so output paths can be turned into URLs.
+    pub root: Option<String>,
+}
+
+/// Many images scaled down to fit a grid of cells and saved as one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContactSheet {
+    /// The paths or globs as written in the config.
+    pub patterns: Vec<String>,
+    /// Every image the paths matched, in the order they are laid out.
+    pub images: Vec<String>,
+    pub output: String,
+    pub columns: u32,
+    pub cell_width: u32,
+    pub cell_height: u32,
+    pub padding: u32,
+    /// RGBA colour behind the images.
+    pub background: [u8; 4],
+    /// Whether to write each file name under its image.
+    pub captions: bool,
+}
+
+/// Small images packed into one atlas, with a stylesheet and JSON map giving where each one is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpriteSheet {
+    /// Each image with its own jobs, applied before packing.
+    pub images: Vec<FileWatch>,
+    pub output: String,
+    pub css: Option<String>,
+    pub json: Option<String>,
+    /// Pixels between images, so they do not bleed into each other when scaled.
+    pub padding: u32,
+    /// Put before each image's name to make its class name.
+    pub prefix: String,
+    /// Jobs for every image, which their own jobs take priority over.
+    pub other: SharedSettings,
+}
+
+/// Every size of favicon and app icon, made from one square logo.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IconSet {
+    pub path: String,
+    /// The folder the icons and manifest are saved in.
+    pub output: String,
+    /// Space around the logo in each icon, as a percentage of its size.
+    pub padding: u32,
+    /// RGBA colour behind the logo, filling the space around sources that are not square.
+    pub background: [u8; 4],
+    pub other: SharedSettings,
+}
+
+/// A shell command to run, killed if it takes longer than `timeout`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hook {
+    pub command: String,
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Resize {
     pub size: Size,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Size {
     Width(u32),
     Height(u32),
     WidthHeight(u32, u32),
 }
 
-pub fn parse_config() -> Result<Settings, String> {
-    fn get_jobs(yaml: &Hash) -> Result<ImgEditJobs, String> {
-        fn get_i32(yaml: &Hash, field: &str) -> Result<Option<i32>, String> {
-            Ok(match yaml.get(&Yaml::String(field.to_string())) {
-                Some(x) => Some({
-                    x.as_i64()
-                        .and_then(|x| i32::try_from(x).ok())
-                        .set_error(&format!("{} value is valid: Not a valid number", field))?
-                }),
-                None => None,
-            })
+pub fn resize_filter_from_name(name: &str) -> Result<FilterType, String> {
+    match name {
+        "Nearest" => Ok(Nearest),
+        "Triangle" => Ok(Triangle),
+        "CatmullRom" => Ok(CatmullRom),
+        "Gaussian" => Ok(Gaussian),
+        "Lanczos3" => Ok(Lanczos3),
+        _ => Err(format!("Unknown resize_filter {name}")),
+    }
+}
+
+/// Finds the config file, asking for its path if there is no `image_watcher.yaml` here.
+pub fn config_path() -> String {
+    if File::open("image_watcher.yaml").is_ok() {
+        String::from("image_watcher.yaml")
+    } else {
+        let fail_msg = "Failed to open config file.";
+        println!("{fail_msg}");
+        input::<NewTypeFile>()
+            .repeat_msg("Input path to config file: ")
+            .err(fail_msg)
+            .get()
+            .0
+    }
+}
+
+/// The keys for jobs and the resize filter, which can also be given outside of the config file.
+pub const JOB_KEYS: &[&str] = &[
+    "trim",
+    "width",
+    "height",
+    "resize_filter",
+    "blur",
+    "sharpen",
+    "contrast",
+    "brighten",
+    "huerotate",
+    "flipv",
+    "fliph",
+    "rotate90",
+    "rotate180",
+    "rotate270",
+    "grayscale",
+    "invert",
+    "saturation",
+    "gamma",
+    "exposure",
+    "levels",
+    "curves",
+    "auto_levels",
+    "auto_contrast",
+    "equalize",
+    "sepia",
+    "tint",
+    "duotone",
+    "posterize",
+    "pixelate",
+    "vignette",
+    "convolve",
+    "border",
+    "rounded_corners",
+    "mask",
+    "pad",
+    "background",
+];
+
+/// Reads the jobs and resize filter set directly in `yaml`, for either one file or every file.
+pub fn get_shared_settings(yaml: &Hash) -> Result<SharedSettings, String> {
+    Ok(SharedSettings {
+        jobs: get_jobs(yaml)?,
+        resize_filter: resize_filter_getter(yaml.get(&Yaml::String("resize_filter".to_string())))?,
+        after_save: get_hook(yaml, "after_save")?,
+        placeholders: Placeholders {
+            blurhash: get_optional_bool(yaml, "blurhash")?,
+            preview: get_optional_bool(yaml, "preview")?,
+            dominant_colour: get_optional_bool(yaml, "dominant_colour")?,
+        },
+        encoding: Encoding {
+            max_bytes: get_bytes(yaml, "max_bytes")?,
+            min_quality: in_range("min_quality", get_u32(yaml, "min_quality")?, 1..=100)?
+                .map(|x| x as u8),
+            min_ssim: in_range("min_ssim", get_float(yaml, "min_ssim")?, 0.0..=1.0)?,
+            shrink_to_fit: get_optional_bool(yaml, "shrink_to_fit")?,
+            palette: get_palette(yaml)?,
+        },
+        animation: Animation {
+            speed: above_zero("speed", get_float(yaml, "speed")?)?,
+            frame_step: above_zero("frame_step", get_u32(yaml, "frame_step")?)?,
+            poster_frame: get_u32(yaml, "poster_frame")?,
+        },
+    })
+}
+
+const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_mins(1);
+
+/// Reads a hook given either as just a command or as a hash with a `command` and a `timeout` in
+/// seconds.
+fn get_hook(yaml: &Hash, field: &str) -> Result<Option<Hook>, String> {
+    Ok(match yaml.get(&Yaml::String(field.to_string())) {
+        Some(Yaml::String(command)) => Some(Hook {
+            command: command.clone(),
+            timeout: DEFAULT_HOOK_TIMEOUT,
+        }),
+        Some(Yaml::Hash(hook)) => Some(Hook {
+            command: hook
+                .get(&Yaml::String("command".to_string()))
+                .and_then(|x| x.clone().into_string())
+                .set_error(&format!("{field} has no command"))?,
+            timeout: match get_float(hook, "timeout")? {
+                Some(x) if x > 0.0 => Duration::from_secs_f32(x),
+                Some(_) => return Err(format!("{field} timeout must be positive")),
+                None => DEFAULT_HOOK_TIMEOUT,
+            },
+        }),
+        Some(_) => return Err(format!("{field} value is not valid: Not a command")),
+        None => None,
+    })
+}
+
+/// Finds every file matching `pattern`, or just `pattern` itself if it is not a glob.
+pub fn expand_glob(pattern: &str) -> Result<Vec<String>, String> {
+    if !pattern.contains(['*', '?', '[']) {
+        return Ok(vec![pattern.to_string()]);
+    }
+    let mut paths = Vec::new();
+    for path in glob::glob(pattern).map_err(|e| format!("Invalid glob {pattern}: {e}"))? {
+        let path = path.map_err(|e| format!("Failed to read {}", e.path().display()))?;
+        paths.push(
+            path.to_str()
+                .ok_or_else(|| format!("{} is not a valid path", path.display()))?
+                .to_string(),
+        );
+    }
+    if paths.is_empty() {
+        return Err(format!("No images match {pattern}"));
+    }
+    Ok(paths)
+}
+
+/// Reads a colour written as `#rgb`, `#rrggbb` or `#rrggbbaa`.
+pub fn colour_from_hex(hex: &str) -> Result<[u8; 4], String> {
+    let error = || format!("{hex} is not a colour like #ffffff");
+    let digits = hex.strip_prefix('#').ok_or_else(error)?;
+    let digits: String = if digits.len() == 3 {
+        digits.chars().flat_map(|x| [x, x]).collect()
+    } else {
+        digits.to_string()
+    };
+    if !(digits.len() == 6 || digits.len() == 8) || !digits.is_ascii() {
+        return Err(error());
+    }
+    let mut colour = [255; 4];
+    for (index, channel) in colour.iter_mut().enumerate().take(digits.len() / 2) {
+        *channel =
+            u8::from_str_radix(&digits[index * 2..index * 2 + 2], 16).map_err(|_| error())?;
+    }
+    Ok(colour)
+}
+
+/// Every image matching `patterns` once each, in order, leaving out the sheet's own `output`.
+pub fn sheet_images(patterns: &[String], output: &str) -> Result<Vec<String>, String> {
+    let mut images: Vec<String> = Vec::new();
+    for pattern in patterns {
+        for path in expand_glob(pattern)? {
+            // The sheet is often saved next to its images, so it must not include itself.
+            if !images.contains(&path) && Path::new(&path) != Path::new(output) {
+                images.push(path);
+            }
         }
-        fn get_bool(yaml: &Hash, field: &str) -> Result<bool, String> {
-            Ok(match yaml.get(&Yaml::String(field.to_string())) {
-                Some(x) => x
-                    .clone()
-                    .into_bool()
-                    .set_error(&format!("{} value is valid: Not true or false.", field))?,
-                None => false,
+    }
+    Ok(images)
+}
+
+fn get_contact_sheet(yaml: &Hash, index: usize) -> Result<ContactSheet, String> {
+    let paths = match yaml.get(&Yaml::String("paths".to_string())) {
+        Some(Yaml::String(x)) => vec![x.clone()],
+        Some(Yaml::Array(x)) => x
+            .iter()
+            .map(|x| {
+                x.clone().into_string().set_error(&format!(
+                    "contact sheet index {index} has a path that is not a string"
+                ))
             })
+            .collect::<Result<_, _>>()?,
+        _ => return Err(format!("contact sheet index {index} has no paths")),
+    };
+    let output = get_string(yaml, "output")?
+        .set_error(&format!("contact sheet index {index} has no output"))?;
+    let images = sheet_images(&paths, &output)?;
+    if images.is_empty() {
+        return Err(format!("contact sheet index {index} has no images"));
+    }
+    let positive = |field: &str, default: u32| -> Result<u32, String> {
+        match get_u32(yaml, field)? {
+            Some(0) => Err(format!("{field} value is not valid: Must be more than 0")),
+            x => Ok(x.unwrap_or(default)),
         }
-        fn get_float(yaml: &Hash, field: &str) -> Result<Option<f32>, String> {
-            Ok(match yaml.get(&Yaml::String(field.to_string())) {
-                Some(x) => Some({
-                    let f = x
-                        .clone()
-                        .into_f64()
-                        .set_error(&format!("{} value is valid: Not Float", field))?;
-                    if f < f64::from(core::f32::MAX) {
-                        f as f32
-                    } else {
-                        core::f32::MAX
-                    }
-                }),
+    };
+    Ok(ContactSheet {
+        patterns: paths,
+        images,
+        output,
+        columns: positive("columns", 4)?,
+        cell_width: positive("cell_width", 200)?,
+        cell_height: positive("cell_height", 200)?,
+        padding: get_u32(yaml, "padding")?.unwrap_or(10),
+        background: match get_string(yaml, "background")? {
+            Some(x) => colour_from_hex(&x)?,
+            None => [255; 4],
+        },
+        captions: get_bool(yaml, "captions")?,
+    })
+}
+
+/// Reads the section at `key`, which can be one hash or a list of them.
+fn get_outputs<T>(
+    yaml: &Hash,
+    key: &str,
+    get_output: fn(&Hash, usize) -> Result<T, String>,
+) -> Result<Vec<T>, String> {
+    match yaml.get(&Yaml::String(key.to_string())) {
+        Some(Yaml::Hash(x)) => Ok(vec![get_output(x, 0)?]),
+        Some(Yaml::Array(list)) => {
+            let mut outputs = Vec::new();
+            for (index, output) in list.iter().enumerate() {
+                outputs.push(get_output(
+                    output
+                        .as_hash()
+                        .set_error(&format!("{key} index {index} is not a hash"))?,
+                    index,
+                )?);
+            }
+            Ok(outputs)
+        }
+        Some(_) => Err(format!("{key} section in config is not a hash or list.")),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn get_icon_set(yaml: &Hash, index: usize) -> Result<IconSet, String> {
+    Ok(IconSet {
+        path: get_string(yaml, "path")?
+            .set_error(&format!("icon set index {index} has no path"))?,
+        output: get_string(yaml, "output")?
+            .set_error(&format!("icon set index {index} has no output folder"))?,
+        padding: match get_u32(yaml, "padding")? {
+            Some(x) if x >= 50 => {
+                return Err(String::from(
+                    "padding value is not valid: Must be less than 50",
+                ))
+            }
+            x => x.unwrap_or(0),
+        },
+        background: match get_string(yaml, "background")? {
+            Some(x) => colour_from_hex(&x)?,
+            None => [0; 4],
+        },
+        other: {
+            // Here background fills the space around the logo rather than flattening it.
+            let mut shared = yaml.clone();
+            shared.remove(&Yaml::String(String::from("background")));
+            get_shared_settings(&shared)?
+        },
+    })
+}
+
+fn get_sprite(yaml: &Hash, index: usize) -> Result<SpriteSheet, String> {
+    let output =
+        get_string(yaml, "output")?.set_error(&format!("sprite index {index} has no output"))?;
+    let entries = yaml
+        .get(&Yaml::String("images".to_string()))
+        .and_then(Yaml::as_vec)
+        .set_error(&format!("sprite index {index} has no list of images"))?;
+    let mut images: Vec<FileWatch> = Vec::new();
+    for (image_index, entry) in entries.iter().enumerate() {
+        let (pattern, other) = match entry {
+            Yaml::String(x) => (x.clone(), SharedSettings::default()),
+            Yaml::Hash(x) => (
+                get_string(x, "path")?.set_error(&format!(
+                    "sprite index {index} image index {image_index} has no path"
+                ))?,
+                get_shared_settings(x)?,
+            ),
+            _ => {
+                return Err(format!(
+                    "sprite index {index} image index {image_index} is not a path or hash"
+                ))
+            }
+        };
+        for path in expand_glob(&pattern)? {
+            if Path::new(&path) != Path::new(&output) {
+                images.push(FileWatch {
+                    path,
+                    output: None,
+                    other: other.clone(),
+                });
+            }
+        }
+    }
+    if images.is_empty() {
+        return Err(format!("sprite index {index} has no images"));
+    }
+    Ok(SpriteSheet {
+        images,
+        output,
+        css: get_string(yaml, "css")?,
+        json: get_string(yaml, "json")?,
+        padding: get_u32(yaml, "padding")?.unwrap_or(2),
+        prefix: get_string(yaml, "prefix")?.unwrap_or_else(|| String::from("icon-")),
+        other: get_shared_settings(yaml)?,
+    })
+}
+
+fn get_jobs(yaml: &Hash) -> Result<ImgEditJobs, String> {
+    Ok(ImgEditJobs {
+        trim: get_trim(yaml)?,
+        resize: get_size(yaml)?.map(|x| Resize { size: x }),
+        pad: get_pad(yaml)?,
+        blur: get_float(yaml, "blur")?,
+        sharpen: get_i32(yaml, "sharpen")?,
+        adjust_contrast: get_float(yaml, "contrast")?,
+        brighten: get_i32(yaml, "brighten")?,
+        huerotate: get_i32(yaml, "huerotate")?,
+        flipv: get_bool(yaml, "flipv")?,
+        fliph: get_bool(yaml, "fliph")?,
+        rotate90: get_bool(yaml, "rotate90")?,
+        rotate180: get_bool(yaml, "rotate180")?,
+        rotate270: get_bool(yaml, "rotate270")?,
+        grayscale: get_bool(yaml, "grayscale")?,
+        invert: get_bool(yaml, "invert")?,
+        saturation: not_negative("saturation", get_float(yaml, "saturation")?)?,
+        gamma: above_zero("gamma", get_float(yaml, "gamma")?)?,
+        exposure: get_float(yaml, "exposure")?,
+        levels: get_levels(yaml)?,
+        curves: get_curves(yaml)?,
+        auto_levels: get_strength(yaml, "auto_levels")?,
+        auto_contrast: get_strength(yaml, "auto_contrast")?,
+        equalize: get_equalize(yaml)?,
+        sepia: get_strength(yaml, "sepia")?,
+        tint: get_tint(yaml)?,
+        duotone: get_duotone(yaml)?,
+        posterize: in_range("posterize", get_u32(yaml, "posterize")?, 2..=256)?,
+        pixelate: above_zero("pixelate", get_u32(yaml, "pixelate")?)?,
+        vignette: get_vignette(yaml)?,
+        convolve: get_convolve(yaml)?,
+        border: get_border(yaml)?,
+        rounded_corners: get_u32(yaml, "rounded_corners")?,
+        mask: get_string(yaml, "mask")?.map(|x| mask_from_str(&x)),
+        background: match get_string(yaml, "background")? {
+            Some(x) => Some(colour_from_hex(&x)?),
+            None => None,
+        },
+        script: get_string(yaml, "script")?,
+    })
+}
+
+/// Reads how strongly to apply an automatic job, where `true` is fully and `false` is not at all.
+fn get_strength(yaml: &Hash, field: &str) -> Result<Option<f32>, String> {
+    match yaml.get(&Yaml::String(field.to_string())) {
+        Some(Yaml::Boolean(x)) => Ok(Some(1.0).filter(|_| *x)),
+        Some(_) => match get_float(yaml, field)? {
+            Some(x) if (0.0..=1.0).contains(&x) => Ok(Some(x)),
+            _ => Err(format!(
+                "{field} value is not valid: Must be true, false or between 0 and 1"
+            )),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Reads equalize as a strength, or a hash with `strength`, `local`, `tiles` and `clip_limit`.
+fn get_equalize(yaml: &Hash) -> Result<Option<Equalize>, String> {
+    let defaults = |strength| Equalize {
+        strength,
+        local: false,
+        tiles: 8,
+        clip_limit: 2.0,
+    };
+    match yaml.get(&Yaml::String(String::from("equalize"))) {
+        Some(Yaml::Hash(x)) => Ok(Some(Equalize {
+            strength: get_strength(x, "strength")?.unwrap_or(1.0),
+            local: get_bool(x, "local")?,
+            tiles: match get_u32(x, "tiles")? {
+                Some(0) => {
+                    return Err(String::from(
+                        "equalize tiles value is not valid: Must be above 0",
+                    ))
+                }
+                Some(x) => x,
+                None => 8,
+            },
+            clip_limit: match get_float(x, "clip_limit")? {
+                Some(x) if x < 1.0 => {
+                    return Err(String::from(
+                        "equalize clip_limit value is not valid: Must be at least 1",
+                    ))
+                }
+                Some(x) => x,
+                None => 2.0,
+            },
+        })),
+        Some(_) => Ok(get_strength(yaml, "equalize")?.map(defaults)),
+        None => Ok(None),
+    }
+}
+
+/// Reads a palette as a number of colours, or a hash of `colors` and `dither`.
+fn get_palette(yaml: &Hash) -> Result<Option<Palette>, String> {
+    let (colors, dither) = match yaml.get(&Yaml::String(String::from("palette"))) {
+        Some(Yaml::Hash(x)) => (
+            get_u32(x, "colors")?,
+            match get_string(x, "dither")? {
+                Some(x) => Some(dither_from_name(&x)?),
                 None => None,
+            },
+        ),
+        Some(_) => (get_u32(yaml, "palette")?, None),
+        None => return Ok(None),
+    };
+    Ok(Some(Palette {
+        colors: in_range("palette colors", colors, 2..=256)?,
+        dither,
+    }))
+}
+
+pub fn dither_from_name(name: &str) -> Result<Dither, String> {
+    match name {
+        "floyd_steinberg" => Ok(Dither::FloydSteinberg),
+        "ordered" => Ok(Dither::Ordered),
+        "none" => Ok(Dither::None),
+        _ => Err(format!(
+            "dither value {name} is not valid: Must be floyd_steinberg, ordered or none"
+        )),
+    }
+}
+
+/// Reads a tint as a hash of `colour` and `strength`, or a string like `#ff8800,0.3`. The
+/// strength is 0.5 if not given.
+fn get_tint(yaml: &Hash) -> Result<Option<Tint>, String> {
+    Ok(match yaml.get(&Yaml::String(String::from("tint"))) {
+        Some(Yaml::String(x)) => Some(tint_from_str(x)?),
+        Some(Yaml::Hash(x)) => Some(Tint {
+            colour: colour_from_hex(
+                &get_string(x, "colour")?.set_error("tint value is not valid: Needs a colour")?,
+            )?,
+            strength: get_strength(x, "strength")?.unwrap_or(0.5),
+        }),
+        Some(_) => {
+            return Err(String::from(
+                "tint value is not valid: Not a hash or string",
+            ))
+        }
+        None => None,
+    })
+}
+
+/// Reads a tint like `#ff8800` or `#ff8800,0.3`.
+pub fn tint_from_str(tint: &str) -> Result<Tint, String> {
+    let mut parts = tint.split(',').map(str::trim);
+    let colour = colour_from_hex(parts.next().unwrap_or_default())?;
+    let strength = match parts.next() {
+        Some(x) => x
+            .parse::<f32>()
+            .ok()
+            .filter(|x| (0.0..=1.0).contains(x))
+            .set_error(&format!(
+                "tint value {tint} is not valid: Strength must be between 0 and 1"
+            ))?,
+        None => 0.5,
+    };
+    if parts.next().is_some() {
+        return Err(format!(
+            "tint value {tint} is not valid: Must be a colour and a strength"
+        ));
+    }
+    Ok(Tint { colour, strength })
+}
+
+/// Reads a vignette like `0.4`, `0.4,0.6` or `0.4,0.6,#1b1b3a`, as a strength, a radius and a
+/// colour.
+pub fn vignette_from_str(vignette: &str) -> Result<Vignette, String> {
+    let mut parts = vignette.split(',').map(str::trim);
+    let mut fraction = |name: &str, default: f32| match parts.next() {
+        Some(x) => x
+            .parse::<f32>()
+            .ok()
+            .filter(|x| (0.0..=1.0).contains(x))
+            .set_error(&format!(
+                "vignette value {vignette} is not valid: {name} must be between 0 and 1"
+            )),
+        None => Ok(default),
+    };
+    let strength = fraction("Strength", 0.5)?;
+    let radius = fraction("Radius", 0.5)?;
+    let colour = match parts.next() {
+        Some(x) => colour_from_hex(x)?,
+        None => [0, 0, 0, 255],
+    };
+    if parts.next().is_some() {
+        return Err(format!(
+            "vignette value {vignette} is not valid: Must be a strength, a radius and a colour"
+        ));
+    }
+    Ok(Vignette {
+        radius,
+        strength,
+        colour,
+    })
+}
+
+/// Reads a duotone as a hash of `shadows` and `highlights`, or a string like `#1b1b3a,#ffd166`.
+fn get_duotone(yaml: &Hash) -> Result<Option<Duotone>, String> {
+    Ok(match yaml.get(&Yaml::String(String::from("duotone"))) {
+        Some(Yaml::String(x)) => Some(duotone_from_str(x)?),
+        Some(Yaml::Hash(x)) => {
+            let colour = |field: &str| -> Result<[u8; 4], String> {
+                colour_from_hex(&get_string(x, field)?.set_error(&format!(
+                    "duotone value is not valid: Needs a {field} colour"
+                ))?)
+            };
+            Some(Duotone {
+                shadows: colour("shadows")?,
+                highlights: colour("highlights")?,
             })
         }
+        Some(_) => {
+            return Err(String::from(
+                "duotone value is not valid: Not a hash or string",
+            ))
+        }
+        None => None,
+    })
+}
 
-        Ok(ImgEditJobs {
-            resize: {
-                match get_size(yaml)? {
-                    Some(x) => Some(Resize { size: x }),
-                    None => None,
-                }
+/// Reads a duotone like `#1b1b3a,#ffd166`, shadows first.
+pub fn duotone_from_str(duotone: &str) -> Result<Duotone, String> {
+    match duotone
+        .split(',')
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [shadows, highlights] => Ok(Duotone {
+            shadows: colour_from_hex(shadows)?,
+            highlights: colour_from_hex(highlights)?,
+        }),
+        _ => Err(format!(
+            "duotone value {duotone} is not valid: Must be two colours"
+        )),
+    }
+}
+
+/// Reads a vignette as a strength, or a hash of `radius`, `strength` and `colour`.
+fn get_vignette(yaml: &Hash) -> Result<Option<Vignette>, String> {
+    let vignette = |strength| Vignette {
+        radius: 0.5,
+        strength,
+        colour: [0, 0, 0, 255],
+    };
+    Ok(match yaml.get(&Yaml::String(String::from("vignette"))) {
+        Some(Yaml::Hash(x)) => Some(Vignette {
+            radius: get_strength(x, "radius")?.unwrap_or(0.5),
+            colour: match get_string(x, "colour")? {
+                Some(colour) => colour_from_hex(&colour)?,
+                None => [0, 0, 0, 255],
             },
-            blur: get_float(yaml, "blur")?,
-            sharpen: get_i32(yaml, "sharpen")?,
-            adjust_contrast: get_float(yaml, "contrast")?,
-            brighten: get_i32(yaml, "brighten")?,
-            huerotate: get_i32(yaml, "huerotate")?,
-            flipv: get_bool(yaml, "flipv")?,
-            fliph: get_bool(yaml, "fliph")?,
-            rotate90: get_bool(yaml, "rotate90")?,
-            rotate180: get_bool(yaml, "rotate180")?,
-            rotate270: get_bool(yaml, "rotate270")?,
-            grayscale: get_bool(yaml, "grayscale")?,
-            invert: get_bool(yaml, "invert")?,
-        })
-    }
-
-    fn get_size(yaml: &Hash) -> Result<Option<Size>, String> {
-        fn get_u32(yaml: &Hash, field: &str) -> Result<Option<u32>, String> {
-            Ok(match yaml.get(&Yaml::String(field.to_string())) {
-                Some(x) => Some({
-                    x.as_i64()
-                        .and_then(|x| u32::try_from(x).ok())
-                        .set_error(&format!("{} value is valid: Not a valid number", field))?
-                }),
+            ..vignette(get_strength(x, "strength")?.unwrap_or(0.5))
+        }),
+        Some(_) => get_strength(yaml, "vignette")?.map(vignette),
+        None => None,
+    })
+}
+
+/// Reads a trim as `transparent` or a colour string, or a hash of an optional `colour` and
+/// `tolerance`.
+fn get_trim(yaml: &Hash) -> Result<Option<Trim>, String> {
+    Ok(match yaml.get(&Yaml::String(String::from("trim"))) {
+        Some(Yaml::String(x)) => Some(trim_from_str(x)?),
+        Some(Yaml::Hash(x)) => Some(Trim {
+            colour: match get_string(x, "colour")? {
+                Some(colour) => Some(colour_from_hex(&colour)?),
                 None => None,
+            },
+            tolerance: match get_u32(x, "tolerance")? {
+                Some(x) => u8::try_from(x)
+                    .ok()
+                    .set_error("trim tolerance value is not valid: Must be between 0 and 255")?,
+                None => 0,
+            },
+        }),
+        Some(_) => {
+            return Err(String::from(
+                "trim value is not valid: Not a hash or string",
+            ))
+        }
+        None => None,
+    })
+}
+
+/// Reads a trim like `transparent`, `#ffffff` or `#ffffff,10`.
+pub fn trim_from_str(trim: &str) -> Result<Trim, String> {
+    let mut parts = trim.split(',').map(str::trim);
+    let colour = match parts.next().unwrap_or_default() {
+        "transparent" => None,
+        x => Some(colour_from_hex(x)?),
+    };
+    let tolerance = match parts.next() {
+        Some(x) => x.parse::<u8>().ok().set_error(&format!(
+            "trim value {trim} is not valid: Tolerance must be between 0 and 255"
+        ))?,
+        None => 0,
+    };
+    if parts.next().is_some() {
+        return Err(format!(
+            "trim value {trim} is not valid: Must be a colour or transparent, and a tolerance"
+        ));
+    }
+    Ok(Trim { colour, tolerance })
+}
+
+/// Reads padding as a number of pixels, a string of sides and a colour, or a hash of `size`,
+/// `top`, `right`, `bottom`, `left` and `colour`, where the sides default to `size`.
+fn get_pad(yaml: &Hash) -> Result<Option<Pad>, String> {
+    Ok(match yaml.get(&Yaml::String(String::from("pad"))) {
+        Some(Yaml::String(x)) => Some(pad_from_str(x)?),
+        Some(Yaml::Hash(x)) => {
+            let size = get_u32(x, "size")?.unwrap_or(0);
+            Some(Pad {
+                top: get_u32(x, "top")?.unwrap_or(size),
+                right: get_u32(x, "right")?.unwrap_or(size),
+                bottom: get_u32(x, "bottom")?.unwrap_or(size),
+                left: get_u32(x, "left")?.unwrap_or(size),
+                colour: match get_string(x, "colour")? {
+                    Some(colour) => colour_from_hex(&colour)?,
+                    None => [0; 4],
+                },
             })
         }
-        let width = get_u32(yaml, "width")?;
-        let height = get_u32(yaml, "height")?;
-        Ok(Some(match (width, height) {
-            (Some(width), Some(height)) => Size::WidthHeight(width, height),
-            (Some(width), None) => Size::Width(width),
-            (None, Some(height)) => Size::Height(height),
-            (None, None) => return Ok(None),
-        }))
-    }
-    fn resize_filter_getter(
-        yaml: Option<&yaml_rust::yaml::Yaml>,
-    ) -> Result<Option<FilterType>, String> {
-        Ok(match yaml {
-            Some(x) => {
-                let x = x
-                    .clone()
-                    .into_string()
-                    .set_error("Resize_filter not a string.");
-                match x.clone()?.as_ref() {
-                    "Nearest" => Some(Nearest),
-                    "Triangle" => Some(Triangle),
-                    "CatmullRom" => Some(CatmullRom),
-                    "Gaussian" => Some(Gaussian),
-                    "Lanczos3" => Some(Lanczos3),
-                    _ => return Err(format!("Unknown resize_filter {}", x?)),
+        Some(_) => get_u32(yaml, "pad")?.map(|size| Pad {
+            top: size,
+            right: size,
+            bottom: size,
+            left: size,
+            colour: [0; 4],
+        }),
+        None => None,
+    })
+}
+
+/// Reads padding like `10`, `10,20` or `10,20,30,40`, in the order top, right, bottom and left
+/// as in CSS, optionally followed by a colour like `10,#ffffff`.
+pub fn pad_from_str(pad: &str) -> Result<Pad, String> {
+    let error = || {
+        format!(
+            "pad value {pad} is not valid: Must be 1, 2 or 4 sizes in pixels and an optional colour"
+        )
+    };
+    let mut parts: Vec<&str> = pad.split(',').map(str::trim).collect();
+    let colour = match parts.last() {
+        Some(x) if x.starts_with('#') => {
+            let colour = colour_from_hex(x)?;
+            parts.pop();
+            colour
+        }
+        _ => [0; 4],
+    };
+    let sizes = parts
+        .iter()
+        .map(|x| x.parse::<u32>().ok())
+        .collect::<Option<Vec<u32>>>()
+        .set_error(&error())?;
+    let (top, right, bottom, left) = match sizes[..] {
+        [size] => (size, size, size, size),
+        [vertical, horizontal] => (vertical, horizontal, vertical, horizontal),
+        [top, right, bottom, left] => (top, right, bottom, left),
+        _ => return Err(error()),
+    };
+    Ok(Pad {
+        top,
+        right,
+        bottom,
+        left,
+        colour,
+    })
+}
+
+/// Reads a border as a width in pixels, a string of a width and a colour, or a hash of `width`
+/// and `colour`. The colour is black if not set.
+fn get_border(yaml: &Hash) -> Result<Option<Border>, String> {
+    let border = match yaml.get(&Yaml::String(String::from("border"))) {
+        Some(Yaml::String(x)) => border_from_str(x)?,
+        Some(Yaml::Hash(x)) => Border {
+            width: get_u32(x, "width")?.set_error("border value is not valid: Needs a width")?,
+            colour: match get_string(x, "colour")? {
+                Some(colour) => colour_from_hex(&colour)?,
+                None => [0, 0, 0, 255],
+            },
+        },
+        Some(_) => Border {
+            width: get_u32(yaml, "border")?.unwrap_or_default(),
+            colour: [0, 0, 0, 255],
+        },
+        None => return Ok(None),
+    };
+    if border.width == 0 {
+        return Err(String::from(
+            "border value is not valid: Width must be above 0",
+        ));
+    }
+    Ok(Some(border))
+}
+
+/// Reads a border like `4` or `4,#ffffff`.
+pub fn border_from_str(border: &str) -> Result<Border, String> {
+    let mut parts = border.split(',').map(str::trim);
+    let width = parts
+        .next()
+        .and_then(|x| x.parse::<u32>().ok())
+        .filter(|x| *x > 0)
+        .set_error(&format!(
+            "border value {border} is not valid: Width must be a number of pixels above 0"
+        ))?;
+    let colour = match parts.next() {
+        Some(x) => colour_from_hex(x)?,
+        None => [0, 0, 0, 255],
+    };
+    if parts.next().is_some() {
+        return Err(format!(
+            "border value {border} is not valid: Must be a width and a colour"
+        ));
+    }
+    Ok(Border { width, colour })
+}
+
+/// Reads a mask as `circle`, `ellipse` or the path to a mask image.
+pub fn mask_from_str(mask: &str) -> Mask {
+    match mask {
+        "circle" => Mask::Circle,
+        "ellipse" => Mask::Ellipse,
+        path => Mask::Image(path.to_string()),
+    }
+}
+
+/// Reads a convolution as a preset name, a string of weights, or a hash of `kernel` or `preset`
+/// with an optional `divisor` and `offset`. The kernel can be a list of rows, a flat list or a
+/// string.
+fn get_convolve(yaml: &Hash) -> Result<Option<Convolve>, String> {
+    let x = match yaml.get(&Yaml::String(String::from("convolve"))) {
+        Some(Yaml::String(x)) => return convolve_from_str(x).map(Some),
+        Some(Yaml::Hash(x)) => x,
+        Some(_) => {
+            return Err(String::from(
+                "convolve value is not valid: Not a hash or string",
+            ))
+        }
+        None => return Ok(None),
+    };
+    let weights = match (
+        x.get(&Yaml::String(String::from("kernel"))),
+        get_string(x, "preset")?,
+    ) {
+        (Some(_), Some(_)) => {
+            return Err(String::from(
+                "convolve value is not valid: Has both a kernel and a preset",
+            ))
+        }
+        (None, Some(name)) => match convolve_preset(&name)? {
+            Convolve::Kernel(kernel) => kernel.weights,
+            Convolve::Sobel => return Ok(Some(Convolve::Sobel)),
+        },
+        (Some(Yaml::String(weights)), None) => weights_from_str(weights)?,
+        (Some(Yaml::Array(rows)), None) => {
+            let mut weights = Vec::new();
+            for value in rows {
+                match value {
+                    Yaml::Array(row) => {
+                        for value in row {
+                            weights.push(weight(value)?);
+                        }
+                    }
+                    value => weights.push(weight(value)?),
                 }
             }
-            None => None,
-        })
-    }
-    fn load_file() -> Result<Hash, String> {
-        Ok({
-            YamlLoader::load_from_str(&{
-                let mut contents = String::new();
-
-                if let Ok(x) = File::open("image_watcher.yaml") {
-                    x
-                } else {
-                    let fail_msg = "Failed to open config file.";
-                    println!("{}", fail_msg);
-                    input::<NewTypeFile>()
-                        .repeat_msg("Input path to config file: ")
-                        .err(fail_msg)
-                        .get()
-                        .0
-                }
-                .read_to_string(&mut contents)
-                .set_error("Failed to open read file.")?;
-                contents
+            weights
+        }
+        (Some(_), None) => {
+            return Err(String::from(
+                "convolve kernel value is not valid: Not a list or string",
+            ))
+        }
+        (None, None) => {
+            return Err(String::from(
+                "convolve value is not valid: Needs a kernel or preset",
+            ))
+        }
+    };
+    let mut kernel = kernel(weights)?;
+    if let Some(divisor) = get_float(x, "divisor")? {
+        kernel.divisor = divisor;
+    }
+    if let Some(offset) = get_float(x, "offset")? {
+        kernel.offset = offset;
+    }
+    if kernel.divisor == 0.0 {
+        return Err(String::from(
+            "convolve divisor value is not valid: Must not be 0",
+        ));
+    }
+    Ok(Some(Convolve::Kernel(kernel)))
+}
+
+/// Reads a convolution like `emboss` or `0,-1,0,-1,5,-1,0,-1,0`.
+pub fn convolve_from_str(convolve: &str) -> Result<Convolve, String> {
+    if convolve.contains(',') {
+        Ok(Convolve::Kernel(kernel(weights_from_str(convolve)?)?))
+    } else {
+        convolve_preset(convolve.trim())
+    }
+}
+
+fn convolve_preset(name: &str) -> Result<Convolve, String> {
+    let weights: Vec<f32> = match name {
+        "emboss" => vec![-2.0, -1.0, 0.0, -1.0, 1.0, 1.0, 0.0, 1.0, 2.0],
+        "edge_detect" => vec![-1.0, -1.0, -1.0, -1.0, 8.0, -1.0, -1.0, -1.0, -1.0],
+        "laplacian" => vec![0.0, -1.0, 0.0, -1.0, 4.0, -1.0, 0.0, -1.0, 0.0],
+        "box_blur" => vec![1.0; 9],
+        "sobel" => return Ok(Convolve::Sobel),
+        _ => {
+            return Err(format!(
+                "convolve value {name} is not valid: Presets are emboss, edge_detect, sobel, laplacian and box_blur"
+            ))
+        }
+    };
+    Ok(Convolve::Kernel(kernel(weights)?))
+}
+
+fn weights_from_str(weights: &str) -> Result<Vec<f32>, String> {
+    weights
+        .split(',')
+        .map(|x| x.trim().parse::<f32>())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("convolve kernel value {weights} is not valid: {e}"))
+}
+
+fn weight(value: &Yaml) -> Result<f32, String> {
+    #[allow(clippy::cast_precision_loss)]
+    match value {
+        Yaml::Integer(x) => Ok(*x as f32),
+        Yaml::Real(_) => value
+            .as_f64()
+            .map(|x| x as f32)
+            .set_error("convolve kernel value is not valid: Not a number"),
+        _ => Err(String::from(
+            "convolve kernel value is not valid: Not a number",
+        )),
+    }
+}
+
+/// Checks `weights` make an odd sized square, dividing by their sum, or 1 if they sum to 0.
+fn kernel(weights: Vec<f32>) -> Result<Kernel, String> {
+    let size = (1..=MAX_KERNEL_SIZE)
+        .step_by(2)
+        .find(|size| (size * size) as usize == weights.len())
+        .set_error(&format!(
+            "convolve kernel value is not valid: Must be a square with an odd size up to {}, not {} weights",
+            MAX_KERNEL_SIZE,
+            weights.len()
+        ))?;
+    let sum: f32 = weights.iter().sum();
+    Ok(Kernel {
+        size,
+        divisor: if sum == 0.0 { 1.0 } else { sum },
+        weights,
+        offset: 0.0,
+    })
+}
+
+/// Reads levels as a hash of `in_black`, `in_white`, `out_black` and `out_white`, or a string of
+/// the same numbers separated by commas.
+fn get_levels(yaml: &Hash) -> Result<Option<Levels>, String> {
+    let levels = match yaml.get(&Yaml::String(String::from("levels"))) {
+        Some(Yaml::String(x)) => levels_from_str(x)?,
+        Some(Yaml::Hash(x)) => {
+            let value = |field: &str, default: u8| -> Result<u8, String> {
+                Ok(match get_u32(x, field)? {
+                    Some(value) => u8::try_from(value).set_error(&format!(
+                        "levels {field} value is not valid: Must be between 0 and 255"
+                    ))?,
+                    None => default,
+                })
+            };
+            Levels {
+                in_black: value("in_black", 0)?,
+                in_white: value("in_white", 255)?,
+                out_black: value("out_black", 0)?,
+                out_white: value("out_white", 255)?,
+            }
+        }
+        Some(_) => {
+            return Err(String::from(
+                "levels value is not valid: Not a hash or string",
+            ))
+        }
+        None => return Ok(None),
+    };
+    Ok(Some(check_levels(levels)?))
+}
+
+fn check_levels(levels: Levels) -> Result<Levels, String> {
+    if levels.in_black < levels.in_white {
+        Ok(levels)
+    } else {
+        Err(String::from(
+            "levels value is not valid: in_black must be below in_white",
+        ))
+    }
+}
+
+/// Reads levels like `10,245` or `10,245,0,255`.
+pub fn levels_from_str(levels: &str) -> Result<Levels, String> {
+    let values = levels
+        .split(',')
+        .map(|x| x.trim().parse::<u8>())
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|e| format!("levels value {levels} is not valid: {e}"))?;
+    check_levels(match values.as_slice() {
+        [in_black, in_white] => Levels {
+            in_black: *in_black,
+            in_white: *in_white,
+            out_black: 0,
+            out_white: 255,
+        },
+        [in_black, in_white, out_black, out_white] => Levels {
+            in_black: *in_black,
+            in_white: *in_white,
+            out_black: *out_black,
+            out_white: *out_white,
+        },
+        _ => {
+            return Err(format!(
+                "levels value {levels} is not valid: Must be 2 or 4 numbers"
+            ))
+        }
+    })
+}
+
+/// Reads curves as a string of points for every channel, or a hash of strings for `rgb`, `red`,
+/// `green` and `blue`.
+fn get_curves(yaml: &Hash) -> Result<Option<Curves>, String> {
+    Ok(match yaml.get(&Yaml::String(String::from("curves"))) {
+        Some(Yaml::String(x)) => Some(Curves {
+            rgb: curve_from_str(x)?,
+            ..Curves::default()
+        }),
+        Some(Yaml::Hash(x)) => {
+            let curve = |field: &str| -> Result<Vec<(u8, u8)>, String> {
+                Ok(match get_string(x, field)? {
+                    Some(points) => curve_from_str(&points)?,
+                    None => Vec::new(),
+                })
+            };
+            Some(Curves {
+                rgb: curve("rgb")?,
+                red: curve("red")?,
+                green: curve("green")?,
+                blue: curve("blue")?,
             })
-            .set_error("Failed to parse config file.")?[0]
+        }
+        Some(_) => {
+            return Err(String::from(
+                "curves value is not valid: Not a hash or string",
+            ))
+        }
+        None => None,
+    })
+}
+
+/// Reads control points like `0:0, 64:50, 192:210, 255:255`, sorted by input value.
+pub fn curve_from_str(points: &str) -> Result<Vec<(u8, u8)>, String> {
+    let error = |reason: &str| format!("curves value {points} is not valid: {reason}");
+    let mut curve = Vec::new();
+    for point in points.split(',') {
+        let mut values = point.split(':').map(|x| x.trim().parse::<u8>());
+        match (values.next(), values.next(), values.next()) {
+            (Some(Ok(input)), Some(Ok(output)), None) => curve.push((input, output)),
+            _ => return Err(error("Points must be input:output from 0 to 255")),
+        }
+    }
+    curve.sort_unstable();
+    if curve.len() < 2 {
+        return Err(error("Needs at least 2 points"));
+    }
+    if curve.windows(2).any(|x| x[0].0 == x[1].0) {
+        return Err(error("Two points have the same input"));
+    }
+    Ok(curve)
+}
+
+fn get_size(yaml: &Hash) -> Result<Option<Size>, String> {
+    let width = get_u32(yaml, "width")?;
+    let height = get_u32(yaml, "height")?;
+    Ok(Some(match (width, height) {
+        (Some(width), Some(height)) => Size::WidthHeight(width, height),
+        (Some(width), None) => Size::Width(width),
+        (None, Some(height)) => Size::Height(height),
+        (None, None) => return Ok(None),
+    }))
+}
+
+fn resize_filter_getter(
+    yaml: Option<&yaml_rust::yaml::Yaml>,
+) -> Result<Option<FilterType>, String> {
+    Ok(match yaml {
+        Some(x) => {
+            let x = x
                 .clone()
+                .into_string()
+                .set_error("Resize_filter not a string.")?;
+            Some(resize_filter_from_name(&x)?)
         }
+        None => None,
+    })
+}
+
+fn load_file(path: &str) -> Result<Hash, String> {
+    let mut contents = String::new();
+    File::open(path)
+        .set_error("Failed to open config file.")?
+        .read_to_string(&mut contents)
+        .set_error("Failed to open read file.")?;
+    YamlLoader::load_from_str(&contents)
+        .set_error("Failed to parse config file.")?
+        .into_iter()
+        .next()
+        .set_error("Config file is empty.")?
         .into_hash()
-        .set_error("Base of the file not a hash.")?)
-    }
-    let open_file = load_file()?;
-    let files_list = open_file
-        .get(&Yaml::String("files".to_string()))
-        .set_error("No files section in config file.")?
-        .clone()
-        .into_vec()
-        .set_error("Files section in config is not a list.")?
-        .into_iter();
+        .set_error("Base of the file not a hash.")
+}
+
+fn get_i32(yaml: &Hash, field: &str) -> Result<Option<i32>, String> {
+    Ok(match yaml.get(&Yaml::String(field.to_string())) {
+        Some(x) => Some({
+            x.as_i64()
+                .and_then(|x| i32::try_from(x).ok())
+                .set_error(&format!("{field} value is not valid: Not a valid number"))?
+        }),
+        None => None,
+    })
+}
+fn get_bool(yaml: &Hash, field: &str) -> Result<bool, String> {
+    Ok(match yaml.get(&Yaml::String(field.to_string())) {
+        Some(x) => x
+            .clone()
+            .into_bool()
+            .set_error(&format!("{field} value is not valid: Not true or false."))?,
+        None => false,
+    })
+}
+/// Reads a number of bytes, either as a number or a string like `150KB` or `1.5MB`.
+fn get_bytes(yaml: &Hash, field: &str) -> Result<Option<u64>, String> {
+    Ok(match yaml.get(&Yaml::String(field.to_string())) {
+        Some(Yaml::Integer(x)) => Some(
+            u64::try_from(*x)
+                .set_error(&format!("{field} value is not valid: Must be positive"))?,
+        ),
+        Some(Yaml::String(x)) => {
+            Some(bytes_from_str(x).map_err(|e| format!("{field} value is not valid: {e}"))?)
+        }
+        Some(_) => return Err(format!("{field} value is not valid: Not a size")),
+        None => None,
+    })
+}
+
+/// Checks `value` of `field` is within `range`. Shared by the config file and the command line so
+/// both check the same way.
+pub fn in_range<T: PartialOrd + fmt::Display>(
+    field: &str,
+    value: Option<T>,
+    range: RangeInclusive<T>,
+) -> Result<Option<T>, String> {
+    match value {
+        Some(x) if !range.contains(&x) => Err(format!(
+            "{} value is not valid: Must be between {} and {}",
+            field,
+            range.start(),
+            range.end()
+        )),
+        x => Ok(x),
+    }
+}
+
+pub fn above_zero<T: PartialOrd + Default>(
+    field: &str,
+    value: Option<T>,
+) -> Result<Option<T>, String> {
+    match value {
+        Some(x) if x <= T::default() => Err(format!("{field} value is not valid: Must be above 0")),
+        x => Ok(x),
+    }
+}
+
+pub fn not_negative(field: &str, value: Option<f32>) -> Result<Option<f32>, String> {
+    match value {
+        Some(x) if x < 0.0 => Err(format!("{field} value is not valid: Must not be negative")),
+        x => Ok(x),
+    }
+}
+
+/// Reads a size like `150000`, `150KB` or `1.5MB`, where a kilobyte is 1024 bytes.
+pub fn bytes_from_str(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size
+        .find(|x: char| !(x.is_ascii_digit() || x == '.'))
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let multiplier = match unit.trim().to_ascii_uppercase().as_ref() {
+        "" | "B" => 1.0,
+        "KB" | "KIB" => 1024.0,
+        "MB" | "MIB" => 1024.0 * 1024.0,
+        _ => return Err(format!("Unknown unit {}", unit.trim())),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("{size} is not a size"))?;
+    let bytes = (number * multiplier).round();
+    if (1.0..1e15).contains(&bytes) {
+        #[allow(clippy::cast_sign_loss)]
+        Ok(bytes as u64)
+    } else {
+        Err(format!("{size} is not a size"))
+    }
+}
+
+fn get_optional_bool(yaml: &Hash, field: &str) -> Result<Option<bool>, String> {
+    match yaml.get(&Yaml::String(field.to_string())) {
+        Some(_) => get_bool(yaml, field).map(Some),
+        None => Ok(None),
+    }
+}
+fn get_float(yaml: &Hash, field: &str) -> Result<Option<f32>, String> {
+    Ok(match yaml.get(&Yaml::String(field.to_string())) {
+        Some(x) => Some({
+            #[allow(clippy::cast_precision_loss)]
+            let f = match x {
+                Yaml::Integer(x) => *x as f64,
+                x => x
+                    .clone()
+                    .into_f64()
+                    .set_error(&format!("{field} value is not valid: Not Float"))?,
+            };
+            if f < f64::from(f32::MAX) {
+                f as f32
+            } else {
+                f32::MAX
+            }
+        }),
+        None => None,
+    })
+}
+
+fn get_string(yaml: &Hash, field: &str) -> Result<Option<String>, String> {
+    Ok(match yaml.get(&Yaml::String(field.to_string())) {
+        Some(x) => Some(
+            x.clone()
+                .into_string()
+                .set_error(&format!("{field} value is not valid: Not a string"))?,
+        ),
+        None => None,
+    })
+}
+
+fn get_u32(yaml: &Hash, field: &str) -> Result<Option<u32>, String> {
+    Ok(match yaml.get(&Yaml::String(field.to_string())) {
+        Some(x) => Some({
+            x.as_i64()
+                .and_then(|x| u32::try_from(x).ok())
+                .set_error(&format!("{field} value is not valid: Not a valid number"))?
+        }),
+        None => None,
+    })
+}
+
+pub fn parse_config(path: &str) -> Result<Settings, String> {
+    let open_file = load_file(path)?;
+    let contact_sheets = get_outputs(&open_file, "contact_sheets", get_contact_sheet)?;
+    let sprites = get_outputs(&open_file, "sprite", get_sprite)?;
+    let icon_sets = get_outputs(&open_file, "icons", get_icon_set)?;
+    let files_list = match open_file.get(&Yaml::String("files".to_string())) {
+        Some(x) => x
+            .clone()
+            .into_vec()
+            .set_error("Files section in config is not a list.")?,
+        None if !contact_sheets.is_empty() || !sprites.is_empty() || !icon_sets.is_empty() => {
+            Vec::new()
+        }
+        None => return Err(String::from("No files section in config file.")),
+    }
+    .into_iter();
     let mut files_as_hash_list = Vec::new();
     for (index, file) in files_list.enumerate() {
         files_as_hash_list.push(
             file.into_hash()
-                .set_error(&format!("file index {} is not a hash", index))?,
-        )
+                .set_error(&format!("file index {index} is not a hash"))?,
+        );
     }
     let mut files_list = Vec::new();
     for (index, file) in files_as_hash_list.into_iter().enumerate() {
         files_list.push({
             let path = file
                 .get(&Yaml::String("path".to_string()))
-                .set_error(&format!("file index {} has no path", index))?
+                .set_error(&format!("file index {index} has no path"))?
                 .clone()
                 .into_string()
                 .set_error(&format!(
-                    "file index {} has a path that is not a string",
-                    index
+                    "file index {index} has a path that is not a string"
                 ))?;
             FileWatch {
                 path: path.clone(),
                 output: match file.get(&Yaml::String("output".to_string())) {
                     Some(x) => Some(x.clone().into_string().set_error(&format!(
-                        "file index {} has a output path that is not a string",
-                        index
+                        "file index {index} has a output path that is not a string"
                     ))?),
                     None => None,
                 },
-                other: SharedSettings {
-                    jobs: get_jobs(&file)?,
-                    resize_filter: resize_filter_getter(
-                        file.get(&Yaml::String("resize_filter".to_string())),
-                    )?,
-                },
+                other: get_shared_settings(&file)?,
             }
-        })
+        });
     }
     Ok(Settings {
         files_list,
-        other: SharedSettings {
-            jobs: get_jobs(&open_file)?,
-            resize_filter: resize_filter_getter(
-                open_file.get(&Yaml::String("resize_filter".to_string())),
-            )?,
+        other: get_shared_settings(&open_file)?,
+        after_build: get_hook(&open_file, "after_build")?,
+        contact_sheets,
+        sprites,
+        icon_sets,
+        placeholders_file: get_string(&open_file, "placeholders_file")?,
+        snippets: match open_file.get(&Yaml::String("snippets".to_string())) {
+            Some(Yaml::Hash(x)) => Some(Snippets {
+                html: get_string(x, "html")?,
+                json: get_string(x, "json")?,
+                sizes: get_string(x, "sizes")?,
+                root: get_string(x, "root")?,
+            }),
+            Some(_) => return Err(String::from("Snippets section in config is not a hash.")),
+            None => None,
         },
     })
 }
 
-struct NewTypeFile(File);
+struct NewTypeFile(String);
 
 impl FromStr for NewTypeFile {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self(File::open(s).ok().ok_or(())?))
+        File::open(s).ok().ok_or(())?;
+        Ok(Self(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_checks() {
+        assert_eq!(in_range("q", Some(100), 1..=100), Ok(Some(100)));
+        assert_eq!(in_range("q", None, 1..=100), Ok(None));
+        assert_eq!(
+            in_range("q", Some(0), 1..=100),
+            Err(String::from(
+                "q value is not valid: Must be between 1 and 100"
+            ))
+        );
+        assert_eq!(
+            in_range("s", Some(1.5), 0.0..=1.0),
+            Err(String::from(
+                "s value is not valid: Must be between 0 and 1"
+            ))
+        );
+        assert!(above_zero("speed", Some(0.0)).is_err());
+        assert!(above_zero("frame_step", Some(0_u32)).is_err());
+        assert_eq!(above_zero("frame_step", Some(2_u32)), Ok(Some(2)));
+        assert!(not_negative("saturation", Some(-0.1)).is_err());
+        assert_eq!(not_negative("saturation", Some(0.0)), Ok(Some(0.0)));
+    }
+
+    #[test]
+    fn bytes_from_str_reads_units() {
+        assert_eq!(bytes_from_str("150"), Ok(150));
+        assert_eq!(bytes_from_str("150B"), Ok(150));
+        assert_eq!(bytes_from_str("1.5kb"), Ok(1536));
+        assert_eq!(bytes_from_str(" 2 MiB "), Ok(2 * 1024 * 1024));
+        assert!(bytes_from_str("0").is_err());
+        assert!(bytes_from_str("10GB").is_err());
+        assert!(bytes_from_str("KB").is_err());
+    }
+
+    #[test]
+    fn vignette_from_str_reads_every_part() {
+        assert_eq!(
+            vignette_from_str("0.4"),
+            Ok(Vignette {
+                radius: 0.5,
+                strength: 0.4,
+                colour: [0, 0, 0, 255],
+            })
+        );
+        assert_eq!(
+            vignette_from_str("1, 0.6, #1b1b3a"),
+            Ok(Vignette {
+                radius: 0.6,
+                strength: 1.0,
+                colour: [0x1b, 0x1b, 0x3a, 255],
+            })
+        );
+        assert!(vignette_from_str("2").is_err());
+        assert!(vignette_from_str("0.4,1.5").is_err());
+        assert!(vignette_from_str("0.4,0.5,#000,1").is_err());
+    }
+
+    #[test]
+    fn convolve_kernels_are_odd_squares() {
+        assert_eq!(
+            convolve_from_str("0,-1,0,-1,5,-1,0,-1,0"),
+            Ok(Convolve::Kernel(Kernel {
+                size: 3,
+                weights: vec![0.0, -1.0, 0.0, -1.0, 5.0, -1.0, 0.0, -1.0, 0.0],
+                divisor: 1.0,
+                offset: 0.0,
+            }))
+        );
+        assert_eq!(convolve_from_str("sobel"), Ok(Convolve::Sobel));
+        assert!(convolve_from_str("laplacian").is_ok());
+        assert!(convolve_from_str("1,1,1,1").is_err());
+        assert!(convolve_from_str("1,2,x").is_err());
+        assert!(convolve_from_str("sharpen").is_err());
+        assert!(kernel(vec![1.0; 15 * 15]).is_ok());
+        assert!(kernel(vec![1.0; 17 * 17]).is_err());
+    }
+
+    #[test]
+    fn kernels_divide_by_their_sum_unless_it_is_zero() {
+        let divisor = |weights: &str| match convolve_from_str(weights) {
+            Ok(Convolve::Kernel(kernel)) => kernel.divisor,
+            _ => f32::NAN,
+        };
+        assert!((divisor("1,1,1,1,1,1,1,1,1") - 9.0).abs() < f32::EPSILON);
+        assert!((divisor("0,1,0,1,-4,1,0,1,0") - 1.0).abs() < f32::EPSILON);
+        assert!((divisor("-1,-1,-1,-1,8,-1,-1,-1,-1") - 1.0).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn convolve_reads_hashes() {
+        let convolve = |yaml: &str| get_convolve(&hash(yaml));
+        assert_eq!(
+            convolve("convolve:\n  kernel: [[1, 2, 1], [2, 4, 2], [1, 2, 1]]\n  offset: 10"),
+            Ok(Some(Convolve::Kernel(Kernel {
+                size: 3,
+                weights: vec![1.0, 2.0, 1.0, 2.0, 4.0, 2.0, 1.0, 2.0, 1.0],
+                divisor: 16.0,
+                offset: 10.0,
+            })))
+        );
+        assert_eq!(
+            convolve("convolve:\n  preset: sobel"),
+            Ok(Some(Convolve::Sobel))
+        );
+        assert!(convolve("convolve:\n  preset: emboss\n  kernel: '1'").is_err());
+        assert!(
+            convolve("convolve:\n  kernel: [1, 1, 1, 1, 1, 1, 1, 1, 1]\n  divisor: 0").is_err()
+        );
+        assert!(convolve("convolve:\n  offset: 1").is_err());
+    }
+
+    fn hash(yaml: &str) -> Hash {
+        YamlLoader::load_from_str(yaml).unwrap()[0]
+            .as_hash()
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn pad_reads_css_order() {
+        let pad = |top, right, bottom, left, colour| Pad {
+            top,
+            right,
+            bottom,
+            left,
+            colour,
+        };
+        assert_eq!(pad_from_str("10"), Ok(pad(10, 10, 10, 10, [0; 4])));
+        assert_eq!(pad_from_str("10, 20"), Ok(pad(10, 20, 10, 20, [0; 4])));
+        assert_eq!(pad_from_str("1,2,3,4,#fff"), Ok(pad(1, 2, 3, 4, [255; 4])));
+        assert!(pad_from_str("1,2,3").is_err());
+        assert!(pad_from_str("-1").is_err());
+        assert_eq!(
+            get_pad(&hash("pad:\n  size: 5\n  left: 0")),
+            Ok(Some(pad(5, 5, 5, 0, [0; 4])))
+        );
+        assert_eq!(get_pad(&hash("pad: 3")), Ok(Some(pad(3, 3, 3, 3, [0; 4]))));
+    }
+
+    #[test]
+    fn trim_reads_colour_and_tolerance() {
+        assert_eq!(
+            trim_from_str("transparent"),
+            Ok(Trim {
+                colour: None,
+                tolerance: 0
+            })
+        );
+        assert_eq!(
+            trim_from_str("#ffffff, 10"),
+            Ok(Trim {
+                colour: Some([255; 4]),
+                tolerance: 10
+            })
+        );
+        assert!(trim_from_str("#ffffff,300").is_err());
+        assert!(trim_from_str("white").is_err());
+        assert_eq!(
+            get_trim(&hash("trim:\n  tolerance: 4")),
+            Ok(Some(Trim {
+                colour: None,
+                tolerance: 4
+            }))
+        );
+    }
+
+    #[test]
+    fn colours_are_hex() {
+        assert_eq!(colour_from_hex("#f80"), Ok([255, 136, 0, 255]));
+        assert_eq!(colour_from_hex("#ff880080"), Ok([255, 136, 0, 128]));
+        assert!(colour_from_hex("ff8800").is_err());
+        assert!(colour_from_hex("#ff88").is_err());
+        assert!(colour_from_hex("#gggggg").is_err());
+    }
+
+    #[test]
+    fn levels_and_curves_are_checked() {
+        let levels = |in_black, in_white, out_black, out_white| Levels {
+            in_black,
+            in_white,
+            out_black,
+            out_white,
+        };
+        assert_eq!(levels_from_str("10, 245"), Ok(levels(10, 245, 0, 255)));
+        assert_eq!(
+            levels_from_str("10,245,20,200"),
+            Ok(levels(10, 245, 20, 200))
+        );
+        assert!(levels_from_str("245,10").is_err());
+        assert!(levels_from_str("10,245,0").is_err());
+        assert!(levels_from_str("10,300").is_err());
+        assert_eq!(
+            get_levels(&hash("levels:\n  in_white: 200")),
+            Ok(Some(levels(0, 200, 0, 255)))
+        );
+        assert_eq!(
+            curve_from_str("255:255, 0:0, 64:50"),
+            Ok(vec![(0, 0), (64, 50), (255, 255)])
+        );
+        assert!(curve_from_str("0:0").is_err());
+        assert!(curve_from_str("0:0, 0:10").is_err());
+        assert!(curve_from_str("0:0, 128").is_err());
+        assert_eq!(
+            get_curves(&hash("curves:\n  red: '0:20, 255:255'")),
+            Ok(Some(Curves {
+                red: vec![(0, 20), (255, 255)],
+                ..Curves::default()
+            }))
+        );
+    }
+
+    #[test]
+    fn borders_and_masks() {
+        let border = |width, colour| Border { width, colour };
+        assert_eq!(border_from_str("4"), Ok(border(4, [0, 0, 0, 255])));
+        assert_eq!(border_from_str("4, #fff"), Ok(border(4, [255; 4])));
+        assert!(border_from_str("0").is_err());
+        assert!(border_from_str("4,#fff,2").is_err());
+        assert_eq!(
+            get_border(&hash("border:\n  width: 2\n  colour: '#000'")),
+            Ok(Some(border(2, [0, 0, 0, 255])))
+        );
+        assert!(get_border(&hash("border: 0")).is_err());
+        assert_eq!(mask_from_str("circle"), Mask::Circle);
+        assert_eq!(
+            mask_from_str("masks/star.png"),
+            Mask::Image(String::from("masks/star.png"))
+        );
+    }
+
+    #[test]
+    fn icon_set_background_is_not_a_job() {
+        let set = get_icon_set(
+            &hash("path: logo.png\noutput: public\nbackground: '#ffffff'\ngrayscale: true"),
+            0,
+        )
+        .unwrap();
+        assert_eq!(set.background, [255; 4]);
+        assert_eq!(set.other.jobs.background, None);
+        assert!(set.other.jobs.grayscale);
     }
 }
@@ -0,0 +1,537 @@
+use crate::{
+    canvas, colour, convolve, enhance, filters, metrics, output,
+    parse::{Convolve, Encoding, Mask, Resize, SharedSettings, Size},
+    script,
+};
+use image::{DynamicImage, FilterType, GenericImageView, ImageOutputFormat};
+use set_error::ChangeError;
+use std::convert::TryFrom;
+
+/// One step of the pipeline every image goes through between being decoded and encoded.
+pub type Job = Box<dyn Fn(DynamicImage) -> Result<DynamicImage, String>>;
+
+/// Builds the jobs set in `settings`, in the order they are applied.
+#[allow(clippy::too_many_lines)]
+pub fn jobs(settings: &SharedSettings) -> Vec<Job> {
+    let mut jobs: Vec<Job> = Vec::new();
+    let job_settings = settings.jobs.clone();
+    if let Some(x) = job_settings.trim {
+        jobs.push(Box::new(move |img| Ok(canvas::trim(img, &x))));
+    }
+    if let Some(x) = job_settings.resize {
+        let resize_filter = settings.resize_filter;
+        jobs.push(Box::new(move |img| {
+            Ok(resize_image(&img, &x, resize_filter))
+        }));
+    }
+    if let Some(x) = job_settings.pad {
+        jobs.push(Box::new(move |img| {
+            output::detail(&format!(
+                "With padding of {}px, {}px, {}px and {}px",
+                x.top, x.right, x.bottom, x.left
+            ));
+            canvas::pad(&img, &x)
+        }));
+    }
+    if let Some(x) = job_settings.blur {
+        jobs.push(Box::new(move |img| Ok(blur_image(&img, x))));
+    }
+    //Sharpen does not work for some reason. Output does not look sharp
+    if let Some(x) = job_settings.sharpen {
+        jobs.push(Box::new(move |img| {
+            output::detail(&format!("With sharpening level {x}"));
+            Ok(img.unsharpen(100.0, x))
+        }));
+    }
+    if let Some(x) = job_settings.adjust_contrast {
+        jobs.push(Box::new(move |img| {
+            output::detail(&format!("With contrast level {x}"));
+            Ok(img.adjust_contrast(x))
+        }));
+    }
+    if let Some(x) = job_settings.brighten {
+        jobs.push(Box::new(move |img| {
+            output::detail(&format!("With brightness level {x}"));
+            Ok(img.brighten(x))
+        }));
+    }
+    if let Some(x) = job_settings.huerotate {
+        jobs.push(Box::new(move |img| {
+            output::detail(&format!("With hue rotation of {x}"));
+            Ok(img.huerotate(x))
+        }));
+    }
+    if let Some(x) = job_settings.auto_levels {
+        jobs.push(Box::new(move |img| {
+            output::detail(&format!("With auto levels at strength {x}"));
+            Ok(enhance::auto_levels(img, x))
+        }));
+    }
+    if let Some(x) = job_settings.auto_contrast {
+        jobs.push(Box::new(move |img| {
+            output::detail(&format!("With auto contrast at strength {x}"));
+            Ok(enhance::auto_contrast(img, x))
+        }));
+    }
+    if let Some(x) = job_settings.equalize {
+        jobs.push(Box::new(move |img| {
+            output::detail(&format!(
+                "With {} equalization at strength {}",
+                if x.local { "local" } else { "global" },
+                x.strength
+            ));
+            Ok(enhance::equalize(img, &x))
+        }));
+    }
+    if let Some(x) = job_settings.exposure {
+        jobs.push(Box::new(move |img| {
+            output::detail(&format!("With exposure of {x} stops"));
+            Ok(colour::exposure(img, x))
+        }));
+    }
+    if let Some(x) = job_settings.levels {
+        jobs.push(Box::new(move |img| {
+            output::detail(&format!(
+                "With levels from {}-{} to {}-{}",
+                x.in_black, x.in_white, x.out_black, x.out_white
+            ));
+            Ok(colour::levels(img, &x))
+        }));
+    }
+    if let Some(x) = job_settings.gamma {
+        jobs.push(Box::new(move |img| {
+            output::detail(&format!("With gamma of {x}"));
+            Ok(colour::gamma(img, x))
+        }));
+    }
+    if let Some(x) = job_settings.curves {
+        jobs.push(Box::new(move |img| {
+            output::detail("With curves");
+            Ok(colour::curves(img, &x))
+        }));
+    }
+    if let Some(x) = job_settings.saturation {
+        jobs.push(Box::new(move |img| {
+            output::detail(&format!("With saturation of {x}"));
+            Ok(colour::saturation(img, x))
+        }));
+    }
+    if job_settings.flipv {
+        jobs.push(Box::new(|img| {
+            output::detail("And flipping vertically");
+            Ok(img.flipv())
+        }));
+    }
+    if job_settings.fliph {
+        jobs.push(Box::new(|img| {
+            output::detail("And flipping horizontally");
+            Ok(img.fliph())
+        }));
+    }
+    if job_settings.rotate90 {
+        jobs.push(Box::new(|img| {
+            output::detail("And rotating 90 degrees");
+            Ok(img.rotate90())
+        }));
+    }
+    if job_settings.rotate180 {
+        jobs.push(Box::new(|img| {
+            output::detail("And rotating 180 degrees");
+            Ok(img.rotate180())
+        }));
+    }
+    if job_settings.rotate270 {
+        jobs.push(Box::new(|img| {
+            output::detail("And rotating 270 degrees");
+            Ok(img.rotate270())
+        }));
+    }
+    if job_settings.grayscale {
+        jobs.push(Box::new(|img| {
+            output::detail("And changing image to grayscale");
+            Ok(img.grayscale())
+        }));
+    }
+    if job_settings.invert {
+        jobs.push(Box::new(|mut img| {
+            output::detail("And inverting image");
+            img.invert();
+            Ok(img)
+        }));
+    }
+    if let Some(x) = job_settings.sepia {
+        jobs.push(Box::new(move |img| {
+            output::detail(&format!("With sepia at strength {x}"));
+            Ok(filters::sepia(img, x))
+        }));
+    }
+    if let Some(x) = job_settings.tint {
+        jobs.push(Box::new(move |img| {
+            output::detail(&format!("With tint at strength {}", x.strength));
+            Ok(filters::tint(img, &x))
+        }));
+    }
+    if let Some(x) = job_settings.duotone {
+        jobs.push(Box::new(move |img| {
+            output::detail("With duotone");
+            Ok(filters::duotone(img, &x))
+        }));
+    }
+    if let Some(x) = job_settings.posterize {
+        jobs.push(Box::new(move |img| {
+            output::detail(&format!("With posterize to {x} levels"));
+            Ok(filters::posterize(img, x))
+        }));
+    }
+    if let Some(x) = job_settings.pixelate {
+        jobs.push(Box::new(move |img| {
+            output::detail(&format!("With pixelate in {x}px blocks"));
+            Ok(filters::pixelate(img, x))
+        }));
+    }
+    if let Some(x) = job_settings.vignette {
+        jobs.push(Box::new(move |img| {
+            output::detail(&format!("With vignette at strength {}", x.strength));
+            Ok(filters::vignette(img, &x))
+        }));
+    }
+    if let Some(x) = job_settings.convolve {
+        jobs.push(Box::new(move |img| {
+            output::detail(&match &x {
+                Convolve::Kernel(kernel) => {
+                    format!("With a {0}x{0} convolution", kernel.size)
+                }
+                Convolve::Sobel => String::from("With Sobel edge detection"),
+            });
+            Ok(convolve::apply(img, &x))
+        }));
+    }
+    if job_settings.border.is_some()
+        || job_settings.rounded_corners.is_some()
+        || job_settings.mask.is_some()
+    {
+        let (border, radius, mask) = (
+            job_settings.border,
+            job_settings.rounded_corners,
+            job_settings.mask,
+        );
+        jobs.push(Box::new(move |img| {
+            if let Some(x) = radius {
+                output::detail(&format!("With rounded corners of {x}px"));
+            }
+            if let Some(x) = &mask {
+                output::detail(&match x {
+                    Mask::Circle => String::from("With a circle mask"),
+                    Mask::Ellipse => String::from("With an ellipse mask"),
+                    Mask::Image(path) => format!("With the mask {path}"),
+                });
+            }
+            if let Some(x) = &border {
+                output::detail(&format!("With a {}px border", x.width));
+            }
+            canvas::shape(&img, border.as_ref(), radius, mask.as_ref())
+        }));
+    }
+    if let Some(x) = job_settings.background {
+        jobs.push(Box::new(move |img| {
+            output::detail("And flattening onto the background");
+            Ok(canvas::flatten(img, x))
+        }));
+    }
+    if let Some(path) = job_settings.script {
+        let script = script::Script::new(path);
+        jobs.push(Box::new(move |img| script.run(&img)));
+    }
+    jobs
+}
+
+pub fn apply(jobs: &[Job], img: DynamicImage) -> Result<DynamicImage, String> {
+    jobs.iter().try_fold(img, |img, job| job(img))
+}
+
+pub fn decode(bytes: &[u8]) -> Result<DynamicImage, String> {
+    image::load_from_memory(bytes).set_error("Failed to decode image.")
+}
+
+pub fn encode(img: &DynamicImage, format: ImageOutputFormat) -> Result<Vec<u8>, String> {
+    let flattened = canvas::flatten_for(img, &format);
+    let img = flattened.as_ref().unwrap_or(img);
+    let mut bytes = Vec::new();
+    img.write_to(&mut bytes, format)
+        .set_error("Failed to encode image.")?;
+    Ok(bytes)
+}
+
+/// An image encoded to fit a size limit or keep a minimum SSIM.
+pub struct Fitted {
+    pub bytes: Vec<u8>,
+    /// The JPEG quality used, if the format has one.
+    pub quality: Option<u8>,
+    pub width: u32,
+    pub height: u32,
+    /// Whether `bytes` is within the limit. If not, it is the smallest encoding tried.
+    pub fits: bool,
+}
+
+/// The highest quality tried when fitting a size limit.
+const MAX_QUALITY: u8 = 95;
+
+/// Checks the limits in `encoding` can be kept when encoding as `format`. Only JPEG has a quality
+/// to lower, so other formats need a palette or `shrink_to_fit` to keep `max_bytes`.
+pub fn check_limits(format: &ImageOutputFormat, encoding: &Encoding) -> Result<(), String> {
+    let has_quality = matches!(format, ImageOutputFormat::JPEG(_)) && encoding.palette.is_none();
+    if encoding.min_ssim.is_some() && !has_quality {
+        return Err(String::from(
+            "min_ssim only applies to JPEGs without a palette",
+        ));
+    }
+    if encoding.max_bytes.is_some()
+        && !has_quality
+        && encoding.palette.is_none()
+        && encoding.shrink_to_fit != Some(true)
+    {
+        return Err(String::from(
+            "max_bytes only lowers the quality of JPEGs, so other formats need a palette or shrink_to_fit",
+        ));
+    }
+    Ok(())
+}
+
+/// Encodes `img` as `format` within the limits in `encoding`. The JPEG quality is the highest
+/// that fits `max_bytes`, but never lower than keeps `min_ssim`, and without `max_bytes` it is
+/// the lowest that keeps `min_ssim`. If `shrink_to_fit` is set, the image is then scaled down by
+/// a tenth at a time until it fits.
+pub fn encode_to_fit(
+    img: &DynamicImage,
+    format: &ImageOutputFormat,
+    encoding: &Encoding,
+) -> Result<Fitted, String> {
+    let min_quality = encoding.min_quality.unwrap_or(40).min(MAX_QUALITY);
+    let mut current = img.clone();
+    loop {
+        let fitted = match format {
+            ImageOutputFormat::JPEG(_) => search_quality(&current, encoding, min_quality)?,
+            format => {
+                let bytes = encode(&current, format.clone())?;
+                Fitted {
+                    fits: encoding
+                        .max_bytes
+                        .is_none_or(|max_bytes| bytes.len() as u64 <= max_bytes),
+                    bytes,
+                    quality: None,
+                    width: current.width(),
+                    height: current.height(),
+                }
+            }
+        };
+        let (width, height) = (current.width() * 9 / 10, current.height() * 9 / 10);
+        if fitted.fits || encoding.shrink_to_fit != Some(true) || width < 16 || height < 16 {
+            return Ok(fitted);
+        }
+        output::detail(&format!(
+            "Still over {} bytes, scaling down to {}x{}",
+            encoding.max_bytes.unwrap_or_default(),
+            width,
+            height
+        ));
+        current = img.resize_exact(width, height, FilterType::Lanczos3);
+    }
+}
+
+/// Binary searches for the JPEG quality to use, assuming size and SSIM both grow with quality.
+fn search_quality(
+    img: &DynamicImage,
+    encoding: &Encoding,
+    min_quality: u8,
+) -> Result<Fitted, String> {
+    let fitted = |quality: u8| -> Result<Fitted, String> {
+        let bytes = encode(img, ImageOutputFormat::JPEG(quality))?;
+        output::debug(&format!("Quality {} is {} bytes", quality, bytes.len()));
+        Ok(Fitted {
+            fits: encoding
+                .max_bytes
+                .is_none_or(|max_bytes| bytes.len() as u64 <= max_bytes),
+            bytes,
+            quality: Some(quality),
+            width: img.width(),
+            height: img.height(),
+        })
+    };
+    let min_quality = match encoding.min_ssim {
+        Some(min_ssim) => {
+            let keeps_ssim = |quality: u8| -> Result<bool, String> {
+                let ssim = metrics::compare(img, &decode(&fitted(quality)?.bytes)?).ssim;
+                output::debug(&format!("Quality {quality} has SSIM {ssim:.4}"));
+                Ok(ssim >= f64::from(min_ssim))
+            };
+            let (mut low, mut high) = (min_quality, MAX_QUALITY);
+            while low < high {
+                let middle = low + (high - low) / 2;
+                if keeps_ssim(middle)? {
+                    high = middle;
+                } else {
+                    low = middle + 1;
+                }
+            }
+            output::detail(&format!(
+                "Not going below quality {low} to keep SSIM at least {min_ssim}"
+            ));
+            low
+        }
+        None => min_quality,
+    };
+    let mut best = fitted(min_quality)?;
+    if !best.fits || encoding.max_bytes.is_none() {
+        return Ok(best);
+    }
+    let (mut low, mut high) = (min_quality + 1, MAX_QUALITY);
+    while low <= high {
+        let middle = low + (high - low) / 2;
+        let attempt = fitted(middle)?;
+        if attempt.fits {
+            best = attempt;
+            low = middle + 1;
+        } else {
+            high = middle - 1;
+        }
+    }
+    Ok(best)
+}
+
+/// Encodes every image in `images` into one ICO file, each stored as a PNG. Images must be at
+/// most 256 pixels wide and high.
+pub fn encode_ico(images: &[DynamicImage]) -> Result<Vec<u8>, String> {
+    let count = u16::try_from(images.len()).set_error("Too many images for one icon.")?;
+    let mut pngs = Vec::new();
+    for img in images {
+        if img.width() > 256 || img.height() > 256 {
+            return Err(String::from(
+                "Icons can be at most 256 pixels wide and high.",
+            ));
+        }
+        pngs.push(encode(img, ImageOutputFormat::PNG)?);
+    }
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&0_u16.to_le_bytes());
+    bytes.extend_from_slice(&1_u16.to_le_bytes());
+    bytes.extend_from_slice(&count.to_le_bytes());
+    let mut offset = 6 + 16 * images.len();
+    for (img, png) in images.iter().zip(&pngs) {
+        // 256 is written as 0 as it does not fit in a byte.
+        bytes.push(img.width() as u8);
+        bytes.push(img.height() as u8);
+        bytes.extend_from_slice(&[0, 0]);
+        bytes.extend_from_slice(&1_u16.to_le_bytes());
+        bytes.extend_from_slice(&32_u16.to_le_bytes());
+        bytes.extend_from_slice(&(png.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += png.len();
+    }
+    for png in pngs {
+        bytes.extend(png);
+    }
+    Ok(bytes)
+}
+
+/// Finds the format to encode to from a file extension or format name.
+pub fn output_format(name: &str) -> Result<ImageOutputFormat, String> {
+    Ok(match name.to_ascii_lowercase().as_ref() {
+        "png" => ImageOutputFormat::PNG,
+        "jpg" | "jpeg" => ImageOutputFormat::JPEG(75),
+        "gif" => ImageOutputFormat::GIF,
+        "ico" => ImageOutputFormat::ICO,
+        "bmp" => ImageOutputFormat::BMP,
+        _ => return Err(format!("Can not encode images as {name}")),
+    })
+}
+
+fn resize_image(img: &DynamicImage, resize: &Resize, filter: Option<FilterType>) -> DynamicImage {
+    let filter_type = filter.unwrap_or(FilterType::Gaussian);
+    let size = &resize.size;
+    output::detail(&format!(
+        "With {}",
+        match size {
+            Size::WidthHeight(x, y) => format!(
+                "as close as possible to width {x}px and height {y}px while keeping aspect ratio"
+            ),
+            Size::Width(x) => format!("new width {x}px"),
+            Size::Height(x) => format!("new height {x}px"),
+        }
+    ));
+    let size = match size {
+        Size::WidthHeight(x, y) => (x, y),
+        Size::Width(x) => (x, &u32::MAX),
+        Size::Height(x) => (&u32::MAX, x),
+    };
+    if canvas::has_alpha(img) {
+        canvas::resize_premultiplied(img, *size.0, *size.1, filter_type)
+    } else {
+        img.resize(*size.0, *size.1, filter_type)
+    }
+}
+
+fn blur_image(img: &DynamicImage, blur_amount: f32) -> DynamicImage {
+    output::detail(&format!("With a blur of {blur_amount}"));
+    img.blur(blur_amount)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::Palette;
+    use image::RgbaImage;
+
+    #[test]
+    fn limits_need_a_quality_to_lower() {
+        let max_bytes = Encoding {
+            max_bytes: Some(1000),
+            ..Encoding::default()
+        };
+        let min_ssim = Encoding {
+            min_ssim: Some(0.98),
+            ..Encoding::default()
+        };
+        assert!(check_limits(&ImageOutputFormat::JPEG(75), &max_bytes).is_ok());
+        assert!(check_limits(&ImageOutputFormat::JPEG(75), &min_ssim).is_ok());
+        assert!(check_limits(&ImageOutputFormat::PNG, &Encoding::default()).is_ok());
+        assert!(check_limits(&ImageOutputFormat::PNG, &max_bytes).is_err());
+        assert!(check_limits(&ImageOutputFormat::PNG, &min_ssim).is_err());
+        for encoding in [
+            Encoding {
+                shrink_to_fit: Some(true),
+                ..max_bytes.clone()
+            },
+            Encoding {
+                palette: Some(Palette::default()),
+                ..max_bytes.clone()
+            },
+        ] {
+            assert!(check_limits(&ImageOutputFormat::PNG, &encoding).is_ok());
+        }
+    }
+
+    #[test]
+    fn ico_has_a_directory_entry_per_image() {
+        let images = [
+            DynamicImage::ImageRgba8(RgbaImage::new(16, 16)),
+            DynamicImage::ImageRgba8(RgbaImage::new(256, 256)),
+        ];
+        let ico = encode_ico(&images).unwrap();
+        assert_eq!(&ico[..6], &[0, 0, 1, 0, 2, 0]);
+        assert_eq!(&ico[6..8], &[16, 16]);
+        // 256 is stored as 0.
+        assert_eq!(&ico[22..24], &[0, 0]);
+        let offset = u32::from_le_bytes([ico[18], ico[19], ico[20], ico[21]]) as usize;
+        assert_eq!(offset, 6 + 16 * 2);
+        assert_eq!(&ico[offset + 1..offset + 4], b"PNG");
+        let length = u32::from_le_bytes([ico[30], ico[31], ico[32], ico[33]]) as usize;
+        let second = u32::from_le_bytes([ico[34], ico[35], ico[36], ico[37]]) as usize;
+        assert_eq!(second + length, ico.len());
+    }
+
+    #[test]
+    fn ico_images_are_limited_to_256() {
+        let images = [DynamicImage::ImageRgba8(RgbaImage::new(257, 16))];
+        assert!(encode_ico(&images).is_err());
+    }
+}
@@ -0,0 +1,306 @@
+use crate::{json, output, parse::Placeholders, pipeline};
+use image::{DynamicImage, FilterType, ImageOutputFormat, RgbaImage};
+use set_error::ChangeError;
+use std::{
+    collections::BTreeMap,
+    f64::consts::PI,
+    fs, io,
+    sync::{Mutex, PoisonError},
+};
+
+/// Held while a placeholders file is read, changed and written back.
+static WRITING: Mutex<()> = Mutex::new(());
+
+/// Width and height of the tiny preview image.
+const PREVIEW_SIZE: u32 = 20;
+
+const BASE83: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// Works out the placeholders turned on in `settings` for the image saved to `output_path`, and
+/// rewrites its entry in `file`, keeping the entries for every other image. Does nothing if none
+/// are turned on.
+pub fn record(
+    file: &str,
+    output_path: &str,
+    img: &DynamicImage,
+    settings: &Placeholders,
+) -> Result<(), String> {
+    if [
+        settings.blurhash,
+        settings.preview,
+        settings.dominant_colour,
+    ]
+    .iter()
+    .all(|x| *x != Some(true))
+    {
+        return Ok(());
+    }
+    let mut fields = Vec::new();
+    if settings.blurhash == Some(true) {
+        fields.push(("blurhash", json::string(&blurhash(img))));
+    }
+    if settings.preview == Some(true) {
+        fields.push(("preview", json::string(&preview(img)?)));
+    }
+    if settings.dominant_colour == Some(true) {
+        let (dominant, average) = colours(img);
+        fields.push(("dominant_colour", json::string(&dominant)));
+        fields.push(("average_colour", json::string(&average)));
+    }
+    let _writing = WRITING.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut entries = read(file)?;
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(key, value)| format!("    \"{key}\": {value}"))
+        .collect();
+    entries.insert(
+        output_path.to_string(),
+        format!("{{\n{}\n  }}", fields.join(",\n")),
+    );
+    let json: Vec<String> = entries
+        .iter()
+        .map(|(path, fields)| format!("  {}: {}", json::string(path), fields))
+        .collect();
+    fs::write(file, format!("{{\n{}\n}}\n", json.join(",\n")))
+        .set_error(&format!("Failed to write {file}"))?;
+    output::detail(&format!("Wrote placeholders to {file}"));
+    Ok(())
+}
+
+/// The entries already in `file`, or none if it does not exist yet or can not be read as JSON.
+fn read(file: &str) -> Result<BTreeMap<String, String>, String> {
+    let text = match fs::read_to_string(file) {
+        Ok(x) => x,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(_) => return Err(format!("Failed to read {file}")),
+    };
+    Ok(json::object_entries(&text).unwrap_or_else(|| {
+        output::warn(&format!("{file} is not a JSON object, so it is replaced."));
+        BTreeMap::new()
+    }))
+}
+
+/// Encodes a blurred version of `img` in a short string, as described at <https://blurha.sh>.
+fn blurhash(img: &DynamicImage) -> String {
+    let small = img.thumbnail(32, 32).to_rgba();
+    let (width, height) = small.dimensions();
+    let (x_components, y_components) = if width >= height { (4, 3) } else { (3, 4) };
+    let linear: Vec<[f64; 3]> = small
+        .pixels()
+        .map(|x| {
+            [
+                srgb_to_linear(x.data[0]),
+                srgb_to_linear(x.data[1]),
+                srgb_to_linear(x.data[2]),
+            ]
+        })
+        .collect();
+    let mut factors = Vec::new();
+    for j in 0..y_components {
+        for i in 0..x_components {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0.0; 3];
+            for y in 0..height {
+                for x in 0..width {
+                    let basis = (PI * f64::from(i) * f64::from(x) / f64::from(width)).cos()
+                        * (PI * f64::from(j) * f64::from(y) / f64::from(height)).cos();
+                    let pixel = linear[(y * width + x) as usize];
+                    for channel in 0..3 {
+                        factor[channel] += basis * pixel[channel];
+                    }
+                }
+            }
+            let scale = normalisation / f64::from(width * height);
+            factors.push(factor.map(|x| x * scale));
+        }
+    }
+    let mut hash = String::new();
+    base83(&mut hash, (x_components - 1) + (y_components - 1) * 9, 1);
+    let ac = &factors[1..];
+    let maximum = ac
+        .iter()
+        .flat_map(|x| x.iter())
+        .fold(0.0_f64, |maximum, x| maximum.max(x.abs()));
+    let quantised_maximum = to_int((maximum * 166.0 - 0.5).floor().clamp(0.0, 82.0));
+    base83(&mut hash, quantised_maximum, 1);
+    let maximum = f64::from(quantised_maximum + 1) / 166.0;
+    let [r, g, b] = factors[0].map(linear_to_srgb);
+    base83(&mut hash, (r << 16) + (g << 8) + b, 4);
+    for factor in ac {
+        let [r, g, b] = factor.map(|x| {
+            let x = x / maximum;
+            to_int(
+                (x.signum() * x.abs().sqrt() * 9.0 + 9.5)
+                    .floor()
+                    .clamp(0.0, 18.0),
+            )
+        });
+        base83(&mut hash, r * 19 * 19 + g * 19 + b, 2);
+    }
+    hash
+}
+
+fn base83(hash: &mut String, value: u32, length: u32) {
+    for index in 1..=length {
+        let digit = (value / 83_u32.pow(length - index)) % 83;
+        hash.push(char::from(BASE83[digit as usize]));
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f64 {
+    let value = f64::from(value) / 255.0;
+    if value <= 0.040_45 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f64) -> u32 {
+    let value = value.clamp(0.0, 1.0);
+    to_int(if value <= 0.003_130_8 {
+        value * 12.92 * 255.0 + 0.5
+    } else {
+        (1.055 * value.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5
+    })
+}
+
+/// Converts a value already clamped to be positive and in range.
+#[allow(clippy::cast_sign_loss)]
+fn to_int(value: f64) -> u32 {
+    value as u32
+}
+
+/// A tiny copy of `img` as a data URI, a JPEG unless it has transparency.
+fn preview(img: &DynamicImage) -> Result<String, String> {
+    let small = img.resize(PREVIEW_SIZE, PREVIEW_SIZE, FilterType::Triangle);
+    let opaque = small.to_rgba().pixels().all(|x| x.data[3] == 255);
+    let (format, mime) = if opaque {
+        (ImageOutputFormat::JPEG(60), "image/jpeg")
+    } else {
+        (ImageOutputFormat::PNG, "image/png")
+    };
+    Ok(format!(
+        "data:{};base64,{}",
+        mime,
+        base64(&pipeline::encode(&small, format)?)
+    ))
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0_u32, |group, (index, x)| {
+            group | u32::from(*x) << (16 - index * 8)
+        });
+        for index in 0..4 {
+            if index <= chunk.len() {
+                encoded.push(char::from(
+                    ALPHABET[(group >> (18 - index * 6) & 63) as usize],
+                ));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// The most common colour, found by grouping similar colours together, and the average colour of
+/// `img`, both ignoring transparent pixels.
+fn colours(img: &DynamicImage) -> (String, String) {
+    let small: RgbaImage = img.thumbnail(64, 64).to_rgba();
+    // Ordered so that when buckets tie, the same one wins every time.
+    let mut buckets: BTreeMap<[u8; 3], (u32, [u32; 3])> = BTreeMap::new();
+    let mut total = (0, [0_u32; 3]);
+    for pixel in small.pixels().filter(|x| x.data[3] >= 128) {
+        let [r, g, b, _] = pixel.data;
+        let bucket = buckets.entry([r >> 4, g >> 4, b >> 4]).or_default();
+        bucket.0 += 1;
+        total.0 += 1;
+        for (channel, value) in [r, g, b].iter().enumerate() {
+            bucket.1[channel] += u32::from(*value);
+            total.1[channel] += u32::from(*value);
+        }
+    }
+    let dominant = buckets
+        .values()
+        .max_by_key(|(count, _)| *count)
+        .copied()
+        .unwrap_or_default();
+    (hex(dominant), hex(total))
+}
+
+fn hex((count, sums): (u32, [u32; 3])) -> String {
+    let [r, g, b] = sums.map(|x| x / count.max(1));
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn base64_pads() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn blurhash_of_a_flat_image() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 6, Rgba([255, 0, 0, 255])));
+        let hash = blurhash(&img);
+        // 4 by 3 components, then the DC colour, then 11 AC components of 2 digits each.
+        assert_eq!(hash.len(), 1 + 1 + 4 + 11 * 2);
+        assert!(hash.starts_with('L'));
+        let mut dc = String::new();
+        base83(&mut dc, 255 << 16, 4);
+        assert_eq!(&hash[2..6], dc);
+    }
+
+    #[test]
+    fn dominant_colour_ties_are_stable() {
+        let rgba = RgbaImage::from_fn(128, 64, |x, _| {
+            if x < 64 {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 255, 255])
+            }
+        });
+        let img = DynamicImage::ImageRgba8(rgba);
+        let (dominant, _) = colours(&img);
+        assert!(dominant == "#ff0000" || dominant == "#0000ff");
+        for _ in 0..20 {
+            assert_eq!(colours(&img).0, dominant);
+        }
+    }
+
+    #[test]
+    fn record_keeps_other_entries() {
+        let file = std::env::temp_dir().join("image-watcher-placeholders.json");
+        let file = file.to_str().unwrap();
+        fs::write(
+            file,
+            "{\n  \"other.png\": {\n    \"blurhash\": \"abc\"\n  }\n}\n",
+        )
+        .unwrap();
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, Rgba([0, 255, 0, 255])));
+        let settings = Placeholders {
+            dominant_colour: Some(true),
+            ..Placeholders::default()
+        };
+        record(file, "new.png", &img, &settings).unwrap();
+        let entries = json::object_entries(&fs::read_to_string(file).unwrap()).unwrap();
+        assert_eq!(entries["other.png"], "{\n    \"blurhash\": \"abc\"\n  }");
+        assert!(entries["new.png"].contains("\"dominant_colour\": \"#00ff00\""));
+        let written = fs::read_to_string(file).unwrap();
+        record(file, "new.png", &img, &Placeholders::default()).unwrap();
+        assert_eq!(fs::read_to_string(file).unwrap(), written);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::output;
+use image::{DynamicImage, GenericImageView, RgbaImage};
+use rhai::{module_resolvers::DummyModuleResolver, Array, Blob, Engine, Scope, AST, INT};
+use set_error::ChangeError;
+use std::{cell::RefCell, convert::TryFrom, fs, rc::Rc, time::SystemTime};
+
+/// How many operations a script can run for each pixel before it is stopped, so a script that
+/// never ends can not hang the watcher or the server.
+const OPERATIONS_PER_PIXEL: u64 = 10_000;
+const MAX_CALL_LEVELS: usize = 64;
+const MAX_STRING_SIZE: usize = 1 << 20;
+const MAX_ARRAY_SIZE: usize = 1 << 16;
+
+/// A Rhai script that is compiled when first run, and again only when its file changes.
+pub struct Script {
+    path: String,
+    compiled: RefCell<Option<(SystemTime, Rc<AST>)>>,
+}
+
+impl Script {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            compiled: RefCell::new(None),
+        }
+    }
+    /// Runs the script on `img`. The script either defines
+    /// `fn pixel(r, g, b, a, x, y, w, h)`, called for every pixel and returning `[r, g, b]` or
+    /// `[r, g, b, a]`, or `fn image(pixels, w, h)`, given every pixel as a blob of RGBA bytes and
+    /// returning a blob of the same length.
+    ///
+    /// Scripts can not import modules or touch the filesystem, and anything they print is only
+    /// shown with `-v`.
+    pub fn run(&self, img: &DynamicImage) -> Result<DynamicImage, String> {
+        output::detail(&format!("With script {}", self.path));
+        let ast = self.ast()?;
+        let defines = |name: &str| ast.iter_functions().any(|f| f.name == name);
+        let (width, height) = img.dimensions();
+        let rgba = img.to_rgba();
+        let result = if defines("pixel") {
+            per_pixel(&engine(OPERATIONS_PER_PIXEL, MAX_ARRAY_SIZE), &ast, rgba)
+        } else if defines("image") {
+            let pixels = u64::from(width) * u64::from(height);
+            let bytes = usize::try_from(pixels * 4).unwrap_or(usize::MAX);
+            let engine = engine(
+                OPERATIONS_PER_PIXEL.saturating_mul(pixels),
+                bytes.max(MAX_ARRAY_SIZE),
+            );
+            whole_image(&engine, &ast, rgba)
+        } else {
+            Err(String::from("defines neither fn pixel nor fn image"))
+        };
+        result.map(DynamicImage::ImageRgba8).map_err(|e| {
+            format!(
+                "Script {} failed on a {}x{} image: {}",
+                self.path, width, height, e
+            )
+        })
+    }
+    /// The compiled script, compiling it again if the file changed since it was last compiled.
+    fn ast(&self) -> Result<Rc<AST>, String> {
+        let modified = fs::metadata(&self.path)
+            .and_then(|x| x.modified())
+            .set_error(&format!("Failed to read script {}", self.path))?;
+        if let Some((compiled_at, ast)) = &*self.compiled.borrow() {
+            if *compiled_at == modified {
+                return Ok(Rc::clone(ast));
+            }
+        }
+        let source = fs::read_to_string(&self.path)
+            .set_error(&format!("Failed to read script {}", self.path))?;
+        let ast = Rc::new(
+            engine(0, MAX_ARRAY_SIZE)
+                .compile(&source)
+                .map_err(|e| format!("Failed to compile script {}: {}", self.path, e))?,
+        );
+        output::debug(&format!("Compiled script {}", self.path));
+        self.compiled.replace(Some((modified, Rc::clone(&ast))));
+        Ok(ast)
+    }
+}
+
+/// An engine that stops scripts after `operations`, or never if 0, and limits how deep they
+/// recurse and how large their strings and arrays grow.
+fn engine(operations: u64, array_size: usize) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_module_resolver(DummyModuleResolver::new())
+        .set_max_operations(operations)
+        .set_max_call_levels(MAX_CALL_LEVELS)
+        .set_max_string_size(MAX_STRING_SIZE)
+        .set_max_array_size(array_size)
+        .set_max_map_size(MAX_ARRAY_SIZE);
+    engine.on_print(output::detail);
+    engine.on_debug(|s, _, _| output::detail(s));
+    engine
+}
+
+fn per_pixel(engine: &Engine, ast: &AST, mut rgba: RgbaImage) -> Result<RgbaImage, String> {
+    let (width, height) = rgba.dimensions();
+    let mut scope = Scope::new();
+    for (x, y, pixel) in rgba.enumerate_pixels_mut() {
+        let [red, green, blue, alpha] = pixel.data;
+        let result: Array = engine
+            .call_fn(
+                &mut scope,
+                ast,
+                "pixel",
+                [
+                    INT::from(red),
+                    INT::from(green),
+                    INT::from(blue),
+                    INT::from(alpha),
+                    INT::from(x),
+                    INT::from(y),
+                    INT::from(width),
+                    INT::from(height),
+                ],
+            )
+            .map_err(|e| format!("pixel({x}, {y}): {e}"))?;
+        if result.len() != 3 && result.len() != 4 {
+            return Err(format!(
+                "pixel({x}, {y}) must return [r, g, b] or [r, g, b, a]"
+            ));
+        }
+        let channel = |index: usize| -> Result<u8, String> {
+            let value = match result.get(index) {
+                Some(value) => value.as_int().map_err(|_| {
+                    format!("pixel({x}, {y}) returned a channel that is not a number")
+                })?,
+                None => INT::from(alpha),
+            };
+            Ok(u8::try_from(value.clamp(0, 255)).unwrap_or_default())
+        };
+        pixel.data = [channel(0)?, channel(1)?, channel(2)?, channel(3)?];
+    }
+    Ok(rgba)
+}
+
+fn whole_image(engine: &Engine, ast: &AST, rgba: RgbaImage) -> Result<RgbaImage, String> {
+    let (width, height) = rgba.dimensions();
+    let pixels: Blob = rgba.into_raw();
+    let expected = pixels.len();
+    let result: Blob = engine
+        .call_fn(
+            &mut Scope::new(),
+            ast,
+            "image",
+            (pixels, INT::from(width), INT::from(height)),
+        )
+        .map_err(|e| format!("image(): {e}"))?;
+    if result.len() != expected {
+        return Err(format!(
+            "image() returned {} bytes instead of {}",
+            result.len(),
+            expected
+        ));
+    }
+    RgbaImage::from_raw(width, height, result).set_error("image() returned an invalid image")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(name: &str, source: &str) -> Script {
+        let path = std::env::temp_dir().join(format!("image-watcher-script-{name}.rhai"));
+        fs::write(&path, source).unwrap();
+        Script::new(path.to_string_lossy().into_owned())
+    }
+
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, image::Rgba([10, 20, 30, 255])))
+    }
+
+    #[test]
+    fn runs_pixel_scripts() {
+        let script = script("pixel", "fn pixel(r, g, b, a, x, y, w, h) { [b, g, r] }");
+        let result = script.run(&image()).unwrap();
+        assert_eq!(result.get_pixel(1, 1).data, [30, 20, 10, 255]);
+    }
+
+    #[test]
+    fn compiles_once_until_changed() {
+        let script = script("cache", "fn image(pixels, w, h) { pixels }");
+        let first = script.ast().unwrap();
+        assert!(Rc::ptr_eq(&first, &script.ast().unwrap()));
+        assert!(script.run(&image()).is_ok());
+    }
+
+    #[test]
+    fn endless_scripts_are_stopped() {
+        let script = script("loop", "fn pixel(r, g, b, a, x, y, w, h) { loop {} }");
+        assert!(script.run(&image()).is_err());
+    }
+
+    #[test]
+    fn deep_recursion_is_stopped() {
+        let script = script(
+            "recurse",
+            "fn deeper(n) { deeper(n + 1) } fn image(pixels, w, h) { deeper(0) }",
+        );
+        assert!(script.run(&image()).is_err());
+    }
+
+    #[test]
+    fn strings_are_limited() {
+        let script = script(
+            "string",
+            "fn image(pixels, w, h) { let s = \"x\"; loop { s += s; } }",
+        );
+        assert!(script.run(&image()).is_err());
+    }
+}
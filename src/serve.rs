@@ -0,0 +1,722 @@
+use crate::{
+    output,
+    parse::{get_shared_settings, ImgEditJobs, Mask, Size, JOB_KEYS},
+    pipeline,
+};
+use image::{GenericImageView, ImageOutputFormat};
+use set_error::ChangeError;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs::{self, File},
+    hash::{Hash as _, Hasher},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tiny_http::{Header, Method, Request, Response, Server};
+use yaml_rust::{yaml::Hash, Yaml, YamlLoader};
+
+pub struct ServeOptions {
+    pub root: PathBuf,
+    pub port: u16,
+    /// The largest width or height that can be requested.
+    pub max_size: u32,
+    /// If not empty, the only widths and heights that can be requested.
+    pub allowed_sizes: Vec<u32>,
+    pub cache_bytes: usize,
+    pub cache_dir: Option<PathBuf>,
+    pub cache_dir_bytes: u64,
+}
+
+/// Transformed images kept in memory, dropping the least recently used once over `capacity`
+/// bytes. Images are also written to `dir` if there is one so they survive a restart, dropping the
+/// least recently used files once over `dir_capacity` bytes.
+struct Cache {
+    entries: HashMap<String, CacheEntry>,
+    used: usize,
+    capacity: usize,
+    tick: u64,
+    dir: Option<PathBuf>,
+    dir_capacity: u64,
+}
+
+struct CacheEntry {
+    /// When the source and mask images were last modified, so entries for older versions are not
+    /// used.
+    version: u128,
+    bytes: Arc<Vec<u8>>,
+    last_used: u64,
+}
+
+/// A response that could not be made, with the status code to send instead.
+struct HttpError(u16, String);
+
+const WORKERS: usize = 4;
+
+/// The largest `blur` sigma that can be requested, as the time taken grows with it.
+const MAX_BLUR: f32 = 50.0;
+
+/// The largest `sharpen` threshold that can be requested. Differences between channels are never
+/// more than 255, so anything larger does nothing more.
+const MAX_SHARPEN: i32 = 255;
+
+/// The most `equalize` tiles across and down that can be requested, as each keeps its own
+/// histogram.
+const MAX_TILES: u32 = 16;
+
+pub fn serve(options: ServeOptions) -> Result<(), String> {
+    let root = options.root.canonicalize().set_error(&format!(
+        "Failed to open root folder {}",
+        options.root.display()
+    ))?;
+    if let Some(dir) = &options.cache_dir {
+        fs::create_dir_all(dir)
+            .set_error(&format!("Failed to create cache folder {}", dir.display()))?;
+    }
+    let server = Arc::new(
+        Server::http(("127.0.0.1", options.port))
+            .map_err(|e| format!("Failed to listen on port {}: {}", options.port, e))?,
+    );
+    output::info(&format!(
+        "Serving images from {} at http://localhost:{}/",
+        root.display(),
+        options.port
+    ));
+    let cache = Cache {
+        entries: HashMap::new(),
+        used: 0,
+        capacity: options.cache_bytes,
+        tick: 0,
+        dir: options.cache_dir.clone(),
+        dir_capacity: options.cache_dir_bytes,
+    };
+    cache.evict_files();
+    let cache = Arc::new(Mutex::new(cache));
+    let options = Arc::new(ServeOptions { root, ..options });
+    let workers: Vec<_> = (0..WORKERS)
+        .map(|_| {
+            let server = Arc::clone(&server);
+            let cache = Arc::clone(&cache);
+            let options = Arc::clone(&options);
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    handle(request, &options, &cache);
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().ok();
+    }
+    Ok(())
+}
+
+fn handle(request: Request, options: &ServeOptions, cache: &Mutex<Cache>) {
+    let url = request.url().to_string();
+    let response = match request.method() {
+        // A panic while transforming would otherwise take the worker down with it.
+        Method::Get | Method::Head => {
+            panic::catch_unwind(AssertUnwindSafe(|| respond(&url, options, cache))).unwrap_or_else(
+                |_| {
+                    Err(HttpError(
+                        500,
+                        String::from("Failed to transform the image."),
+                    ))
+                },
+            )
+        }
+        _ => Err(HttpError(
+            405,
+            String::from("Only GET requests are allowed."),
+        )),
+    };
+    let result = match response {
+        Ok((bytes, content_type)) => {
+            output::info(&format!("200 {url}"));
+            request.respond(
+                Response::from_data(bytes.as_ref().clone()).with_header(
+                    Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes())
+                        .expect("content type is a valid header"),
+                ),
+            )
+        }
+        Err(HttpError(status, message)) => {
+            output::info(&format!("{status} {url} {message}"));
+            request.respond(Response::from_string(message).with_status_code(status))
+        }
+    };
+    if let Err(e) = result {
+        output::error(&format!("Failed to respond to {url}: {e}"));
+    }
+}
+
+#[allow(clippy::too_many_lines)]
+fn respond(
+    url: &str,
+    options: &ServeOptions,
+    cache: &Mutex<Cache>,
+) -> Result<(Arc<Vec<u8>>, &'static str), HttpError> {
+    let (path, query) = match url.find('?') {
+        Some(index) => (&url[..index], &url[index + 1..]),
+        None => (url, ""),
+    };
+    let source = source_path(&options.root, &percent_decode(path))?;
+    let params = query_params(query)?;
+    let mut yaml = Hash::new();
+    let mut format_name = None;
+    let mut quality = None;
+    for (key, value) in &params {
+        match key.as_ref() {
+            "fmt" => format_name = Some(value.clone()),
+            "q" => {
+                quality = Some(
+                    value
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|q| (1..=100).contains(q))
+                        .ok_or_else(|| bad_request("q must be between 1 and 100."))?,
+                );
+            }
+            _ => {
+                let key = match key.as_ref() {
+                    "w" => "width",
+                    "h" => "height",
+                    key if JOB_KEYS.contains(&key) => key,
+                    _ => return Err(bad_request(&format!("Unknown parameter {key}"))),
+                };
+                yaml.insert(Yaml::String(key.to_string()), yaml_value(value));
+            }
+        }
+    }
+    let mut settings = get_shared_settings(&yaml).map_err(|e| bad_request(&e))?;
+    if let Some(Mask::Image(mask)) = &mut settings.jobs.mask {
+        let path = source_path(&options.root, mask).map_err(|_| {
+            bad_request(&format!("Mask {mask} is not an image in the root folder."))
+        })?;
+        *mask = path.to_string_lossy().into_owned();
+    }
+    check_limits(&settings.jobs, options.max_size)?;
+    if let Some(resize) = &settings.jobs.resize {
+        let sizes = match resize.size {
+            Size::Width(x) | Size::Height(x) => vec![x],
+            Size::WidthHeight(x, y) => vec![x, y],
+        };
+        for size in sizes {
+            if size == 0 || size > options.max_size {
+                return Err(bad_request(&format!(
+                    "Sizes must be between 1 and {}.",
+                    options.max_size
+                )));
+            }
+            if !options.allowed_sizes.is_empty() && !options.allowed_sizes.contains(&size) {
+                return Err(bad_request(&format!("Size {size} is not allowed.")));
+            }
+        }
+    }
+    let format_name = match format_name {
+        Some(x) => x,
+        None => source
+            .extension()
+            .and_then(|x| x.to_str())
+            .unwrap_or_default()
+            .to_string(),
+    };
+    let format = match (
+        pipeline::output_format(&format_name).map_err(|e| bad_request(&e))?,
+        quality,
+    ) {
+        (ImageOutputFormat::JPEG(_), Some(quality)) => ImageOutputFormat::JPEG(quality),
+        (_, Some(_)) => return Err(bad_request("q can only be used with JPEG.")),
+        (format, None) => format,
+    };
+    let content_type = content_type(&format);
+
+    // Editing the mask changes the response as much as editing the image.
+    let mut version = modified(&source)?;
+    if let Some(Mask::Image(mask)) = &settings.jobs.mask {
+        version += modified(Path::new(mask))?;
+    }
+    let key = format!(
+        "{}|{}|{:?}",
+        source.display(),
+        params
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>()
+            .join("&"),
+        format
+    );
+    if let Some(bytes) = lock(cache).get(&key, version) {
+        output::debug(&format!("Cache hit for {url}"));
+        return Ok((bytes, content_type));
+    }
+    let bytes = fs::read(&source).map_err(|_| HttpError(404, String::from("Not found.")))?;
+    let img = pipeline::decode(&bytes)
+        .and_then(|img| pipeline::apply(&pipeline::jobs(&settings), img))
+        .map_err(|e| HttpError(500, e))?;
+    let (width, height) = img.dimensions();
+    if width > options.max_size || height > options.max_size {
+        return Err(bad_request(&format!(
+            "The transformed image is {}x{}, but sizes must be at most {}.",
+            width, height, options.max_size
+        )));
+    }
+    let bytes = Arc::new(pipeline::encode(&img, format).map_err(|e| HttpError(500, e))?);
+    lock(cache).insert(key, version, Arc::clone(&bytes));
+    Ok((bytes, content_type))
+}
+
+/// Refuses jobs whose cost grows with their value past what a development server should do.
+fn check_limits(jobs: &ImgEditJobs, max_size: u32) -> Result<(), HttpError> {
+    let too_large = |name: &str, max: &dyn std::fmt::Display| {
+        Err(bad_request(&format!("{name} must be at most {max}.")))
+    };
+    if let Some(pad) = &jobs.pad {
+        if [pad.top, pad.right, pad.bottom, pad.left]
+            .iter()
+            .any(|x| *x > max_size)
+        {
+            return too_large("Padding", &max_size);
+        }
+    }
+    if jobs.blur.is_some_and(|x| x > MAX_BLUR) {
+        return too_large("blur", &MAX_BLUR);
+    }
+    if jobs.sharpen.is_some_and(|x| x.abs() > MAX_SHARPEN) {
+        return too_large("sharpen", &MAX_SHARPEN);
+    }
+    if jobs
+        .equalize
+        .as_ref()
+        .is_some_and(|x| x.local && x.tiles > MAX_TILES)
+    {
+        return too_large("equalize tiles", &MAX_TILES);
+    }
+    for (name, value) in [
+        ("pixelate", jobs.pixelate),
+        ("border", jobs.border.as_ref().map(|x| x.width)),
+        ("rounded_corners", jobs.rounded_corners),
+    ] {
+        if value.is_some_and(|x| x > max_size) {
+            return too_large(name, &max_size);
+        }
+    }
+    Ok(())
+}
+
+/// When the file at `path` was last modified, in nanoseconds.
+fn modified(path: &Path) -> Result<u128, HttpError> {
+    let modified = fs::metadata(path)
+        .and_then(|x| x.modified())
+        .map_err(|_| HttpError(404, String::from("Not found.")))?;
+    Ok(modified
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_nanos())
+        .unwrap_or_default())
+}
+
+/// Finds the file a request is for, refusing anything outside of `root`.
+fn source_path(root: &Path, path: &str) -> Result<PathBuf, HttpError> {
+    let not_found = || HttpError(404, String::from("Not found."));
+    let source = root
+        .join(path.trim_start_matches('/'))
+        .canonicalize()
+        .map_err(|_| not_found())?;
+    if source.starts_with(root) && source.is_file() {
+        Ok(source)
+    } else {
+        Err(not_found())
+    }
+}
+
+/// Splits a query string into its keys and values, sorted so the same parameters in a different
+/// order share a cache entry.
+fn query_params(query: &str) -> Result<Vec<(String, String)>, HttpError> {
+    let mut params: Vec<(String, String)> = query
+        .split('&')
+        .filter(|x| !x.is_empty())
+        .map(|pair| match pair.find('=') {
+            Some(index) => (
+                percent_decode(&pair[..index]),
+                percent_decode(&pair[index + 1..]),
+            ),
+            None => (percent_decode(pair), String::new()),
+        })
+        .collect();
+    params.sort();
+    if params.windows(2).any(|x| x[0].0 == x[1].0) {
+        return Err(bad_request("Parameters can only be given once."));
+    }
+    Ok(params)
+}
+
+/// Reads a query value the same way as a value in the config file, with a key on its own meaning
+/// `true`.
+fn yaml_value(value: &str) -> Yaml {
+    if value.is_empty() {
+        return Yaml::Boolean(true);
+    }
+    YamlLoader::load_from_str(value)
+        .ok()
+        .and_then(|docs| docs.into_iter().next())
+        .filter(|x| {
+            matches!(
+                x,
+                Yaml::Integer(_) | Yaml::Real(_) | Yaml::Boolean(_) | Yaml::String(_)
+            )
+        })
+        .unwrap_or_else(|| Yaml::String(value.to_string()))
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let hex = |offset: usize| {
+            bytes
+                .get(index + offset)
+                .and_then(|x| char::from(*x).to_digit(16))
+        };
+        match (bytes[index], hex(1), hex(2)) {
+            (b'%', Some(high), Some(low)) => {
+                decoded.push((high * 16 + low) as u8);
+                index += 2;
+            }
+            (b'+', _, _) => decoded.push(b' '),
+            (byte, _, _) => decoded.push(byte),
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn content_type(format: &ImageOutputFormat) -> &'static str {
+    match format {
+        ImageOutputFormat::PNG => "image/png",
+        ImageOutputFormat::JPEG(_) => "image/jpeg",
+        ImageOutputFormat::GIF => "image/gif",
+        ImageOutputFormat::ICO => "image/x-icon",
+        ImageOutputFormat::BMP => "image/bmp",
+        _ => "application/octet-stream",
+    }
+}
+
+fn bad_request(message: &str) -> HttpError {
+    HttpError(400, message.to_string())
+}
+
+fn lock(cache: &Mutex<Cache>) -> std::sync::MutexGuard<'_, Cache> {
+    cache
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+impl Cache {
+    fn get(&mut self, key: &str, version: u128) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) if entry.version == version => {
+                entry.last_used = self.tick;
+                return Some(Arc::clone(&entry.bytes));
+            }
+            Some(_) => self.remove_in_memory(key),
+            None => {}
+        }
+        let path = self.file_path(key, version)?;
+        let bytes = Arc::new(fs::read(&path).ok()?);
+        // Files are evicted by when they were last modified, so reading one counts as a use.
+        if let Ok(file) = File::options().write(true).open(&path) {
+            file.set_modified(SystemTime::now()).ok();
+        }
+        self.insert_in_memory(key.to_string(), version, Arc::clone(&bytes));
+        Some(bytes)
+    }
+    fn insert(&mut self, key: String, version: u128, bytes: Arc<Vec<u8>>) {
+        if let Some(path) = self.file_path(&key, version) {
+            for (stale, _, _) in self.files() {
+                if stale != path && same_key(&stale, &path) {
+                    fs::remove_file(&stale).ok();
+                }
+            }
+            match fs::write(&path, bytes.as_ref()) {
+                Ok(()) => self.evict_files(),
+                Err(e) => output::error(&format!(
+                    "Failed to write cache file {}: {}",
+                    path.display(),
+                    e
+                )),
+            }
+        }
+        self.insert_in_memory(key, version, bytes);
+    }
+    fn insert_in_memory(&mut self, key: String, version: u128, bytes: Arc<Vec<u8>>) {
+        if bytes.len() > self.capacity {
+            self.remove_in_memory(&key);
+            return;
+        }
+        self.tick += 1;
+        self.used += bytes.len();
+        if let Some(old) = self.entries.insert(
+            key,
+            CacheEntry {
+                version,
+                bytes,
+                last_used: self.tick,
+            },
+        ) {
+            self.used -= old.bytes.len();
+        }
+        while self.used > self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(key) => self.remove_in_memory(&key),
+                None => break,
+            }
+        }
+    }
+    fn remove_in_memory(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.used -= entry.bytes.len();
+        }
+    }
+    /// Deletes the least recently used files until the folder is within `dir_capacity`.
+    fn evict_files(&self) {
+        let mut files = self.files();
+        let mut used: u64 = files.iter().map(|(_, _, len)| len).sum();
+        files.sort_by_key(|(_, modified, _)| *modified);
+        for (path, _, len) in files {
+            if used <= self.dir_capacity {
+                break;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => used -= len,
+                Err(e) => output::error(&format!(
+                    "Failed to remove cache file {}: {}",
+                    path.display(),
+                    e
+                )),
+            }
+        }
+    }
+    /// The cache files in `dir` with when they were last used and their size.
+    fn files(&self) -> Vec<(PathBuf, SystemTime, u64)> {
+        let Some(Ok(entries)) = self.dir.as_ref().map(fs::read_dir) else {
+            return Vec::new();
+        };
+        entries
+            .filter_map(Result::ok)
+            .filter(|x| is_cache_file(&x.path()))
+            .filter_map(|x| {
+                let metadata = x.metadata().ok()?;
+                Some((x.path(), metadata.modified().ok()?, metadata.len()))
+            })
+            .collect()
+    }
+    /// Names files by a hash of `key` then `version`, so older versions can be found and removed.
+    fn file_path(&self, key: &str, version: u128) -> Option<PathBuf> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        Some(
+            self.dir
+                .as_ref()?
+                .join(format!("{:016x}-{}", hasher.finish(), version)),
+        )
+    }
+}
+
+fn is_cache_file(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .and_then(|x| x.to_str())
+        .unwrap_or_default();
+    match name.split_once('-') {
+        Some((hash, version)) => {
+            hash.len() == 16
+                && hash.chars().all(|x| x.is_ascii_hexdigit())
+                && !version.is_empty()
+                && version.chars().all(|x| x.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+/// Whether two cache files are for the same key, though maybe different versions.
+fn same_key(a: &Path, b: &Path) -> bool {
+    let hash = |x: &Path| {
+        x.file_name()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.split_once('-'))
+            .map(|(hash, _)| hash.to_string())
+    };
+    hash(a).is_some() && hash(a) == hash(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(name: &str) -> ServeOptions {
+        let root = std::env::temp_dir().join(format!("image-watcher-serve-{name}"));
+        fs::create_dir_all(&root).unwrap();
+        image::RgbImage::new(4, 4)
+            .save(root.join("hero.png"))
+            .unwrap();
+        ServeOptions {
+            root: root.canonicalize().unwrap(),
+            port: 0,
+            max_size: 64,
+            allowed_sizes: Vec::new(),
+            cache_bytes: 0,
+            cache_dir: None,
+            cache_dir_bytes: 0,
+        }
+    }
+
+    fn status(url: &str, options: &ServeOptions) -> u16 {
+        let cache = Mutex::new(Cache {
+            entries: HashMap::new(),
+            used: 0,
+            capacity: 0,
+            tick: 0,
+            dir: None,
+            dir_capacity: 0,
+        });
+        match respond(url, options, &cache) {
+            Ok(_) => 200,
+            Err(HttpError(status, _)) => status,
+        }
+    }
+
+    #[test]
+    fn mask_images_must_be_in_root() {
+        let options = options("mask");
+        let outside = std::env::temp_dir().join("image-watcher-serve-mask-outside.png");
+        image::RgbImage::new(4, 4).save(&outside).unwrap();
+        let url = format!("/hero.png?mask={}", outside.display());
+        assert_eq!(status(&url, &options), 400);
+        assert_eq!(status("/hero.png?mask=../hero.png", &options), 400);
+        assert_eq!(status("/hero.png?mask=hero.png", &options), 200);
+        assert_eq!(status("/hero.png?mask=circle", &options), 200);
+    }
+
+    #[test]
+    fn padding_is_limited() {
+        let options = options("pad");
+        assert_eq!(status("/hero.png?pad=4294967295", &options), 400);
+        assert_eq!(status("/hero.png?pad=65", &options), 400);
+        assert_eq!(status("/hero.png?pad=8", &options), 200);
+    }
+
+    #[test]
+    fn costly_jobs_are_limited() {
+        let options = options("limits");
+        assert_eq!(status("/hero.png?blur=1e9", &options), 400);
+        assert_eq!(status("/hero.png?blur=2", &options), 200);
+        assert_eq!(status("/hero.png?sharpen=100000", &options), 400);
+        assert_eq!(status("/hero.png?sharpen=-300", &options), 400);
+        assert_eq!(status("/hero.png?pixelate=65", &options), 400);
+        assert_eq!(status("/hero.png?border=65", &options), 400);
+        assert_eq!(
+            status("/hero.png?rounded_corners=4294967295", &options),
+            400
+        );
+        assert_eq!(status("/hero.png?rounded_corners=2", &options), 200);
+    }
+
+    #[test]
+    fn webp_is_refused() {
+        let options = options("webp");
+        assert_eq!(status("/hero.png?fmt=webp", &options), 400);
+        assert_eq!(status("/hero.png?fmt=png", &options), 200);
+    }
+
+    #[test]
+    fn editing_the_mask_changes_the_cached_version() {
+        let options = options("mask-cache");
+        let mask = options.root.join("mask.png");
+        image::GrayImage::from_pixel(4, 4, image::Luma([255]))
+            .save(&mask)
+            .unwrap();
+        let cache = Mutex::new(Cache {
+            entries: HashMap::new(),
+            used: 0,
+            capacity: 1 << 20,
+            tick: 0,
+            dir: None,
+            dir_capacity: 0,
+        });
+        let url = "/hero.png?mask=mask.png";
+        assert!(respond(url, &options, &cache).is_ok());
+        let version = |cache: &Mutex<Cache>| lock(cache).entries.values().next().unwrap().version;
+        let before = version(&cache);
+        File::options()
+            .write(true)
+            .open(&mask)
+            .unwrap()
+            .set_modified(SystemTime::now() + std::time::Duration::from_secs(10))
+            .unwrap();
+        assert!(respond(url, &options, &cache).is_ok());
+        assert_eq!(lock(&cache).entries.len(), 1);
+        assert_ne!(version(&cache), before);
+    }
+
+    #[test]
+    fn percent_decode_handles_escapes() {
+        assert_eq!(percent_decode("a%20b+c"), "a b c");
+        assert_eq!(percent_decode("%2Fx%2f"), "/x/");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+
+    #[test]
+    fn query_params_are_sorted_and_unique() {
+        assert_eq!(
+            query_params("w=10&fmt=png&flip").ok().unwrap(),
+            vec![
+                (String::from("flip"), String::new()),
+                (String::from("fmt"), String::from("png")),
+                (String::from("w"), String::from("10")),
+            ]
+        );
+        assert!(query_params("w=1&w=2").is_err());
+    }
+
+    #[test]
+    fn output_size_is_limited() {
+        let options = options("output");
+        assert_eq!(status("/hero.png?pad=30", &options), 200);
+        assert_eq!(status("/hero.png?pad=31", &options), 400);
+    }
+
+    #[test]
+    fn cache_folder_is_limited_and_drops_old_versions() {
+        let dir = std::env::temp_dir().join("image-watcher-serve-cache");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let mut cache = Cache {
+            entries: HashMap::new(),
+            used: 0,
+            capacity: 0,
+            tick: 0,
+            dir: Some(dir.clone()),
+            dir_capacity: 25,
+        };
+        cache.insert(String::from("a"), 1, Arc::new(vec![0; 10]));
+        cache.insert(String::from("a"), 2, Arc::new(vec![0; 10]));
+        assert_eq!(cache.files().len(), 1);
+        assert!(cache.get("a", 1).is_none());
+        assert!(cache.get("a", 2).is_some());
+        cache.insert(String::from("b"), 1, Arc::new(vec![0; 10]));
+        cache.insert(String::from("c"), 1, Arc::new(vec![0; 10]));
+        let files = cache.files();
+        assert_eq!(files.len(), 2);
+        assert!(files.iter().map(|(_, _, len)| len).sum::<u64>() <= 25);
+    }
+}
@@ -0,0 +1,283 @@
+use crate::{json, output, parse::Snippets};
+use set_error::ChangeError;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write as _,
+    fs, io,
+    path::Path,
+    sync::{Mutex, PoisonError},
+};
+
+/// The outputs saved from one source image, with their width and height.
+type Saved = BTreeMap<String, (u32, u32)>;
+
+static OUTPUTS: Mutex<BTreeMap<String, Saved>> = Mutex::new(BTreeMap::new());
+
+/// Formats an `<img>` can fall back to, best first.
+const FALLBACKS: [&str; 3] = ["image/jpeg", "image/png", "image/gif"];
+
+/// The `srcset` of every output in one format.
+struct SourceSet {
+    mime: &'static str,
+    srcset: String,
+    /// The largest output, used for the `<img>` `src`, `width` and `height`.
+    largest: (String, u32, u32),
+}
+
+/// Notes that `source` was saved to `output_path` at `size`, then rewrites the snippet for
+/// `source` and its entry in the JSON file, keeping the entries for every other source.
+pub fn record(
+    snippets: &Snippets,
+    source: &str,
+    output_path: &str,
+    size: (u32, u32),
+) -> Result<(), String> {
+    let mut outputs = OUTPUTS.lock().unwrap_or_else(PoisonError::into_inner);
+    outputs
+        .entry(source.to_string())
+        .or_default()
+        .insert(output_path.to_string(), size);
+    if let Some(folder) = &snippets.html {
+        fs::create_dir_all(folder).set_error(&format!("Failed to create folder {folder}"))?;
+        let name = Path::new(source)
+            .file_stem()
+            .map_or_else(|| source.into(), |x| x.to_string_lossy());
+        let path = Path::new(folder).join(format!("{name}.html"));
+        fs::write(
+            &path,
+            html(snippets, &source_sets(snippets, &outputs[source])),
+        )
+        .set_error(&format!("Failed to write {}", path.display()))?;
+        output::detail(&format!("Wrote snippet {}", path.display()));
+    }
+    if let Some(path) = &snippets.json {
+        let mut entries = read(path)?;
+        entries.insert(
+            source.to_string(),
+            json(snippets, &source_sets(snippets, &outputs[source])),
+        );
+        let entries: Vec<String> = entries
+            .iter()
+            .map(|(source, entry)| format!("  {}: {}", json::string(source), entry))
+            .collect();
+        fs::write(path, format!("{{\n{}\n}}\n", entries.join(",\n")))
+            .set_error(&format!("Failed to write {path}"))?;
+        output::detail(&format!("Wrote snippets to {path}"));
+    }
+    Ok(())
+}
+
+/// Forgets every output not in `saved`, given as source and output paths, so snippets stop listing
+/// outputs the config no longer saves.
+pub fn retain(saved: &HashSet<(String, String)>) {
+    retain_in(
+        &mut OUTPUTS.lock().unwrap_or_else(PoisonError::into_inner),
+        saved,
+    );
+}
+
+fn retain_in(outputs: &mut BTreeMap<String, Saved>, saved: &HashSet<(String, String)>) {
+    for (source, paths) in outputs.iter_mut() {
+        paths.retain(|path, _| saved.contains(&(source.clone(), path.clone())));
+    }
+    outputs.retain(|_, paths| !paths.is_empty());
+}
+
+/// The entries already in the JSON file at `path`, or none if it does not exist yet or can not be
+/// read as JSON.
+fn read(path: &str) -> Result<BTreeMap<String, String>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(_) => return Err(format!("Failed to read {path}")),
+    };
+    Ok(json::object_entries(&text).unwrap_or_else(|| {
+        output::warn(&format!("{path} is not a JSON object, so it is replaced."));
+        BTreeMap::new()
+    }))
+}
+
+/// Groups `saved` by format, with the format the `<img>` falls back to last.
+fn source_sets(snippets: &Snippets, saved: &Saved) -> Vec<SourceSet> {
+    let mut by_mime: BTreeMap<&'static str, Vec<(String, u32, u32)>> = BTreeMap::new();
+    for (path, (width, height)) in saved {
+        by_mime
+            .entry(mime(path))
+            .or_default()
+            .push((url(snippets, path), *width, *height));
+    }
+    let fallback = FALLBACKS.iter().find(|x| by_mime.contains_key(*x)).copied();
+    let mut sets: Vec<SourceSet> = by_mime
+        .into_iter()
+        .map(|(mime, mut outputs)| {
+            outputs.sort_by_key(|(_, width, _)| *width);
+            SourceSet {
+                mime,
+                srcset: outputs
+                    .iter()
+                    .map(|(url, width, _)| format!("{url} {width}w"))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                largest: outputs.last().cloned().unwrap_or_default(),
+            }
+        })
+        .collect();
+    sets.sort_by_key(|x| Some(x.mime) == fallback);
+    sets
+}
+
+fn html(snippets: &Snippets, sets: &[SourceSet]) -> String {
+    let sizes = snippets
+        .sizes
+        .as_ref()
+        .map(|x| format!(" sizes=\"{}\"", attribute(x)))
+        .unwrap_or_default();
+    let mut html = String::from("<picture>\n");
+    if let Some((fallback, sources)) = sets.split_last() {
+        for set in sources {
+            writeln!(
+                html,
+                "  <source type=\"{}\" srcset=\"{}\"{}>",
+                set.mime,
+                attribute(&set.srcset),
+                sizes
+            )
+            .ok();
+        }
+        let (src, width, height) = &fallback.largest;
+        writeln!(
+            html,
+            "  <img src=\"{}\" srcset=\"{}\"{} width=\"{}\" height=\"{}\" alt=\"\">",
+            attribute(src),
+            attribute(&fallback.srcset),
+            sizes,
+            width,
+            height
+        )
+        .ok();
+    }
+    html.push_str("</picture>\n");
+    html
+}
+
+fn json(snippets: &Snippets, sets: &[SourceSet]) -> String {
+    let Some((fallback, sources)) = sets.split_last() else {
+        return String::from("{}");
+    };
+    let sources: Vec<String> = sources
+        .iter()
+        .map(|set| {
+            format!(
+                "{{ \"type\": \"{}\", \"srcset\": {} }}",
+                set.mime,
+                json::string(&set.srcset)
+            )
+        })
+        .collect();
+    let (src, width, height) = &fallback.largest;
+    format!(
+        "{{\n    \"sources\": [{}],\n    \"img\": {{ \"src\": {}, \"srcset\": {}, \"width\": {}, \"height\": {} }},\n    \"sizes\": {}\n  }}",
+        sources.join(", "),
+        json::string(src),
+        json::string(&fallback.srcset),
+        width,
+        height,
+        snippets
+            .sizes
+            .as_ref()
+            .map_or_else(|| String::from("null"), |x| json::string(x))
+    )
+}
+
+/// Turns an output path into the URL it is served at, relative to the snippets' root if set.
+fn url(snippets: &Snippets, path: &str) -> String {
+    let path = path.replace('\\', "/");
+    match &snippets.root {
+        Some(root) => {
+            let root = root.replace('\\', "/");
+            match path.strip_prefix(root.trim_end_matches('/')) {
+                Some(rest) if rest.starts_with('/') => rest.to_string(),
+                _ => path,
+            }
+        }
+        None => path,
+    }
+}
+
+fn mime(path: &str) -> &'static str {
+    match Path::new(path)
+        .extension()
+        .and_then(|x| x.to_str())
+        .map(str::to_ascii_lowercase)
+        .as_deref()
+    {
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("bmp") => "image/bmp",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+fn attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_keeps_other_sources() {
+        let path = std::env::temp_dir().join("image-watcher-snippets.json");
+        let path = path.to_str().unwrap().to_string();
+        fs::write(&path, "{\n  \"old.png\": {}\n}\n").unwrap();
+        let snippets = Snippets {
+            json: Some(path.clone()),
+            root: Some(String::from("public")),
+            ..Snippets::default()
+        };
+        record(&snippets, "hero.png", "public/hero-640.jpg", (640, 480)).unwrap();
+        record(&snippets, "hero.png", "public/hero-1280.jpg", (1280, 960)).unwrap();
+        let entries = json::object_entries(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(entries["old.png"], "{}");
+        assert!(entries["hero.png"]
+            .contains("\"srcset\": \"/hero-640.jpg 640w, /hero-1280.jpg 1280w\""));
+    }
+
+    #[test]
+    fn fallback_format_comes_last() {
+        let saved: Saved = vec![
+            (String::from("a.jpg"), (100, 50)),
+            (String::from("a.png"), (100, 50)),
+            (String::from("a.bmp"), (100, 50)),
+        ]
+        .into_iter()
+        .collect();
+        let sets = source_sets(&Snippets::default(), &saved);
+        let mimes: Vec<&str> = sets.iter().map(|x| x.mime).collect();
+        assert_eq!(mimes, ["image/bmp", "image/png", "image/jpeg"]);
+    }
+
+    #[test]
+    fn retain_forgets_outputs_no_longer_saved() {
+        let mut outputs = BTreeMap::new();
+        let saved: Saved = vec![
+            (String::from("a-old.jpg"), (10, 10)),
+            (String::from("a-new.jpg"), (20, 20)),
+        ]
+        .into_iter()
+        .collect();
+        outputs.insert(String::from("a.png"), saved.clone());
+        outputs.insert(String::from("b.png"), saved);
+        let kept = vec![(String::from("a.png"), String::from("a-new.jpg"))];
+        retain_in(&mut outputs, &kept.into_iter().collect());
+        assert_eq!(outputs.keys().collect::<Vec<_>>(), ["a.png"]);
+        assert_eq!(outputs["a.png"].keys().collect::<Vec<_>>(), ["a-new.jpg"]);
+    }
+}
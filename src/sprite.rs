@@ -0,0 +1,361 @@
+use crate::{json, output, parse::SpriteSheet, pipeline};
+use image::{imageops, DynamicImage, GenericImageView, RgbaImage};
+use set_error::ChangeError;
+use std::{
+    convert::TryFrom,
+    fmt::Write as _,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+/// Where one image ended up in the atlas.
+pub struct Placement {
+    pub name: String,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Where each rectangle was placed, in the order they were given, and the size of the atlas.
+#[derive(Default)]
+struct Packed {
+    positions: Vec<(u32, u32)>,
+    width: u32,
+    height: u32,
+}
+
+/// Opens every image in `sheet`, runs its jobs and packs them into one atlas.
+pub fn build(sheet: &SpriteSheet) -> Result<(DynamicImage, Vec<Placement>), String> {
+    let mut images = Vec::new();
+    for file in &sheet.images {
+        let name = class_name(&file.path);
+        if images.iter().any(|(other, _)| *other == name) {
+            return Err(format!(
+                "More than one image in sprite {} is named {}",
+                sheet.output, name
+            ));
+        }
+        let img =
+            image::open(&file.path).set_error(&format!("failed to open file {}", file.path))?;
+        images.push((name, pipeline::apply(&pipeline::jobs(&file.other), img)?));
+    }
+    let sizes: Vec<(u32, u32)> = images.iter().map(|(_, img)| img.dimensions()).collect();
+    let Packed {
+        positions,
+        width,
+        height,
+    } = pack(&sizes, sheet.padding);
+    output::detail(&format!(
+        "Packed {} images into {}x{}",
+        images.len(),
+        width,
+        height
+    ));
+    let mut atlas = RgbaImage::new(width, height);
+    let mut placements = Vec::new();
+    for ((name, img), (x, y)) in images.into_iter().zip(positions) {
+        imageops::replace(&mut atlas, &img.to_rgba(), x, y);
+        placements.push(Placement {
+            name,
+            x,
+            y,
+            width: img.width(),
+            height: img.height(),
+        });
+    }
+    Ok((DynamicImage::ImageRgba8(atlas), placements))
+}
+
+/// Writes the stylesheet and JSON map for an atlas saved with `placements`, if `sheet` asks for
+/// them.
+pub fn write_maps(sheet: &SpriteSheet, placements: &[Placement]) -> Result<(), String> {
+    if let Some(path) = &sheet.css {
+        let url = relative_path(Path::new(&sheet.output), Path::new(path));
+        let mut css = String::new();
+        for placement in placements {
+            write!(
+                css,
+                ".{}{} {{\n  background: url(\"{}\") no-repeat {} {};\n  width: {}px;\n  height: {}px;\n}}\n",
+                sheet.prefix,
+                placement.name,
+                url,
+                offset(placement.x),
+                offset(placement.y),
+                placement.width,
+                placement.height
+            )
+            .ok();
+        }
+        if Path::new(path)
+            .extension()
+            .is_some_and(|x| x.eq_ignore_ascii_case("scss"))
+        {
+            css.push_str("\n$sprites: (\n");
+            for placement in placements {
+                writeln!(
+                    css,
+                    "  \"{}\": ({}px, {}px, {}px, {}px),",
+                    placement.name, placement.x, placement.y, placement.width, placement.height
+                )
+                .ok();
+            }
+            css.push_str(");\n");
+        }
+        fs::write(path, css).set_error(&format!("Failed to write {path}"))?;
+        output::info(&format!("\"{}\" -> \"{}\"", sheet.output, path));
+    }
+    if let Some(path) = &sheet.json {
+        fs::write(path, json_map(sheet, placements, path))
+            .set_error(&format!("Failed to write {path}"))?;
+        output::info(&format!("\"{}\" -> \"{}\"", sheet.output, path));
+    }
+    Ok(())
+}
+
+/// The JSON map saved at `path`, giving the atlas relative to it and where each image is.
+fn json_map(sheet: &SpriteSheet, placements: &[Placement], path: &str) -> String {
+    let frames: Vec<String> = placements
+        .iter()
+        .map(|placement| {
+            format!(
+                "    {}: {{ \"x\": {}, \"y\": {}, \"width\": {}, \"height\": {} }}",
+                json::string(&placement.name),
+                placement.x,
+                placement.y,
+                placement.width,
+                placement.height
+            )
+        })
+        .collect();
+    format!(
+        "{{\n  \"image\": {},\n  \"frames\": {{\n{}\n  }}\n}}\n",
+        json::string(&relative_path(Path::new(&sheet.output), Path::new(path))),
+        frames.join(",\n")
+    )
+}
+
+/// Places rectangles of `sizes` with `padding` between them using a skyline bottom-left packer,
+/// trying a few widths and keeping the one with the smallest area.
+fn pack(sizes: &[(u32, u32)], padding: u32) -> Packed {
+    let widest = sizes.iter().map(|x| x.0).max().unwrap_or(0);
+    let area: u64 = sizes
+        .iter()
+        .map(|(w, h)| u64::from(w + padding) * u64::from(h + padding))
+        .sum();
+    let square = u32::try_from(area.isqrt()).unwrap_or(u32::MAX);
+    let mut best: Option<Packed> = None;
+    for percent in &[70, 85, 100, 115, 130, 150, 200] {
+        let bin_width = (square * percent / 100).max(widest + padding);
+        let packed = pack_into(sizes, padding, bin_width);
+        let is_better = best.as_ref().is_none_or(|best| {
+            u64::from(packed.width) * u64::from(packed.height)
+                < u64::from(best.width) * u64::from(best.height)
+        });
+        if is_better {
+            best = Some(packed);
+        }
+    }
+    best.unwrap_or_default()
+}
+
+fn pack_into(sizes: &[(u32, u32)], padding: u32, bin_width: u32) -> Packed {
+    // Tallest first packs tighter.
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|&index| std::cmp::Reverse((sizes[index].1, sizes[index].0)));
+    // Each segment is an x, the height filled up to there, and how far it goes right.
+    let mut skyline: Vec<(u32, u32, u32)> = vec![(0, 0, bin_width)];
+    let mut positions = vec![(0, 0); sizes.len()];
+    let (mut width, mut height) = (0, 0);
+    for index in order {
+        let (w, h) = sizes[index];
+        let (padded_w, padded_h) = (w + padding, h + padding);
+        let mut best: Option<(u32, u32, usize)> = None;
+        for start in 0..skyline.len() {
+            let x = skyline[start].0;
+            if x + padded_w > bin_width {
+                break;
+            }
+            let mut y = 0;
+            let mut covered = 0;
+            for segment in &skyline[start..] {
+                y = y.max(segment.1);
+                covered += segment.2;
+                if covered >= padded_w {
+                    break;
+                }
+            }
+            if best.is_none_or(|(best_y, best_x, _)| (y, x) < (best_y, best_x)) {
+                best = Some((y, x, start));
+            }
+        }
+        let Some((y, x, start)) = best else {
+            continue;
+        };
+        positions[index] = (x, y);
+        width = width.max(x + w);
+        height = height.max(y + h);
+        // Raise the skyline under the new rectangle, cutting into the segments it covers.
+        let end = x + padded_w;
+        let mut rest: Vec<(u32, u32, u32)> = skyline[start..]
+            .iter()
+            .filter(|segment| segment.0 + segment.2 > end)
+            .map(|&(seg_x, seg_y, seg_w)| {
+                if seg_x < end {
+                    (end, seg_y, seg_x + seg_w - end)
+                } else {
+                    (seg_x, seg_y, seg_w)
+                }
+            })
+            .collect();
+        skyline.truncate(start);
+        skyline.push((x, y + padded_h, padded_w));
+        skyline.append(&mut rest);
+        skyline.dedup_by(|next, previous| {
+            if next.1 == previous.1 {
+                previous.2 += next.2;
+                true
+            } else {
+                false
+            }
+        });
+    }
+    Packed {
+        positions,
+        width,
+        height,
+    }
+}
+
+fn offset(pixels: u32) -> String {
+    if pixels == 0 {
+        String::from("0")
+    } else {
+        format!("-{pixels}px")
+    }
+}
+
+/// Turns a file name into something usable as a CSS class.
+fn class_name(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map(|x| x.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+        .chars()
+        .map(|x| {
+            if x.is_ascii_alphanumeric() || x == '_' {
+                x
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// The path to `target` from the folder holding `from`, for URLs in generated files.
+fn relative_path(target: &Path, from: &Path) -> String {
+    let target: Vec<Component> = target.components().collect();
+    let folder: Vec<Component> = from
+        .parent()
+        .map(|x| x.components().collect())
+        .unwrap_or_default();
+    let shared = target
+        .iter()
+        .zip(&folder)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut path = PathBuf::new();
+    for _ in shared..folder.len() {
+        path.push("..");
+    }
+    for component in &target[shared..] {
+        path.push(component);
+    }
+    path.to_string_lossy().replace('\\', "/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::SharedSettings;
+
+    fn overlaps(a: ((u32, u32), (u32, u32)), b: ((u32, u32), (u32, u32))) -> bool {
+        let (((ax, ay), (aw, ah)), ((bx, by), (bw, bh))) = (a, b);
+        ax < bx + bw && bx < ax + aw && ay < by + bh && by < ay + ah
+    }
+
+    #[test]
+    fn pack_places_every_rectangle_apart() {
+        let sizes = [(32, 32), (16, 48), (64, 8), (8, 8), (20, 30), (32, 32)];
+        let packed = pack(&sizes, 2);
+        for (index, (&position, &size)) in packed.positions.iter().zip(&sizes).enumerate() {
+            assert!(position.0 + size.0 <= packed.width);
+            assert!(position.1 + size.1 <= packed.height);
+            for (&other_position, &other_size) in
+                packed.positions.iter().zip(&sizes).skip(index + 1)
+            {
+                let padded = |(w, h): (u32, u32)| (w + 2, h + 2);
+                assert!(!overlaps(
+                    (position, padded(size)),
+                    (other_position, other_size)
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn pack_of_nothing_is_empty() {
+        let packed = pack(&[], 4);
+        assert!(packed.positions.is_empty());
+        assert_eq!((packed.width, packed.height), (0, 0));
+    }
+
+    #[test]
+    fn class_names_are_css_safe() {
+        assert_eq!(class_name("icons/Arrow Left.png"), "arrow-left");
+        assert_eq!(class_name("a_b.c.png"), "a_b-c");
+    }
+
+    #[test]
+    fn relative_paths_go_up_from_the_map() {
+        assert_eq!(
+            relative_path(
+                Path::new("public/img/sprite.png"),
+                Path::new("public/css/sprite.css")
+            ),
+            "../img/sprite.png"
+        );
+        assert_eq!(
+            relative_path(Path::new("out/sprite.png"), Path::new("out/sprite.json")),
+            "sprite.png"
+        );
+    }
+
+    #[test]
+    fn json_map_escapes_names_and_paths() {
+        let sheet = SpriteSheet {
+            images: Vec::new(),
+            output: String::from("out/a \"b\".png"),
+            css: None,
+            json: Some(String::from("out/map.json")),
+            padding: 0,
+            prefix: String::new(),
+            other: SharedSettings::default(),
+        };
+        let placement = Placement {
+            name: String::from("say-\"hi\"\\"),
+            x: 1,
+            y: 2,
+            width: 3,
+            height: 4,
+        };
+        let map = json_map(&sheet, &[placement], "out/map.json");
+        let entries = json::object_entries(&map).unwrap();
+        assert_eq!(entries["image"], "\"a \\\"b\\\".png\"");
+        let frames = json::object_entries(&entries["frames"]).unwrap();
+        assert_eq!(
+            frames["say-\"hi\"\\"],
+            "{ \"x\": 1, \"y\": 2, \"width\": 3, \"height\": 4 }"
+        );
+    }
+}
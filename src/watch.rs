@@ -0,0 +1,545 @@
+use crate::output;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use set_error::ChangeError;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::mpsc::{channel, Receiver, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+pub enum WatchingFuncResult<T> {
+    Success(T),
+    Retry(String),
+    Fail(String),
+}
+use WatchingFuncResult::{Fail, Retry, Success};
+
+type OpenFunc<T> = Rc<dyn Fn(&str) -> WatchingFuncResult<T>>;
+type ReloadFunc<T> = Rc<dyn Fn() -> Result<Reloaded<T>, String>>;
+type AfterBatchFunc = Rc<dyn Fn()>;
+
+pub struct FileListBuilder<T: Clone> {
+    files: Vec<WatchedFile<T>>,
+    poll_interval: Option<Duration>,
+    debounce: Duration,
+    open_file_func: OpenFunc<T>,
+    run_only_once: bool,
+    config: Option<(String, ReloadFunc<T>)>,
+    after_batch: Option<AfterBatchFunc>,
+}
+
+/// The files to watch after the config file changed, with the indices of those to rebuild.
+pub struct Reloaded<T> {
+    pub files: Vec<WatchedFile<T>>,
+    pub rebuild: Vec<usize>,
+}
+
+pub struct WatchedFile<T> {
+    path: String,
+    /// Other files the output is built from, so changing one rebuilds this file too.
+    dependencies: Vec<String>,
+    /// Used instead of the builder's function to open this file.
+    open_func: Option<OpenFunc<T>>,
+    functions_on_run: Vec<Rc<dyn Fn(T) -> WatchingFuncResult<T>>>,
+    function_on_end: Rc<dyn Fn(T) -> Result<(), String>>,
+}
+
+/// The size and modification time of a file, used to tell when a save has finished.
+#[derive(Clone, Copy, PartialEq)]
+struct Stamp {
+    len: u64,
+    modified: SystemTime,
+}
+
+/// A file that has changed but has not yet been stable for the whole debounce window.
+struct Pending {
+    stamp: Option<Stamp>,
+    since: Instant,
+}
+
+/// How changes to the watched files are found.
+enum Changes {
+    /// Checks every file after each interval, for filesystems without change notifications.
+    Poll(Duration),
+    /// Waits for the operating system to report changes to the folders holding the files.
+    Notify {
+        watcher: RecommendedWatcher,
+        events: Receiver<notify::Result<Event>>,
+        folders: HashSet<PathBuf>,
+        paths: HashMap<PathBuf, String>,
+    },
+}
+
+impl<T: Clone> FileListBuilder<T> {
+    pub fn new<F: 'static + Fn(&str) -> WatchingFuncResult<T>>(open_func: F) -> Self {
+        Self {
+            files: Vec::new(),
+            poll_interval: None,
+            debounce: Duration::from_millis(300),
+            open_file_func: Rc::new(open_func),
+            run_only_once: false,
+            config: None,
+            after_batch: None,
+        }
+    }
+    pub fn run_only_once(mut self, q: bool) -> Self {
+        self.run_only_once = q;
+        self
+    }
+    pub fn add_file(&mut self, file: WatchedFile<T>) {
+        self.files.push(file);
+    }
+    /// Sets how long a changed file must keep the same size and modification time before it is
+    /// rebuilt. Any changes made within the window are collapsed into one rebuild.
+    pub fn with_debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+    /// Checks every file for changes after each `interval` instead of waiting for the operating
+    /// system to report them, which does not work on some network filesystems.
+    pub fn with_poll_interval(mut self, interval: Option<Duration>) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+    /// Also watches the config file at `path`, calling `reload_func` whenever it changes. If
+    /// reloading fails the error is printed and the previous files keep being watched.
+    pub fn with_config_reload<F: 'static + Fn() -> Result<Reloaded<T>, String>>(
+        mut self,
+        path: &str,
+        reload_func: F,
+    ) -> Self {
+        self.config = Some((path.to_string(), Rc::new(reload_func)));
+        self
+    }
+    /// Calls `func` after the first build of every file, then after each group of rebuilds.
+    pub fn with_after_batch<F: 'static + Fn()>(mut self, func: F) -> Self {
+        self.after_batch = Some(Rc::new(func));
+        self
+    }
+    pub fn launch(mut self) -> Result<(), String> {
+        let mut stamps = HashMap::new();
+        for file in &self.files {
+            for path in file.watched_paths() {
+                stamps.insert(path.to_string(), stamp(path)?);
+            }
+            self.run_file(file)?;
+        }
+        self.after_batch();
+        if self.run_only_once {
+            return Ok(());
+        }
+        if let Some((path, _)) = &self.config {
+            stamps.insert(path.clone(), stamp(path)?);
+        }
+        let mut notifier = Changes::new(self.poll_interval);
+        for path in stamps.keys() {
+            notifier.watch(path);
+        }
+        let mut pending: HashMap<String, Pending> = HashMap::new();
+        loop {
+            let recheck = self.debounce.min(Duration::from_millis(100));
+            let changed = notifier.wait(if pending.is_empty() {
+                None
+            } else {
+                Some(recheck)
+            });
+            let to_check: Vec<String> = match changed {
+                Some(paths) => paths
+                    .into_iter()
+                    .chain(pending.keys().cloned())
+                    .collect::<HashSet<_>>()
+                    .into_iter()
+                    .collect(),
+                None => stamps.keys().cloned().collect(),
+            };
+            for path in to_check {
+                let last_built = match stamps.get(&path) {
+                    Some(x) => *x,
+                    None => continue,
+                };
+                let current = stamp(&path).ok();
+                note_change(&mut pending, path, last_built, current);
+            }
+            let settled = settle(&mut pending, self.debounce);
+            let rebuilt = !settled.is_empty();
+            for (path, settled_stamp) in settled {
+                stamps.insert(path.clone(), settled_stamp);
+                match &self.config {
+                    Some((config_path, reload_func)) if *config_path == path => {
+                        match reload_func() {
+                            Ok(reloaded) => {
+                                self.reload(reloaded, &mut stamps, &mut pending, &mut notifier);
+                            }
+                            Err(s) => {
+                                output::error(&format!("Keeping the previous config. {s}"));
+                            }
+                        }
+                    }
+                    _ => {
+                        for file in self
+                            .files
+                            .iter()
+                            .filter(|file| file.watched_paths().any(|x| *x == path))
+                        {
+                            if let Err(s) = self.run_file(file) {
+                                output::error(&s);
+                            }
+                        }
+                    }
+                }
+            }
+            if rebuilt {
+                self.after_batch();
+            }
+        }
+    }
+    fn after_batch(&self) {
+        if let Some(func) = &self.after_batch {
+            func();
+        }
+    }
+    fn reload(
+        &mut self,
+        reloaded: Reloaded<T>,
+        stamps: &mut HashMap<String, Stamp>,
+        pending: &mut HashMap<String, Pending>,
+        notifier: &mut Changes,
+    ) {
+        self.files = reloaded.files;
+        let mut watched: HashSet<&str> = self
+            .files
+            .iter()
+            .flat_map(WatchedFile::watched_paths)
+            .collect();
+        if let Some((config_path, _)) = &self.config {
+            watched.insert(config_path);
+        }
+        stamps.retain(|path, _| {
+            let keep = watched.contains(path.as_str());
+            if !keep {
+                output::info(&format!("Stopped watching \"{path}\""));
+            }
+            keep
+        });
+        pending.retain(|path, _| watched.contains(path.as_str()));
+        for path in watched {
+            if !stamps.contains_key(path) {
+                if let Ok(current) = stamp(path) {
+                    output::info(&format!("Started watching \"{path}\""));
+                    stamps.insert(path.to_string(), current);
+                    notifier.watch(path);
+                }
+            }
+        }
+        for index in reloaded.rebuild {
+            if let Err(s) = self.run_file(&self.files[index]) {
+                output::error(&s);
+            }
+        }
+    }
+    fn run_file(&self, file: &WatchedFile<T>) -> Result<(), String> {
+        let open_func = file.open_func.as_ref().unwrap_or(&self.open_file_func);
+        let mut file_data = keep_doing_until(|| open_func(&file.path))?;
+        for function_to_run in &file.functions_on_run {
+            file_data = keep_doing_until(|| function_to_run(file_data.clone()))?;
+        }
+        keep_doing_until(|| match (file.function_on_end)(file_data.clone()) {
+            Ok(()) => Success(()),
+            Err(s) => Retry(s),
+        })
+    }
+}
+
+impl Changes {
+    fn new(poll_interval: Option<Duration>) -> Self {
+        if let Some(interval) = poll_interval {
+            return Changes::Poll(interval);
+        }
+        let (sender, events) = channel();
+        match notify::recommended_watcher(sender) {
+            Ok(watcher) => Changes::Notify {
+                watcher,
+                events,
+                folders: HashSet::new(),
+                paths: HashMap::new(),
+            },
+            Err(e) => {
+                output::error(&format!(
+                    "Failed to watch for file changes, checking every second instead. {e}"
+                ));
+                Changes::Poll(Duration::from_secs(1))
+            }
+        }
+    }
+    fn watch(&mut self, path: &str) {
+        if let Changes::Notify {
+            watcher,
+            folders,
+            paths,
+            ..
+        } = self
+        {
+            let path_buf = Path::new(path);
+            let folder = match path_buf.parent() {
+                Some(parent) if parent != Path::new("") => parent,
+                _ => Path::new("."),
+            };
+            let (Ok(folder), Some(file_name)) = (folder.canonicalize(), path_buf.file_name())
+            else {
+                output::error(&format!("Failed to watch \"{path}\" for changes."));
+                return;
+            };
+            // Editors often save by replacing the file, so the folder is watched instead of the
+            // file itself.
+            if !folders.contains(&folder) {
+                if let Err(e) = watcher.watch(&folder, RecursiveMode::NonRecursive) {
+                    output::error(&format!("Failed to watch \"{path}\" for changes. {e}"));
+                    return;
+                }
+                folders.insert(folder.clone());
+            }
+            paths.insert(folder.join(file_name), path.to_string());
+        }
+    }
+    /// Waits for changes, giving up after `timeout` if there is one. Returns the watched paths
+    /// that may have changed, or `None` if every path should be checked.
+    fn wait(&mut self, timeout: Option<Duration>) -> Option<Vec<String>> {
+        match self {
+            Changes::Poll(interval) => {
+                thread::sleep(timeout.map_or(*interval, |timeout| timeout.min(*interval)));
+                None
+            }
+            Changes::Notify { events, paths, .. } => {
+                let mut changed = Vec::new();
+                let mut next = match timeout {
+                    Some(timeout) => events.recv_timeout(timeout),
+                    None => events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                while let Ok(event) = next {
+                    match event {
+                        Ok(event) if !matches!(event.kind, EventKind::Access(_)) => changed.extend(
+                            event
+                                .paths
+                                .iter()
+                                .filter_map(|changed_path| paths.get(changed_path).cloned()),
+                        ),
+                        Ok(_) => (),
+                        Err(e) => output::error(&format!("Failed to watch for changes. {e}")),
+                    }
+                    next = events.try_recv().map_err(|_| RecvTimeoutError::Timeout);
+                }
+                Some(changed)
+            }
+        }
+    }
+}
+
+/// Starts the debounce window for `path` if `current`, its stamp now, is not the one it was last
+/// built from, or starts it again if the stamp changed while it was waiting.
+fn note_change(
+    pending: &mut HashMap<String, Pending>,
+    path: String,
+    last_built: Stamp,
+    current: Option<Stamp>,
+) {
+    match pending.get_mut(&path) {
+        Some(change) if change.stamp != current => {
+            change.stamp = current;
+            change.since = Instant::now();
+        }
+        None if current != Some(last_built) => {
+            output::debug(&format!("Change detected in \"{path}\""));
+            pending.insert(
+                path,
+                Pending {
+                    stamp: current,
+                    since: Instant::now(),
+                },
+            );
+        }
+        _ => (),
+    }
+}
+
+/// Takes the changes that have been stable for `debounce` out of `pending`, returning those to
+/// rebuild. Files still missing are dropped, and noticed again once they are saved.
+fn settle(pending: &mut HashMap<String, Pending>, debounce: Duration) -> Vec<(String, Stamp)> {
+    let done: Vec<String> = pending
+        .iter()
+        .filter(|(_, change)| change.since.elapsed() >= debounce)
+        .map(|(path, _)| path.clone())
+        .collect();
+    done.into_iter()
+        .filter_map(|path| {
+            let change = pending.remove(&path)?;
+            if change.stamp.is_none() {
+                output::debug(&format!("\"{path}\" was removed"));
+            }
+            change.stamp.map(|stamp| (path, stamp))
+        })
+        .collect()
+}
+
+fn keep_doing_until<F, T>(f: F) -> Result<T, String>
+where
+    F: Fn() -> WatchingFuncResult<T>,
+{
+    loop {
+        match f() {
+            Success(t) => break Ok(t),
+            Fail(s) => return Err(s),
+            Retry(s) => {
+                output::error(&s);
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+impl<T> WatchedFile<T> {
+    pub fn new<G: 'static + Fn(T) -> Result<(), String>>(
+        path: &str,
+        end_func: G,
+    ) -> Result<Self, String> {
+        stamp(path)?;
+        Ok(Self {
+            path: path.to_string(),
+            dependencies: Vec::new(),
+            open_func: None,
+            functions_on_run: Vec::new(),
+            function_on_end: Rc::new(end_func),
+        })
+    }
+    pub fn add_func<F: 'static + Fn(T) -> WatchingFuncResult<T>>(&mut self, func: F) {
+        self.functions_on_run.push(Rc::new(func));
+    }
+    /// Opens this file with `func` instead of the function given to the builder.
+    pub fn open_with<F: 'static + Fn(&str) -> WatchingFuncResult<T>>(&mut self, func: F) {
+        self.open_func = Some(Rc::new(func));
+    }
+    pub fn add_dependency(&mut self, path: &str) {
+        self.dependencies.push(path.to_string());
+    }
+    fn watched_paths(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.path.as_str()).chain(self.dependencies.iter().map(String::as_str))
+    }
+}
+
+fn stamp(path: &str) -> Result<Stamp, String> {
+    let metadata = Path::new(path)
+        .metadata()
+        .set_error(&format!("failed to open file {path} metadata"))?;
+    Ok(Stamp {
+        len: metadata.len(),
+        modified: metadata
+            .modified()
+            .set_error(&format!("failed to find files date modified {path}"))?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::Cell, fs};
+
+    fn stamp_of(len: u64) -> Stamp {
+        Stamp {
+            len,
+            modified: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn only_new_stamps_start_waiting() {
+        let mut pending = HashMap::new();
+        note_change(
+            &mut pending,
+            String::from("a"),
+            stamp_of(1),
+            Some(stamp_of(1)),
+        );
+        assert!(pending.is_empty());
+        note_change(
+            &mut pending,
+            String::from("a"),
+            stamp_of(1),
+            Some(stamp_of(2)),
+        );
+        let since = pending["a"].since;
+        note_change(
+            &mut pending,
+            String::from("a"),
+            stamp_of(1),
+            Some(stamp_of(2)),
+        );
+        assert_eq!(pending["a"].since, since);
+        note_change(
+            &mut pending,
+            String::from("a"),
+            stamp_of(1),
+            Some(stamp_of(3)),
+        );
+        assert!(pending["a"].stamp == Some(stamp_of(3)));
+        assert!(pending["a"].since >= since);
+    }
+
+    #[test]
+    fn settle_waits_for_the_debounce() {
+        let mut pending = HashMap::new();
+        note_change(
+            &mut pending,
+            String::from("a"),
+            stamp_of(1),
+            Some(stamp_of(2)),
+        );
+        assert!(settle(&mut pending, Duration::from_secs(30)).is_empty());
+        assert_eq!(pending.len(), 1);
+        let settled = settle(&mut pending, Duration::from_secs(0));
+        assert_eq!(settled.len(), 1);
+        assert!(settled[0].0 == "a" && settled[0].1 == stamp_of(2));
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn settle_drops_deleted_files() {
+        let mut pending = HashMap::new();
+        note_change(&mut pending, String::from("gone"), stamp_of(1), None);
+        assert_eq!(pending.len(), 1);
+        assert!(settle(&mut pending, Duration::from_secs(0)).is_empty());
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn stamp_follows_the_file() {
+        let path = std::env::temp_dir().join("image-watcher-stamp.txt");
+        let path = path.to_str().unwrap();
+        fs::write(path, "one").unwrap();
+        let first = stamp(path).unwrap();
+        fs::write(path, "three").unwrap();
+        assert!(stamp(path).unwrap() != first);
+        fs::remove_file(path).unwrap();
+        assert!(stamp(path).is_err());
+    }
+
+    #[test]
+    fn keep_doing_until_stops_on_success_or_fail() {
+        assert_eq!(keep_doing_until(|| Success(1)), Ok(1));
+        assert_eq!(
+            keep_doing_until(|| Fail::<()>(String::from("no"))),
+            Err(String::from("no"))
+        );
+        let tries = Cell::new(0);
+        let result = keep_doing_until(|| {
+            tries.set(tries.get() + 1);
+            if tries.get() < 2 {
+                Retry(String::from("again"))
+            } else {
+                Success(tries.get())
+            }
+        });
+        assert_eq!(result, Ok(2));
+    }
+}